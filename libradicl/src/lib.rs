@@ -219,6 +219,75 @@ impl BarcodeLookupMap {
 
         (ret, num_neighbors)
     }
+
+    /// Finds *all* barcodes in the BarcodeLookupMap that are a single
+    /// substitution away from `query` and, if `with_indels` is true, those
+    /// that are a single insertion or deletion away.  Since barcodes have a
+    /// fixed length, an insertion pushes the last base out of the barcode and
+    /// a deletion pulls an arbitrary base onto its end.  The indices of
+    /// the neighbors are written to `neighbors` (which is cleared first) along
+    /// with a flag that is true if the neighbor is reachable _only_ through an
+    /// indel.  An exact match of `query` is never reported.
+    pub fn find_all_neighbors(
+        &self,
+        query: u64,
+        with_indels: bool,
+        neighbors: &mut Vec<(usize, bool)>,
+    ) {
+        neighbors.clear();
+        let bclen = self.bclen;
+
+        // all substitution neighbors
+        for i in (0..(2 * bclen)).step_by(2) {
+            let bit_mask = 3 << i;
+            for nmod in 1..4 {
+                let nucl = 0x3 & ((query >> i) + nmod);
+                let nquery = (query & (!bit_mask)) | (nucl << i);
+                if let Some(idx) = self.find_exact(nquery) {
+                    neighbors.push((idx, false));
+                }
+            }
+        }
+
+        if with_indels {
+            // a mask covering the lowest `n` bases
+            let base_mask = |n: u32| -> u64 {
+                if n >= 32 {
+                    u64::MAX
+                } else {
+                    (1u64 << (2 * n)) - 1
+                }
+            };
+
+            // p is the position (from the start of the barcode) of the edit
+            for p in 0..bclen {
+                // the first p bases, shifted into place
+                let prefix = if p == 0 {
+                    0
+                } else {
+                    (query >> (2 * (bclen - p))) << (2 * (bclen - p))
+                };
+                // the bases after position p (deletion) and from position p
+                // up to, but not including, the last base (insertion)
+                let del_rest = (query & base_mask(bclen - p - 1)) << 2;
+                let ins_rest = (query >> 2) & base_mask(bclen - p - 1);
+                for nucl in 0..4u64 {
+                    let del_query = prefix | del_rest | nucl;
+                    let ins_query = prefix | (nucl << (2 * (bclen - p - 1))) | ins_rest;
+                    for nquery in [del_query, ins_query] {
+                        if nquery == query {
+                            continue;
+                        }
+                        if let Some(idx) = self.find_exact(nquery) {
+                            if !neighbors.iter().any(|(n, _)| *n == idx) {
+                                neighbors.push((idx, true));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl CorrectedCbChunk {
@@ -739,4 +808,44 @@ mod tests {
             assert_eq!((Some(3), 1), mo.find_neighbors(et.0, false));
         }
     }
+
+    #[test]
+    fn test_barcode_lookup_map_all_neighbors() {
+        let encode = |b: &[u8]| -> u64 {
+            needletail::bitkmer::BitNuclKmer::new(b, b.len() as u8, false)
+                .next()
+                .unwrap()
+                .1
+                 .0
+        };
+        let barcodes = vec![encode(b"AACCGT"), encode(b"ACCGTT"), encode(b"AACGGT")];
+        let m = BarcodeLookupMap::new(barcodes, 6);
+        let idx = |b: &[u8]| m.find_exact(encode(b)).unwrap();
+
+        let mut neighbors = Vec::new();
+        // substitution neighbor of AACGGT only
+        m.find_all_neighbors(encode(b"AACGGA"), false, &mut neighbors);
+        assert_eq!(neighbors, vec![(idx(b"AACGGT"), false)]);
+
+        // AACCGT and AACGGT are both 1 substitution away
+        m.find_all_neighbors(encode(b"AACTGT"), false, &mut neighbors);
+        neighbors.sort_unstable();
+        let mut expected = vec![(idx(b"AACCGT"), false), (idx(b"AACGGT"), false)];
+        expected.sort_unstable();
+        assert_eq!(neighbors, expected);
+
+        // inserting a C into AACGTT (pushing the last T out) gives AACCGT,
+        // which is 2 substitutions away
+        m.find_all_neighbors(encode(b"AACGTT"), false, &mut neighbors);
+        assert!(!neighbors.iter().any(|(n, _)| *n == idx(b"AACCGT")));
+        m.find_all_neighbors(encode(b"AACGTT"), true, &mut neighbors);
+        assert_eq!(neighbors.len(), 3);
+        assert!(neighbors.contains(&(idx(b"AACCGT"), true)));
+        assert!(neighbors.contains(&(idx(b"ACCGTT"), false)));
+        assert!(neighbors.contains(&(idx(b"AACGGT"), false)));
+
+        // deleting the leading A of AACCGT (pulling a G onto the end)
+        m.find_all_neighbors(encode(b"ACCGTG"), true, &mut neighbors);
+        assert!(neighbors.contains(&(idx(b"AACCGT"), true)));
+    }
 }
@@ -9,26 +9,36 @@
 
 use slog::crit;
 use slog::info;
+use slog::warn;
 
+use crate::chemistry::Chemistry;
+use crate::io_utils;
+use crate::logging;
 use crate::utils as afutils;
 #[allow(unused_imports)]
 use ahash::{AHasher, RandomState};
 use bio_types::strand::Strand;
 use bstr::io::BufReadExt;
+use crossbeam_queue::ArrayQueue;
+use indicatif::ProgressStyle;
 use itertools::Itertools;
-use libradicl::exit_codes;
 use libradicl::rad_types;
 use libradicl::BarcodeLookupMap;
 use needletail::bitkmer::*;
 use num_format::{Locale, ToFormattedString};
+use scroll::Pread;
 use serde_json::json;
 use std::cmp;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read};
 use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Instant;
 
+#[derive(Debug, Clone)]
 pub enum CellFilterMethod {
     // cut off at this cell in
     // the frequency sorted list
@@ -50,6 +60,365 @@ pub enum CellFilterMethod {
     KneeFinding,
 }
 
+/// The default minimum read count of a barcode of an unfiltered permit list.
+pub const DEFAULT_MIN_READS: usize = 10;
+/// The default minimum posterior probability of a correction among
+/// several candidates; with 1.0, such barcodes are never corrected.
+pub const DEFAULT_MIN_CORRECTION_POSTERIOR: f64 = 1.0;
+
+/// Controls how barcodes that do not exactly match the retained
+/// barcodes of an unfiltered permit list are corrected.
+#[derive(Debug, Clone, Copy)]
+pub struct BarcodeCorrectionParams {
+    // also consider retained barcodes a single
+    // insertion or deletion away
+    pub allow_indels: bool,
+    // the minimum posterior probability the best
+    // candidate must have for a barcode with several
+    // candidates to be corrected
+    pub min_posterior: f64,
+}
+
+impl Default for BarcodeCorrectionParams {
+    fn default() -> Self {
+        Self {
+            allow_indels: false,
+            min_posterior: DEFAULT_MIN_CORRECTION_POSTERIOR,
+        }
+    }
+}
+
+/// The configuration of generate-permit-list; see
+/// [`PermitListConfig::builder`].
+#[derive(Debug, Clone)]
+pub struct PermitListConfig {
+    /// the directories holding the (mapped) RAD files
+    pub rad_dirs: Vec<String>,
+    pub output_dir: String,
+    pub filter_meth: CellFilterMethod,
+    /// `None` if the expected orientation is to be inferred from the reads
+    pub expected_ori: Option<Strand>,
+    pub velo_mode: bool,
+    pub correction_params: BarcodeCorrectionParams,
+    pub write_corrections: bool,
+    pub chemistry: Option<&'static Chemistry>,
+    pub num_threads: u32,
+    /// the command line recorded in the metadata
+    pub cmdline: String,
+    pub version: String,
+}
+
+impl PermitListConfig {
+    /// A builder of the configuration reading the RAD files in `rad_dirs`,
+    /// and writing to `output_dir`; by default, the knee finding method
+    /// is used, the expected orientation is inferred, and 1 thread is used.
+    pub fn builder(rad_dirs: Vec<String>, output_dir: &str) -> PermitListConfigBuilder {
+        PermitListConfigBuilder {
+            config: PermitListConfig {
+                rad_dirs,
+                output_dir: output_dir.to_string(),
+                filter_meth: CellFilterMethod::KneeFinding,
+                expected_ori: None,
+                velo_mode: false,
+                correction_params: BarcodeCorrectionParams::default(),
+                write_corrections: false,
+                chemistry: None,
+                num_threads: 1,
+                cmdline: String::new(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+        }
+    }
+}
+
+pub struct PermitListConfigBuilder {
+    config: PermitListConfig,
+}
+
+impl PermitListConfigBuilder {
+    pub fn filter_method(mut self, filter_meth: CellFilterMethod) -> Self {
+        self.config.filter_meth = filter_meth;
+        self
+    }
+    pub fn expected_ori(mut self, expected_ori: Option<Strand>) -> Self {
+        self.config.expected_ori = expected_ori;
+        self
+    }
+    pub fn correction_params(mut self, correction_params: BarcodeCorrectionParams) -> Self {
+        self.config.correction_params = correction_params;
+        self
+    }
+    pub fn write_corrections(mut self, write_corrections: bool) -> Self {
+        self.config.write_corrections = write_corrections;
+        self
+    }
+    pub fn chemistry(mut self, chemistry: Option<&'static Chemistry>) -> Self {
+        self.config.chemistry = chemistry;
+        self
+    }
+    pub fn num_threads(mut self, num_threads: u32) -> Self {
+        self.config.num_threads = num_threads;
+        self
+    }
+    pub fn cmdline(mut self, cmdline: &str) -> Self {
+        self.config.cmdline = cmdline.to_string();
+        self
+    }
+    pub fn version(mut self, version: &str) -> Self {
+        self.config.version = version.to_string();
+        self
+    }
+
+    /// Check the configuration, and return it if it is valid.
+    pub fn build(self) -> Result<PermitListConfig, afutils::ConfigError> {
+        let c = self.config;
+        if c.rad_dirs.is_empty() {
+            return Err(afutils::ConfigError::Incompatible(
+                "at least one input RAD directory must be given".to_string(),
+            ));
+        }
+        if let CellFilterMethod::UnfilteredExternalList(_, min_reads) = c.filter_meth {
+            if min_reads < 1 {
+                return Err(afutils::ConfigError::invalid(
+                    "min-reads",
+                    ">= 1",
+                    min_reads,
+                ));
+            }
+        }
+        let min_posterior = c.correction_params.min_posterior;
+        if !(min_posterior > 0.5 && min_posterior <= 1.0) {
+            return Err(afutils::ConfigError::invalid(
+                "min-correction-posterior",
+                "in (0.5, 1]",
+                min_posterior,
+            ));
+        }
+        if c.num_threads < 1 {
+            return Err(afutils::ConfigError::invalid(
+                "threads",
+                ">= 1",
+                c.num_threads,
+            ));
+        }
+        Ok(c)
+    }
+}
+
+/// The statistics of a generate-permit-list run.
+#[derive(Debug, Clone)]
+pub struct PermitListStats {
+    pub num_reads: usize,
+    pub num_chunks: u64,
+    /// the reads of all barcodes by orientation
+    pub orientation_counts: OrientationCounts,
+    /// the expected orientation, as given or inferred
+    pub expected_ori: Strand,
+    pub num_orientation_consistent_reads: u64,
+    /// the largest number of references of an orientation consistent read
+    pub max_ambiguity_read: usize,
+    pub num_corrected_barcodes: u64,
+}
+
+// The RAD file carries no base qualities, so every substitution
+// is taken to be equally likely; an indel is taken to be this
+// many times less likely than a substitution.
+const INDEL_LIKELIHOOD_RATIO: f64 = 0.1;
+
+/// Given the retained barcodes `candidates` (an index into `prior_counts`
+/// and a flag that is true for indel neighbors) to which an observed barcode
+/// might be corrected, compute the posterior probability of each candidate
+/// being the true barcode, in the manner of cellranger.  The prior of each
+/// candidate is proportional to its (pseudocounted) number of exactly matching
+/// reads.
+fn correction_posteriors(
+    candidates: &[(usize, bool)],
+    prior_counts: &[u64],
+    posteriors: &mut Vec<f64>,
+) {
+    posteriors.clear();
+    for (idx, is_indel) in candidates {
+        let lik = if *is_indel {
+            INDEL_LIKELIHOOD_RATIO
+        } else {
+            1.0
+        };
+        posteriors.push((prior_counts[*idx] as f64 + 1.0) * lik);
+    }
+    let tot: f64 = posteriors.iter().sum();
+    for p in posteriors.iter_mut() {
+        *p /= tot;
+    }
+}
+
+/// Counts of the barcodes (and of the reads carrying them) by
+/// the outcome of correcting them against the permit list.
+#[derive(Debug, Default)]
+struct CorrectionSummary {
+    exact_barcodes: usize,
+    exact_reads: u64,
+    corrected_barcodes: usize,
+    corrected_reads: u64,
+    ambiguous_barcodes: usize,
+    ambiguous_reads: u64,
+    uncorrectable_barcodes: usize,
+    uncorrectable_reads: u64,
+}
+
+impl CorrectionSummary {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "exact_barcodes" : self.exact_barcodes,
+            "exact_reads" : self.exact_reads,
+            "corrected_barcodes" : self.corrected_barcodes,
+            "corrected_reads" : self.corrected_reads,
+            "ambiguous_barcodes" : self.ambiguous_barcodes,
+            "ambiguous_reads" : self.ambiguous_reads,
+            "uncorrectable_barcodes" : self.uncorrectable_barcodes,
+            "uncorrectable_reads" : self.uncorrectable_reads
+        })
+    }
+}
+
+/// The outcome of correcting a single observed barcode, as
+/// written to the correction table.
+#[derive(Debug, Clone, Copy)]
+enum CorrectionStatus {
+    Exact,
+    Corrected,
+    Ambiguous,
+    Uncorrectable,
+}
+
+impl CorrectionStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CorrectionStatus::Exact => "exact",
+            CorrectionStatus::Corrected => "corrected",
+            CorrectionStatus::Ambiguous => "ambiguous",
+            CorrectionStatus::Uncorrectable => "uncorrectable",
+        }
+    }
+}
+
+pub(crate) fn bc_to_string(bc: u64, bclen: u8) -> String {
+    String::from_utf8(bitmer_to_bytes((bc, bclen))).expect("barcode should be valid utf8")
+}
+
+/// Write the table of (raw barcode, corrected barcode, number of reads, status)
+/// for every observed barcode to `barcode_corrections.tsv` in `parent`.
+/// Barcodes that were not corrected have a corrected barcode of `NA`.
+fn write_correction_table(
+    parent: &std::path::Path,
+    bclen: u8,
+    rows: &mut [(u64, Option<u64>, u64, CorrectionStatus)],
+    log: &slog::Logger,
+) -> std::io::Result<()> {
+    rows.sort_unstable_by_key(|r| r.0);
+    let t_path = parent.join("barcode_corrections.tsv");
+    let t_file = std::fs::File::create(&t_path)?;
+    let mut t_writer = BufWriter::new(&t_file);
+    writeln!(
+        &mut t_writer,
+        "raw_barcode\tcorrected_barcode\tnum_reads\tstatus"
+    )?;
+    for (raw, corrected, count, status) in rows.iter() {
+        let corrected_str = match corrected {
+            Some(c) => bc_to_string(*c, bclen),
+            None => String::from("NA"),
+        };
+        writeln!(
+            &mut t_writer,
+            "{}\t{}\t{}\t{}",
+            bc_to_string(*raw, bclen),
+            corrected_str,
+            count,
+            status.as_str()
+        )?;
+    }
+    info!(
+        log,
+        "wrote the correction of {} barcodes to {}",
+        rows.len().to_formatted_string(&Locale::en),
+        t_path.display()
+    );
+    Ok(())
+}
+
+// a barcode whose correction was ambiguous, its number of reads, and the
+// candidates to which it might have been corrected, with their posteriors
+type AmbiguousBarcode = (u64, usize, Vec<(u64, f64)>);
+
+/// Write every barcode whose correction was ambiguous, along with its
+/// number of reads and the candidates to which it might have been
+/// corrected (with their posterior probabilities), to
+/// `ambiguous_barcodes.tsv` in `parent`.
+fn write_ambiguous_table(
+    parent: &std::path::Path,
+    bclen: u8,
+    rows: &mut [AmbiguousBarcode],
+    log: &slog::Logger,
+) -> std::io::Result<()> {
+    rows.sort_unstable_by_key(|r| r.0);
+    let amb_path = parent.join("ambiguous_barcodes.tsv");
+    let amb_file = std::fs::File::create(&amb_path)?;
+    let mut amb_writer = BufWriter::new(&amb_file);
+    writeln!(&mut amb_writer, "barcode\tnum_reads\tcandidates")?;
+    for (ubc, count, candidates) in rows.iter() {
+        let cand_str = candidates
+            .iter()
+            .map(|(c, p)| format!("{}:{:.4}", bc_to_string(*c, bclen), p))
+            .join(",");
+        writeln!(
+            &mut amb_writer,
+            "{}\t{}\t{}",
+            bc_to_string(*ubc, bclen),
+            count,
+            cand_str
+        )?;
+    }
+    info!(
+        log,
+        "wrote {} ambiguous barcodes to {}",
+        rows.len().to_formatted_string(&Locale::en),
+        amb_path.display()
+    );
+    Ok(())
+}
+
+fn log_correction_summary(summary: &CorrectionSummary, log: &slog::Logger) {
+    info!(log, "barcode correction summary\n============");
+    for (label, nbc, nreads) in [
+        ("exact", summary.exact_barcodes, summary.exact_reads),
+        (
+            "corrected",
+            summary.corrected_barcodes,
+            summary.corrected_reads,
+        ),
+        (
+            "ambiguous",
+            summary.ambiguous_barcodes,
+            summary.ambiguous_reads,
+        ),
+        (
+            "uncorrectable",
+            summary.uncorrectable_barcodes,
+            summary.uncorrectable_reads,
+        ),
+    ] {
+        info!(
+            log,
+            "\t{} : {} barcodes, {} reads",
+            label,
+            nbc.to_formatted_string(&Locale::en),
+            nreads.to_formatted_string(&Locale::en);
+            "correction" => label,
+            "num_barcodes" => nbc,
+            "num_reads" => nreads
+        );
+    }
+}
+
 struct Point {
     x: f64,
     y: f64,
@@ -182,16 +551,51 @@ fn get_knee(freq: &[u64], max_iterations: usize, log: &slog::Logger) -> usize {
     max_idx
 }
 
+/// The read counts of the barcodes observed in the RAD file, when using an
+/// external unfiltered permit list. The barcodes of the list are kept in a
+/// sorted `BarcodeLookupMap` with a parallel vector of counts (which takes
+/// much less memory than a hash map for lists of millions of barcodes),
+/// and the reads of the barcodes not in the list are aggregated by barcode.
+pub struct UnfilteredBarcodeCounts {
+    known: BarcodeLookupMap,
+    counts: Vec<u64>,
+    unmatched: HashMap<u64, u64, ahash::RandomState>,
+}
+
+impl UnfilteredBarcodeCounts {
+    pub fn new(mut known_bc: Vec<u64>, bclen: u32) -> Self {
+        known_bc.sort_unstable();
+        known_bc.dedup();
+        let num_known = known_bc.len();
+        let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+        Self {
+            known: BarcodeLookupMap::new(known_bc, bclen),
+            counts: vec![0; num_known],
+            unmatched: HashMap::with_hasher(s),
+        }
+    }
+
+    /// The number of barcodes in the unfiltered permit list.
+    pub fn num_known(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Count `count` reads with the barcode `bc`.
+    pub fn add_count(&mut self, bc: u64, count: u64) {
+        match self.known.find_exact(bc) {
+            Some(i) => self.counts[i] += count,
+            None => *self.unmatched.entry(bc).or_insert(0) += count,
+        }
+    }
+}
+
 fn populate_unfiltered_barcode_map<T: Read>(
     br: BufReader<T>,
     first_bclen: &mut usize,
-) -> HashMap<u64, u64, ahash::RandomState> {
-    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
-    let mut hm = HashMap::with_hasher(s);
-
+) -> UnfilteredBarcodeCounts {
     // read through the external unfiltered barcode list
     // and generate a vector of encoded barcodes
-    // let mut kv = Vec::<u64>::new();
+    let mut kv = Vec::<u64>::new();
     for l in br.byte_lines().flatten() {
         if *first_bclen == 0 {
             *first_bclen = l.len();
@@ -207,16 +611,15 @@ fn populate_unfiltered_barcode_map<T: Read>(
         if let Some((_, km, _)) =
             needletail::bitkmer::BitNuclKmer::new(&l[..], l.len() as u8, false).next()
         {
-            hm.insert(km.0, 0);
+            kv.push(km.0);
         }
     }
-    hm
+    UnfilteredBarcodeCounts::new(kv, *first_bclen as u32)
 }
 
 #[allow(clippy::unnecessary_unwrap, clippy::too_many_arguments)]
 fn process_unfiltered(
-    mut hm: HashMap<u64, u64, ahash::RandomState>,
-    mut unmatched_bc: Vec<u64>,
+    bc_counts: UnfilteredBarcodeCounts,
     ft_vals: &rad_types::FileTags,
     filter_meth: &CellFilterMethod,
     expected_ori: Strand,
@@ -224,11 +627,14 @@ fn process_unfiltered(
     version: &str,
     max_ambiguity_read: usize,
     velo_mode: bool,
+    correction_params: &BarcodeCorrectionParams,
+    write_corrections: bool,
+    chemistry: Option<&Chemistry>,
     cmdline: &str,
     log: &slog::Logger,
-) -> u64 {
+) -> Result<u64, Box<dyn std::error::Error>> {
     let parent = std::path::Path::new(&output_dir);
-    std::fs::create_dir_all(&parent).unwrap();
+    std::fs::create_dir_all(&parent)?;
 
     // the smallest number of reads we'll allow per barcode
     let min_freq;
@@ -242,35 +648,40 @@ fn process_unfiltered(
         }
     }
 
-    // the set of barcodes we'll keep
+    let UnfilteredBarcodeCounts {
+        known,
+        counts,
+        mut unmatched,
+    } = bc_counts;
+
+    // the set of barcodes we'll keep, and their counts
     let mut kept_bc = Vec::<u64>::new();
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut hm = HashMap::with_hasher(s);
 
-    // iterate over the count map
-    for (k, v) in hm.iter_mut() {
+    // iterate over the counts of the known barcodes
+    for (k, v) in known.barcodes.iter().zip(counts.iter()) {
         // if this satisfies our requirement for the minimum count
         // then keep this barcode
         if *v >= min_freq {
             kept_bc.push(*k);
-        } else {
+            hm.insert(*k, *v);
+        } else if *v > 0 {
             // otherwise, we have to add this barcode's
-            // counts to our unmatched list
-            for _ in 0..*v {
-                unmatched_bc.push(*k);
-            }
-            // and then reset the counter for this barcode to 0
-            *v = 0u64;
+            // counts to the unmatched barcodes
+            *unmatched.entry(*k).or_insert(0) += *v;
         }
     }
-
-    // drop the absent barcodes from hm
-    hm.retain(|_, &mut v| v > 0);
+    drop(known);
+    drop(counts);
 
     // how many we will keep
     let num_passing = kept_bc.len();
     info!(
         log,
         "num_passing = {}",
-        num_passing.to_formatted_string(&Locale::en)
+        num_passing.to_formatted_string(&Locale::en);
+        "num_passing" => num_passing
     );
 
     // now, we create a second barcode map with just the barcodes
@@ -282,17 +693,29 @@ fn process_unfiltered(
         bcmap2.barcodes.len().to_formatted_string(&Locale::en)
     );
 
+    // the number of exactly matching reads for each retained barcode,
+    // used as the prior when choosing among several candidate corrections.
+    let prior_counts: Vec<u64> = bcmap2
+        .barcodes
+        .iter()
+        .map(|b| *hm.get(b).unwrap_or(&0))
+        .collect();
+
     // finally, we'll go through the set of unmatched barcodes
     // and try to rescue those that have a *unique* neighbor in the
-    // list of retained barcodes.
+    // list of retained barcodes, or a neighbor that is sufficiently
+    // more likely than the others.
 
     //let mut found_exact = 0usize;
     let mut found_approx = 0usize;
+    let mut found_indel = 0usize;
+    let mut found_by_posterior = 0usize;
     let mut ambig_approx = 0usize;
     let mut not_found = 0usize;
 
     let start_unmatched_time = Instant::now();
 
+    let mut unmatched_bc: Vec<(u64, u64)> = unmatched.into_iter().collect();
     unmatched_bc.sort_unstable();
 
     let mut distinct_unmatched_bc = 0usize;
@@ -301,42 +724,102 @@ fn process_unfiltered(
     // mapping the uncorrected barcode to what it corrects to
     let mut corrected_list = Vec::<(u64, u64)>::with_capacity(1_000_000);
 
-    for (count, ubc) in unmatched_bc.iter().dedup_with_count() {
+    // the barcodes with several candidate corrections that could
+    // not be resolved; (barcode, read count, [(candidate, posterior)])
+    let mut ambiguous_bcs = Vec::<AmbiguousBarcode>::new();
+
+    let mut neighbors = Vec::<(usize, bool)>::new();
+    let mut posteriors = Vec::<f64>::new();
+
+    let mut summary = CorrectionSummary {
+        exact_barcodes: prior_counts.len(),
+        exact_reads: prior_counts.iter().sum(),
+        ..Default::default()
+    };
+    // the rows of the correction table, if we are writing it
+    let mut correction_rows = Vec::<(u64, Option<u64>, u64, CorrectionStatus)>::new();
+    if write_corrections {
+        for (b, c) in bcmap2.barcodes.iter().zip(prior_counts.iter()) {
+            correction_rows.push((*b, Some(*b), *c, CorrectionStatus::Exact));
+        }
+    }
+
+    for (ubc, count) in unmatched_bc.iter() {
+        let count = *count as usize;
         // try to find the unmatched barcode, but
         // look up to 1 edit away
-        match bcmap2.find_neighbors(*ubc, false) {
-            // if we have a match
-            (Some(x), n) => {
-                let cbc = bcmap2.barcodes[x];
-                // if the uncorrected barcode had a
-                // single, unique retained neighbor
-                if cbc != *ubc && n == 1 {
-                    // then increment the count of this
-                    // barcode by 1 (because we'll correct to it)
-                    if let Some(c) = hm.get_mut(&cbc) {
-                        *c += count as u64;
-                        corrected_list.push((*ubc, cbc));
-                    }
-                    // this counts as an approximate find
-                    found_approx += count;
-                    distinct_recoverable_bc += 1;
-                }
-                // if we had > 1 single-mismatch neighbor
-                // then don't keep the barcode, but remember
-                // the count of such events
-                if n > 1 {
-                    ambig_approx += count;
-                }
-            }
-            // if we had no single-mismatch neighbor
+        bcmap2.find_all_neighbors(*ubc, correction_params.allow_indels, &mut neighbors);
+        if neighbors.is_empty() {
+            // if we had no single-edit neighbor
             // then this barcode is not_found and gets
             // dropped.
-            (None, _) => {
-                not_found += count;
+            not_found += count;
+            summary.uncorrectable_barcodes += 1;
+            if write_corrections {
+                correction_rows.push((*ubc, None, count as u64, CorrectionStatus::Uncorrectable));
+            }
+        } else {
+            correction_posteriors(&neighbors, &prior_counts, &mut posteriors);
+            let (best, best_post) =
+                posteriors
+                    .iter()
+                    .enumerate()
+                    .fold(
+                        (0usize, 0f64),
+                        |acc, (i, p)| if *p > acc.1 { (i, *p) } else { acc },
+                    );
+            // a single neighbor always has a posterior of 1
+            if best_post >= correction_params.min_posterior {
+                let cbc = bcmap2.barcodes[neighbors[best].0];
+                // then increment the count of this
+                // barcode (because we'll correct to it)
+                if let Some(c) = hm.get_mut(&cbc) {
+                    *c += count as u64;
+                    corrected_list.push((*ubc, cbc));
+                }
+                // this counts as an approximate find
+                found_approx += count;
+                distinct_recoverable_bc += 1;
+                if neighbors[best].1 {
+                    found_indel += count;
+                }
+                if neighbors.len() > 1 {
+                    found_by_posterior += count;
+                }
+                summary.corrected_barcodes += 1;
+                if write_corrections {
+                    correction_rows.push((
+                        *ubc,
+                        Some(cbc),
+                        count as u64,
+                        CorrectionStatus::Corrected,
+                    ));
+                }
+            } else {
+                // if we had > 1 single-edit neighbor
+                // and none was likely enough, then don't keep
+                // the barcode, but remember the count of such events
+                ambig_approx += count;
+                if write_corrections {
+                    correction_rows.push((*ubc, None, count as u64, CorrectionStatus::Ambiguous));
+                }
+                ambiguous_bcs.push((
+                    *ubc,
+                    count,
+                    neighbors
+                        .iter()
+                        .zip(posteriors.iter())
+                        .map(|((n, _), p)| (bcmap2.barcodes[*n], *p))
+                        .collect(),
+                ));
             }
         }
         distinct_unmatched_bc += 1;
     }
+    summary.corrected_reads = found_approx as u64;
+    summary.ambiguous_barcodes = ambiguous_bcs.len();
+    summary.ambiguous_reads = ambig_approx as u64;
+    summary.uncorrectable_reads = not_found as u64;
     let unmatched_duration = start_unmatched_time.elapsed();
     let num_corrected = distinct_recoverable_bc as u64;
 
@@ -344,39 +827,58 @@ fn process_unfiltered(
         log,
         "There were {} distinct unmatched barcodes, and {} that can be recovered",
         distinct_unmatched_bc,
-        distinct_recoverable_bc
+        distinct_recoverable_bc;
+        "num_unmatched_barcodes" => distinct_unmatched_bc,
+        "num_recoverable_barcodes" => distinct_recoverable_bc
     );
     info!(
         log,
-        "Matching unmatched barcodes to retained barcodes took {:?}", unmatched_duration
+        "Matching unmatched barcodes to retained barcodes took {:?}", unmatched_duration;
+        "wall_time_secs" => unmatched_duration.as_secs_f64()
     );
     info!(log, "Of the unmatched barcodes\n============");
     info!(
         log,
-        "\t{} had exactly 1 single-edit neighbor in the retained list",
-        found_approx.to_formatted_string(&Locale::en)
+        "\t{} were corrected to a single-edit neighbor in the retained list",
+        found_approx.to_formatted_string(&Locale::en);
+        "num_corrected_barcodes" => found_approx
+    );
+    info!(
+        log,
+        "\t\t({} of these through an indel, and {} by choosing the most likely of >1 neighbors)",
+        found_indel.to_formatted_string(&Locale::en),
+        found_by_posterior.to_formatted_string(&Locale::en);
+        "num_corrected_by_indel" => found_indel,
+        "num_corrected_by_posterior" => found_by_posterior
     );
     info!(
         log,
-        "\t{} had >1 single-edit neighbor in the retained list",
-        ambig_approx.to_formatted_string(&Locale::en)
+        "\t{} had >1 single-edit neighbor in the retained list and could not be resolved",
+        ambig_approx.to_formatted_string(&Locale::en);
+        "num_ambiguous_barcodes" => ambig_approx
     );
     info!(
         log,
         "\t{} had no neighbor in the retained list",
-        not_found.to_formatted_string(&Locale::en)
+        not_found.to_formatted_string(&Locale::en);
+        "num_uncorrectable_barcodes" => not_found
     );
 
     let parent = std::path::Path::new(&output_dir);
-    std::fs::create_dir_all(&parent).unwrap();
+    std::fs::create_dir_all(&parent)?;
+
+    log_correction_summary(&summary, log);
+    let bclen = ft_vals.bclen as u8;
+    if write_corrections {
+        // report every barcode whose correction was ambiguous, along with
+        // the candidates to which it might have been corrected.
+        write_ambiguous_table(parent, bclen, &mut ambiguous_bcs, log)?;
+        write_correction_table(parent, bclen, &mut correction_rows, log)?;
+    }
+
     let o_path = parent.join("permit_freq.bin");
 
-    match afutils::write_permit_list_freq(&o_path, ft_vals.bclen, &hm) {
-        Ok(_) => {}
-        Err(error) => {
-            panic!("Error: {}", error);
-        }
-    };
+    afutils::write_permit_list_freq(&o_path, ft_vals.bclen, &hm)?;
 
     /*
     // don't need this right now
@@ -397,9 +899,9 @@ fn process_unfiltered(
     }
 
     let pm_path = parent.join("permit_map.bin");
-    let pm_file = std::fs::File::create(&pm_path).expect("could not create serialization file.");
+    let pm_file = std::fs::File::create(&pm_path)?;
     let mut pm_writer = BufWriter::new(&pm_file);
-    bincode::serialize_into(&mut pm_writer, &hm).expect("couldn't serialize permit list mapping.");
+    bincode::serialize_into(&mut pm_writer, &hm)?;
 
     let meta_info = json!({
     "velo_mode" : velo_mode,
@@ -407,25 +909,29 @@ fn process_unfiltered(
     "version_str" : version,
     "max-ambig-record" : max_ambiguity_read,
     "cmd" : cmdline,
-    "permit-list-type" : "unfiltered"
+    "chemistry" : chemistry.map(|c| c.name),
+    "permit-list-type" : "unfiltered",
+    "barcode-correction" : {
+        "allow_indels" : correction_params.allow_indels,
+        "min_posterior" : correction_params.min_posterior
+    },
+    "correction-summary" : summary.to_json()
     });
 
     let m_path = parent.join("generate_permit_list.json");
-    let mut m_file = std::fs::File::create(&m_path).expect("could not create metadata file.");
+    let mut m_file = std::fs::File::create(&m_path)?;
 
-    let meta_info_string =
-        serde_json::to_string_pretty(&meta_info).expect("could not format json.");
-    m_file
-        .write_all(meta_info_string.as_bytes())
-        .expect("cannot write to generate_permit_list.json file");
+    let meta_info_string = serde_json::to_string_pretty(&meta_info)?;
+    m_file.write_all(meta_info_string.as_bytes())?;
 
     info!(
         log,
         "total number of distinct corrected barcodes : {}",
-        num_corrected.to_formatted_string(&Locale::en)
+        num_corrected.to_formatted_string(&Locale::en);
+        "num_corrected_barcodes" => num_corrected
     );
 
-    num_corrected
+    Ok(num_corrected)
 }
 
 #[allow(clippy::unnecessary_unwrap, clippy::too_many_arguments)]
@@ -438,9 +944,12 @@ fn process_filtered(
     version: &str,
     max_ambiguity_read: usize,
     velo_mode: bool,
+    correction_params: &BarcodeCorrectionParams,
+    write_corrections: bool,
+    chemistry: Option<&Chemistry>,
     cmdline: &str,
     log: &slog::Logger,
-) -> u64 {
+) -> Result<u64, Box<dyn std::error::Error>> {
     let valid_bc: Vec<u64>;
     let mut freq: Vec<u64> = hm.values().cloned().collect();
     freq.sort_unstable();
@@ -458,7 +967,8 @@ fn process_filtered(
             info!(
                 log,
                 "knee distance method resulted in the selection of {} permitted barcodes.",
-                valid_bc.len()
+                valid_bc.len();
+                "num_permitted_barcodes" => valid_bc.len()
             );
         }
         CellFilterMethod::ForceCells(top_k) => {
@@ -494,46 +1004,119 @@ fn process_filtered(
 
     // generate the map from each permitted barcode to all barcodes within
     // edit distance 1 of it.
-    let full_permit_list =
-        afutils::generate_permitlist_map(&valid_bc, ft_vals.bclen as usize).unwrap();
+    let (mut full_permit_list, ties) =
+        afutils::generate_permitlist_map_and_ties(&valid_bc, ft_vals.bclen as usize)?;
+
+    // as in the unfiltered case, a neighbor of several permitted barcodes
+    // can be corrected to the most likely of them given their number of
+    // exactly matching reads, or not at all if none is likely enough.  This
+    // is opt-in; with the default minimum posterior of 1, the map assigns
+    // it to the first of them, as in previous versions.
+    let s_amb = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut ambiguous_candidates: HashMap<u64, Vec<(u64, f64)>, ahash::RandomState> =
+        HashMap::with_hasher(s_amb);
+    if correction_params.min_posterior < 1.0 {
+        let bcmap = BarcodeLookupMap::new(valid_bc.clone(), ft_vals.bclen as u32);
+        let prior_counts: Vec<u64> = bcmap
+            .barcodes
+            .iter()
+            .map(|b| *hm.get(b).unwrap_or(&0))
+            .collect();
+        let mut neighbors = Vec::<(usize, bool)>::new();
+        let mut posteriors = Vec::<f64>::new();
+        for t in ties {
+            // the permit list map always includes the indel neighbors
+            bcmap.find_all_neighbors(t, true, &mut neighbors);
+            if neighbors.len() < 2 {
+                continue;
+            }
+            correction_posteriors(&neighbors, &prior_counts, &mut posteriors);
+            let (best, best_post) =
+                posteriors
+                    .iter()
+                    .enumerate()
+                    .fold(
+                        (0usize, 0f64),
+                        |acc, (i, p)| if *p > acc.1 { (i, *p) } else { acc },
+                    );
+            if best_post >= correction_params.min_posterior {
+                full_permit_list.insert(t, bcmap.barcodes[neighbors[best].0]);
+            } else {
+                full_permit_list.remove(&t);
+                ambiguous_candidates.insert(
+                    t,
+                    neighbors
+                        .iter()
+                        .zip(posteriors.iter())
+                        .map(|((n, _), p)| (bcmap.barcodes[*n], *p))
+                        .collect(),
+                );
+            }
+        }
+    }
 
     let s2 = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
     let mut permitted_map = HashMap::with_capacity_and_hasher(valid_bc.len(), s2);
 
+    let mut summary = CorrectionSummary::default();
+    let mut correction_rows = Vec::<(u64, Option<u64>, u64, CorrectionStatus)>::new();
+    let mut ambiguous_bcs = Vec::<AmbiguousBarcode>::new();
+
     let mut num_corrected = 0;
     for (k, v) in hm.iter() {
         if let Some(&valid_key) = full_permit_list.get(k) {
             *permitted_map.entry(valid_key).or_insert(0u64) += *v;
             num_corrected += 1;
             //println!("{} was a neighbor of {}, with count {}", k, valid_key, v);
+            let status = if valid_key == *k {
+                summary.exact_barcodes += 1;
+                summary.exact_reads += *v;
+                CorrectionStatus::Exact
+            } else {
+                summary.corrected_barcodes += 1;
+                summary.corrected_reads += *v;
+                CorrectionStatus::Corrected
+            };
+            if write_corrections {
+                correction_rows.push((*k, Some(valid_key), *v, status));
+            }
+        } else if let Some(candidates) = ambiguous_candidates.get(k) {
+            summary.ambiguous_barcodes += 1;
+            summary.ambiguous_reads += *v;
+            if write_corrections {
+                correction_rows.push((*k, None, *v, CorrectionStatus::Ambiguous));
+                ambiguous_bcs.push((*k, *v as usize, candidates.clone()));
+            }
+        } else {
+            summary.uncorrectable_barcodes += 1;
+            summary.uncorrectable_reads += *v;
+            if write_corrections {
+                correction_rows.push((*k, None, *v, CorrectionStatus::Uncorrectable));
+            }
         }
     }
 
+    log_correction_summary(&summary, log);
+
     let parent = std::path::Path::new(&output_dir);
-    std::fs::create_dir_all(&parent).unwrap();
+    std::fs::create_dir_all(&parent)?;
+    if write_corrections {
+        write_ambiguous_table(parent, ft_vals.bclen as u8, &mut ambiguous_bcs, log)?;
+        write_correction_table(parent, ft_vals.bclen as u8, &mut correction_rows, log)?;
+    }
+
     let o_path = parent.join("permit_freq.bin");
 
-    match afutils::write_permit_list_freq(&o_path, ft_vals.bclen, &permitted_map) {
-        Ok(_) => {}
-        Err(error) => {
-            panic!("Error: {}", error);
-        }
-    };
+    afutils::write_permit_list_freq(&o_path, ft_vals.bclen, &permitted_map)?;
 
     let o_path = parent.join("all_freq.bin");
 
-    match afutils::write_permit_list_freq(&o_path, ft_vals.bclen, hm) {
-        Ok(_) => {}
-        Err(error) => {
-            panic!("Error: {}", error);
-        }
-    };
+    afutils::write_permit_list_freq(&o_path, ft_vals.bclen, hm)?;
 
     let s_path = parent.join("permit_map.bin");
-    let s_file = std::fs::File::create(&s_path).expect("could not create serialization file.");
+    let s_file = std::fs::File::create(&s_path)?;
     let mut s_writer = BufWriter::new(&s_file);
-    bincode::serialize_into(&mut s_writer, &full_permit_list)
-        .expect("couldn't serialize permit list.");
+    bincode::serialize_into(&mut s_writer, &full_permit_list)?;
 
     let meta_info = json!({
     "velo_mode" : velo_mode,
@@ -541,74 +1124,339 @@ fn process_filtered(
     "version_str" : version,
     "max-ambig-record" : max_ambiguity_read,
     "cmd" : cmdline,
-    "permit-list-type" : "filtered"
+    "chemistry" : chemistry.map(|c| c.name),
+    "permit-list-type" : "filtered",
+    "barcode-correction" : {
+        "allow_indels" : true,
+        "min_posterior" : correction_params.min_posterior
+    },
+    "correction-summary" : summary.to_json()
     });
 
     let m_path = parent.join("generate_permit_list.json");
-    let mut m_file = std::fs::File::create(&m_path).expect("could not create metadata file.");
+    let mut m_file = std::fs::File::create(&m_path)?;
 
-    let meta_info_string =
-        serde_json::to_string_pretty(&meta_info).expect("could not format json.");
-    m_file
-        .write_all(meta_info_string.as_bytes())
-        .expect("cannot write to generate_permit_list.json file");
+    let meta_info_string = serde_json::to_string_pretty(&meta_info)?;
+    m_file.write_all(meta_info_string.as_bytes())?;
 
     info!(
         log,
         "total number of distinct corrected barcodes : {}",
-        num_corrected.to_formatted_string(&Locale::en)
+        num_corrected.to_formatted_string(&Locale::en);
+        "num_corrected_barcodes" => num_corrected
+    );
+
+    Ok(num_corrected)
+}
+
+/// The number of reads whose alignments are all in the forward
+/// orientation (`fw`), all in the reverse complement orientation (`rc`),
+/// or in both orientations (`both`).
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OrientationCounts {
+    pub fw: u64,
+    pub rc: u64,
+    pub both: u64,
+}
+
+/// The smallest fraction of the (unambiguously oriented) reads that must
+/// be in one orientation for `auto` to infer that orientation.
+const AUTO_ORI_MIN_FRAC: f64 = 0.8;
+
+impl OrientationCounts {
+    /// The orientation class (0 = fw, 1 = rc, 2 = both) of a read
+    /// with the alignment orientations `dirs`, if it has any alignments.
+    fn class(dirs: &[bool]) -> Option<usize> {
+        let any_fw = dirs.iter().any(|&x| x);
+        let any_rc = dirs.iter().any(|&x| !x);
+        match (any_fw, any_rc) {
+            (true, false) => Some(0),
+            (false, true) => Some(1),
+            (true, true) => Some(2),
+            (false, false) => None,
+        }
+    }
+
+    fn add_class(&mut self, class: usize, count: u64) {
+        match class {
+            0 => self.fw += count,
+            1 => self.rc += count,
+            _ => self.both += count,
+        }
+    }
+
+    fn merge(&mut self, other: &OrientationCounts) {
+        self.fw += other.fw;
+        self.rc += other.rc;
+        self.both += other.both;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.fw + self.rc + self.both
+    }
+
+    /// The number of reads with at least one alignment in the
+    /// orientation `expected_ori`.
+    pub fn num_compatible(&self, expected_ori: &Strand) -> u64 {
+        match expected_ori {
+            Strand::Forward => self.fw + self.both,
+            Strand::Reverse => self.rc + self.both,
+            Strand::Unknown => self.total(),
+        }
+    }
+
+    /// Infer the expected orientation from the counts; if neither
+    /// orientation accounts for at least [`AUTO_ORI_MIN_FRAC`] of the
+    /// reads mapping in a single orientation, reads of both are kept.
+    pub fn infer_orientation(&self) -> Strand {
+        let oriented = (self.fw + self.rc) as f64;
+        if oriented > 0.0 && self.fw as f64 >= AUTO_ORI_MIN_FRAC * oriented {
+            Strand::Forward
+        } else if oriented > 0.0 && self.rc as f64 >= AUTO_ORI_MIN_FRAC * oriented {
+            Strand::Reverse
+        } else {
+            Strand::Unknown
+        }
+    }
+}
+
+/// The number of shards into which the barcode histogram is split. It
+/// doesn't depend on the number of threads, so that neither does the set
+/// of barcodes in each shard.
+const NUM_BARCODE_SHARDS: usize = 64;
+
+/// The shard of the barcode histogram holding the barcode `bc`.
+#[inline]
+fn barcode_shard(bc: u64) -> usize {
+    // use the top bits of a multiplicative hash, as the low bits of
+    // the barcodes in a sample are far from uniformly distributed
+    (bc.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - NUM_BARCODE_SHARDS.trailing_zeros())) as usize
+}
+
+type BarcodeShard = HashMap<u64, OrientationCounts, ahash::RandomState>;
+
+/// The orientation counts of the barcodes in a RAD file (split by
+/// [`barcode_shard`] into [`NUM_BARCODE_SHARDS`] maps), along with the
+/// total number of reads, the total size (in bytes) of their records and
+/// the largest number of references of any read in each orientation class.
+struct BarcodeHist {
+    shards: Vec<BarcodeShard>,
+    num_reads: usize,
+    num_record_bytes: u64,
+    max_ambiguity_read: [usize; 3],
+}
+
+impl BarcodeHist {
+    /// The largest number of references of any read compatible
+    /// with `expected_ori`.
+    fn max_ambiguity_read(&self, expected_ori: &Strand) -> usize {
+        let m = &self.max_ambiguity_read;
+        match expected_ori {
+            Strand::Forward => m[0].max(m[2]),
+            Strand::Reverse => m[1].max(m[2]),
+            Strand::Unknown => m[0].max(m[1]).max(m[2]),
+        }
+    }
+
+    /// The orientation counts of every barcode, a shard at a time.
+    fn iter(&self) -> impl Iterator<Item = (&u64, &OrientationCounts)> {
+        self.shards.iter().flatten()
+    }
+}
+
+/// Count the reads in each orientation of each barcode in the `num_chunks`
+/// chunks of the RAD file `br`. The chunks are parsed by `num_threads`
+/// worker threads. After each work buffer, a thread moves the counts it
+/// collected into the shared, sharded histogram, so that (beyond the
+/// histogram itself) each thread only holds the barcodes of one buffer.
+/// The progress of reading the chunks is reported through `log`.
+fn count_barcodes<T: Read>(
+    br: T,
+    num_chunks: usize,
+    bc_type: rad_types::RadIntId,
+    umi_type: rad_types::RadIntId,
+    num_threads: u32,
+    log: &slog::Logger,
+) -> Result<BarcodeHist, Box<dyn std::error::Error>> {
+    let n_workers = num_threads.max(1) as usize;
+    let q = Arc::new(ArrayQueue::<io_utils::MetaChunk>::new(4 * n_workers));
+    let chunks_remaining = Arc::new(AtomicUsize::new(num_chunks));
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let shards: Arc<Vec<Mutex<BarcodeShard>>> = Arc::new(
+        (0..NUM_BARCODE_SHARDS)
+            .map(|_| Mutex::new(HashMap::with_hasher(s.clone())))
+            .collect(),
     );
 
-    num_corrected
+    let mut thread_handles = Vec::with_capacity(n_workers);
+    for _ in 0..n_workers {
+        let in_q = q.clone();
+        let chunks_remaining = chunks_remaining.clone();
+        let shards = shards.clone();
+        let s = s.clone();
+        thread_handles.push(thread::spawn(move || {
+            let mut local: BarcodeShard = HashMap::with_hasher(s);
+            let mut staged: Vec<Vec<(u64, OrientationCounts)>> =
+                (0..NUM_BARCODE_SHARDS).map(|_| Vec::new()).collect();
+            let mut num_reads = 0usize;
+            let mut num_record_bytes = 0u64;
+            let mut max_ambiguity_read = [0usize; 3];
+            while chunks_remaining.load(Ordering::SeqCst) > 0 {
+                if let Some((_, chunks_in_buf, _, _, buf)) = in_q.pop() {
+                    let mut byte_offset = 0usize;
+                    for _ in 0..chunks_in_buf {
+                        let nbytes = buf[byte_offset..].pread::<u32>(0).unwrap() as usize;
+                        let mut nbr = &buf[byte_offset..(byte_offset + nbytes)];
+                        byte_offset += nbytes;
+                        let c = rad_types::Chunk::from_bytes(&mut nbr, &bc_type, &umi_type);
+                        update_barcode_hist(&mut local, &mut max_ambiguity_read, &c);
+                        num_reads += c.reads.len();
+                        // the chunk header (nbytes and nrec) is not part of any record
+                        num_record_bytes += (nbytes - 8) as u64;
+                        chunks_remaining.fetch_sub(1, Ordering::SeqCst);
+                    }
+
+                    // move the counts of this buffer into the shared histogram
+                    for (bc, oc) in local.drain() {
+                        staged[barcode_shard(bc)].push((bc, oc));
+                    }
+                    for (shard, st) in shards.iter().zip(staged.iter_mut()) {
+                        if st.is_empty() {
+                            continue;
+                        }
+                        let mut shard = shard.lock().unwrap();
+                        for (bc, oc) in st.drain(..) {
+                            shard.entry(bc).or_default().merge(&oc);
+                        }
+                    }
+                }
+            }
+            (num_reads, num_record_bytes, max_ambiguity_read)
+        }));
+    }
+
+    let pbar = logging::progress_bar(num_chunks as u64, "counting barcodes", log);
+    pbar.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} {msg}",
+            )
+            .progress_chars("╢▌▌░╟"),
+    );
+    pbar.set_draw_delta(500_u64.min(num_chunks as u64 / 10));
+    io_utils::fill_work_queue(q, br, num_chunks, &pbar)?;
+
+    let mut num_reads = 0usize;
+    let mut num_record_bytes = 0u64;
+    let mut max_ambiguity_read = [0usize; 3];
+    for h in thread_handles {
+        let (nr, nb, mar) = h.join().map_err(|_| "a barcode counting thread panicked")?;
+        num_reads += nr;
+        num_record_bytes += nb;
+        for (m, lm) in max_ambiguity_read.iter_mut().zip(mar) {
+            *m = (*m).max(lm);
+        }
+    }
+    pbar.finish_with_message("counted the barcodes of all chunks.");
+
+    // every worker has been joined, so this is the last reference
+    let shards = Arc::try_unwrap(shards)
+        .map_err(|_| "the barcode histogram is still shared")?
+        .into_iter()
+        .map(|m| m.into_inner().unwrap_or_else(|e| e.into_inner()))
+        .collect();
+    Ok(BarcodeHist {
+        shards,
+        num_reads,
+        num_record_bytes,
+        max_ambiguity_read,
+    })
 }
 
-/// Given the input RAD file `input_file`, compute
-/// and output (in `output_dir`) the list of valid
+/// Write the orientation counts of each barcode in `hist`, from the most
+/// to the least observed, to the TSV file `o_path`.
+fn write_orientation_counts(
+    o_path: &std::path::Path,
+    bclen: u8,
+    hist: &BarcodeHist,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rows: Vec<(&u64, &OrientationCounts)> = hist.iter().collect();
+    rows.sort_unstable_by(|a, b| b.1.total().cmp(&a.1.total()).then_with(|| a.0.cmp(b.0)));
+
+    let mut writer = BufWriter::new(File::create(o_path)?);
+    writeln!(writer, "barcode\tfw\trc\tboth")?;
+    for (bc, oc) in rows {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            bc_to_string(*bc, bclen),
+            oc.fw,
+            oc.rc,
+            oc.both
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Given the input RAD files (the map.rad files in
+/// each of the `rad_dirs` of `config`), compute
+/// and output (in its `output_dir`) the list of valid
 /// (i.e. "permitted") barcode values, as well as
 /// a map from each correctable barcode to the
-/// permitted barcode to which it maps.
-#[allow(clippy::too_many_arguments)]
+/// permitted barcode to which it maps. If its `expected_ori`
+/// is `None`, the expected orientation is inferred from
+/// the orientation of the mapped reads.
 pub fn generate_permit_list(
-    rad_dir: String,
-    output_dir: String,
-    filter_meth: CellFilterMethod,
-    expected_ori: Strand,
-    version: &str,
-    velo_mode: bool,
-    cmdline: &str,
-    //top_k: Option<usize>,
-    //valid_bc_file: Option<String>,
-    //use_knee_distance: bool,
+    config: PermitListConfig,
     log: &slog::Logger,
-) -> Result<u64, Box<dyn std::error::Error>> {
-    let i_dir = std::path::Path::new(&rad_dir);
-
-    if !i_dir.exists() {
-        crit!(log, "the input RAD path {} does not exist", rad_dir);
-        // std::process::exit(1);
-        return Err("execution terminated unexpectedly".into());
+) -> Result<PermitListStats, Box<dyn std::error::Error>> {
+    let PermitListConfig {
+        rad_dirs,
+        output_dir,
+        filter_meth,
+        expected_ori,
+        velo_mode,
+        correction_params,
+        write_corrections,
+        chemistry,
+        num_threads,
+        cmdline,
+        version,
+    } = config;
+    let version = version.as_str();
+    let cmdline = cmdline.as_str();
+    for rad_dir in &rad_dirs {
+        if !std::path::Path::new(rad_dir).exists() {
+            crit!(log, "the input RAD path {} does not exist", rad_dir);
+            // std::process::exit(1);
+            return Err("execution terminated unexpectedly".into());
+        }
     }
+    let rad_files = io_utils::rad_file_paths(&rad_dirs);
+    let mut metrics = afutils::StageMetrics::new(num_threads);
+    metrics.add_files_read(&rad_files);
 
     let mut first_bclen = 0usize;
     let mut unfiltered_bc_counts = None;
     if let CellFilterMethod::UnfilteredExternalList(fname, _) = &filter_meth {
-        let i_file = File::open(&fname).expect("could not open input file");
+        metrics.add_files_read(&[fname]);
+        let i_file = File::open(&fname)?;
         let br = BufReader::new(i_file);
         unfiltered_bc_counts = Some(populate_unfiltered_barcode_map(br, &mut first_bclen));
+        let num_known = unfiltered_bc_counts.as_ref().unwrap().num_known();
         info!(
             log,
             "number of unfiltered bcs read = {}",
-            unfiltered_bc_counts
-                .as_ref()
-                .unwrap()
-                .len()
-                .to_formatted_string(&Locale::en)
+            num_known.to_formatted_string(&Locale::en);
+            "num_unfiltered_barcodes" => num_known
         );
     }
 
-    let i_file = File::open(i_dir.join("map.rad")).expect("could not open input rad file");
+    let i_file = File::open(&rad_files[0])?;
     let mut br = BufReader::new(i_file);
-    let hdr = rad_types::RadHeader::from_bytes(&mut br);
+    let prelude = io_utils::RadPrelude::from_bytes(&mut br);
+    let hdr = &prelude.hdr;
     info!(
         log,
         "paired : {:?}, ref_count : {}, num_chunks : {}",
@@ -617,10 +1465,9 @@ pub fn generate_permit_list(
         hdr.num_chunks.to_formatted_string(&Locale::en)
     );
     // file-level
-    let fl_tags = rad_types::TagSection::from_bytes(&mut br);
-    info!(log, "read {:?} file-level tags", fl_tags.tags.len());
+    info!(log, "read {:?} file-level tags", prelude.fl_tags.tags.len());
     // read-level
-    let rl_tags = rad_types::TagSection::from_bytes(&mut br);
+    let rl_tags = &prelude.rl_tags;
     info!(log, "read {:?} read-level tags", rl_tags.tags.len());
 
     // right now, we only handle BC and UMI types of U8—U64, so validate that
@@ -638,7 +1485,9 @@ pub fn generate_permit_list(
                     log,
                     "currently only RAD types 1--4 are supported for 'b' and 'u' tags."
                 );
-                std::process::exit(exit_codes::EXIT_UNSUPPORTED_TAG_TYPE);
+                return Err(
+                    format!("unsupported RAD type {} for tag '{}'.", rt.typeid, rt.name).into(),
+                );
             }
 
             if rt.name == BNAME {
@@ -651,97 +1500,241 @@ pub fn generate_permit_list(
     }
 
     // alignment-level
-    let al_tags = rad_types::TagSection::from_bytes(&mut br);
-    info!(log, "read {:?} alignemnt-level tags", al_tags.tags.len());
+    info!(
+        log,
+        "read {:?} alignemnt-level tags",
+        prelude.al_tags.tags.len()
+    );
 
-    let ft_vals = rad_types::FileTags::from_bytes(&mut br);
+    let ft_vals = &prelude.ft_vals;
     info!(log, "File-level tag values {:?}", ft_vals);
 
-    let mut num_reads: usize = 0;
+    if let Some(chem) = chemistry {
+        if let Err(e) = chem.validate_file_tags(ft_vals) {
+            crit!(log, "{}", e);
+            return Err(Box::new(e));
+        }
+        info!(
+            log,
+            "RAD file geometry is consistent with the {} chemistry", chem.name
+        );
+    }
 
     let bc_type = rad_types::decode_int_type_tag(bct.expect("no barcode tag description present."))
         .expect("unknown barcode type id.");
     let umi_type = rad_types::decode_int_type_tag(umit.expect("no umi tag description present"))
         .expect("unknown barcode type id.");
 
-    // if dealing with filtered type
-    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
-    let mut hm = HashMap::with_hasher(s);
+    // the records of the other inputs are read after those of the first
+    let (br, num_chunks) = io_utils::chain_rad_inputs(br, &prelude, &rad_files[1..])?;
+    if rad_files.len() > 1 {
+        info!(
+            log,
+            "reading {} chunks from {} RAD files",
+            num_chunks.to_formatted_string(&Locale::en),
+            rad_files.len()
+        );
+    }
 
-    // if dealing with the unfiltered type
-    // the set of barcodes that are not an exact match for any known barcodes
-    let mut unmatched_bc: Vec<u64>;
-    let mut num_orientation_compat_reads = 0usize;
-    let mut max_ambiguity_read = 0usize;
+    // count the reads of each barcode in each orientation
+    let bc_hist = count_barcodes(br, num_chunks as usize, bc_type, umi_type, num_threads, log)?;
+    let num_reads = bc_hist.num_reads;
+    let num_record_bytes = bc_hist.num_record_bytes;
+    metrics.end_phase("reading");
 
-    match filter_meth {
+    let mut ori_counts = OrientationCounts::default();
+    for (_, oc) in bc_hist.iter() {
+        ori_counts.merge(oc);
+    }
+    info!(
+        log,
+        "reads mapping only in the forward orientation : {}, only in the reverse complement orientation : {}, in both orientations : {}",
+        ori_counts.fw.to_formatted_string(&Locale::en),
+        ori_counts.rc.to_formatted_string(&Locale::en),
+        ori_counts.both.to_formatted_string(&Locale::en);
+        "num_forward_reads" => ori_counts.fw,
+        "num_reverse_reads" => ori_counts.rc,
+        "num_both_orientation_reads" => ori_counts.both
+    );
+
+    let ori_inferred = expected_ori.is_none();
+    let expected_ori = match expected_ori {
+        Some(o) => o,
+        None => {
+            let o = ori_counts.infer_orientation();
+            info!(
+                log,
+                "inferred the expected orientation {} from the mapped reads",
+                o.strand_symbol()
+            );
+            o
+        }
+    };
+    let num_orientation_compat_reads = ori_counts.num_compatible(&expected_ori);
+    if num_orientation_compat_reads * 2 < ori_counts.total() {
+        warn!(
+            log,
+            "only {:.1}% of the mapped reads are consistent with the expected orientation ({}); check that --expected-ori is correct",
+            100.0 * num_orientation_compat_reads as f64 / ori_counts.total() as f64,
+            expected_ori.strand_symbol()
+        );
+    }
+    let max_ambiguity_read = bc_hist.max_ambiguity_read(&expected_ori);
+
+    let parent = std::path::Path::new(&output_dir);
+    std::fs::create_dir_all(parent)?;
+    write_orientation_counts(
+        &parent.join("orientation_counts.tsv"),
+        ft_vals.bclen as u8,
+        &bc_hist,
+    )?;
+
+    // only the orientation compatible reads of each barcode are kept
+    let num_permitted = match filter_meth {
         CellFilterMethod::UnfilteredExternalList(_, _min_reads) => {
-            unmatched_bc = Vec::with_capacity(10000000);
             // the unfiltered_bc_count map must be valid in this branch
             if let Some(mut hmu) = unfiltered_bc_counts {
-                for _ in 0..(hdr.num_chunks as usize) {
-                    let c = rad_types::Chunk::from_bytes(&mut br, &bc_type, &umi_type);
-                    num_orientation_compat_reads += update_barcode_hist_unfiltered(
-                        &mut hmu,
-                        &mut unmatched_bc,
-                        &mut max_ambiguity_read,
-                        &c,
-                        &expected_ori,
-                    );
-                    num_reads += c.reads.len();
+                for shard in bc_hist.shards {
+                    for (bc, oc) in shard {
+                        let count = oc.num_compatible(&expected_ori);
+                        if count > 0 {
+                            hmu.add_count(bc, count);
+                        }
+                    }
                 }
                 info!(
-		     log,
-		     "observed {} reads ({} orientation consistent) in {} chunks --- max ambiguity read occurs in {} refs",
-		     num_reads.to_formatted_string(&Locale::en),
-		     num_orientation_compat_reads.to_formatted_string(&Locale::en),
-		     hdr.num_chunks.to_formatted_string(&Locale::en),
-		     max_ambiguity_read.to_formatted_string(&Locale::en)
-		 );
-                Ok(process_unfiltered(
+                    log,
+                    "observed {} reads ({} orientation consistent) in {} chunks --- max ambiguity read occurs in {} refs",
+                    num_reads.to_formatted_string(&Locale::en),
+                    num_orientation_compat_reads.to_formatted_string(&Locale::en),
+                    num_chunks.to_formatted_string(&Locale::en),
+                    max_ambiguity_read.to_formatted_string(&Locale::en);
+                    "num_reads" => num_reads,
+                    "num_orientation_consistent_reads" => num_orientation_compat_reads,
+                    "num_chunks" => num_chunks
+                );
+                process_unfiltered(
                     hmu,
-                    unmatched_bc,
-                    &ft_vals,
+                    ft_vals,
                     &filter_meth,
                     expected_ori,
                     &output_dir,
                     version,
                     max_ambiguity_read,
                     velo_mode,
+                    &correction_params,
+                    write_corrections,
+                    chemistry,
                     cmdline,
                     log,
-                ))
+                )?
             } else {
-                Ok(0)
+                return Ok(PermitListStats {
+                    num_reads,
+                    num_chunks,
+                    orientation_counts: ori_counts,
+                    expected_ori,
+                    num_orientation_consistent_reads: num_orientation_compat_reads,
+                    max_ambiguity_read,
+                    num_corrected_barcodes: 0,
+                });
             }
         }
         _ => {
-            for _ in 0..(hdr.num_chunks as usize) {
-                let c = rad_types::Chunk::from_bytes(&mut br, &bc_type, &umi_type);
-                update_barcode_hist(&mut hm, &mut max_ambiguity_read, &c, &expected_ori);
-                num_reads += c.reads.len();
+            // the barcodes are inserted shard by shard, and in sorted order
+            // within each shard, so that the iteration order of the map (and
+            // thereby the output) doesn't depend on the number of threads.
+            let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+            let num_bc = bc_hist.shards.iter().map(|m| m.len()).sum();
+            let mut hm = HashMap::with_capacity_and_hasher(num_bc, s);
+            for shard in bc_hist.shards {
+                let mut counts: Vec<(u64, u64)> = shard
+                    .into_iter()
+                    .map(|(bc, oc)| (bc, oc.num_compatible(&expected_ori)))
+                    .filter(|(_, count)| *count > 0)
+                    .collect();
+                counts.sort_unstable();
+                hm.extend(counts);
             }
             info!(
                 log,
                 "observed {} reads in {} chunks --- max ambiguity read occurs in {} refs",
                 num_reads.to_formatted_string(&Locale::en),
-                hdr.num_chunks.to_formatted_string(&Locale::en),
-                max_ambiguity_read.to_formatted_string(&Locale::en)
+                num_chunks.to_formatted_string(&Locale::en),
+                max_ambiguity_read.to_formatted_string(&Locale::en);
+                "num_reads" => num_reads,
+                "num_chunks" => num_chunks
             );
-            Ok(process_filtered(
+            process_filtered(
                 &hm,
-                &ft_vals,
+                ft_vals,
                 &filter_meth,
                 expected_ori,
                 &output_dir,
                 version,
                 max_ambiguity_read,
                 velo_mode,
+                &correction_params,
+                write_corrections,
+                chemistry,
                 cmdline,
                 log,
-            ))
+            )?
         }
-    }
+    };
+
+    metrics.end_phase("correction");
+
+    let m_path = parent.join("generate_permit_list.json");
+    afutils::add_meta_entry(
+        &m_path,
+        "orientation-counts",
+        json!({
+            "fw" : ori_counts.fw,
+            "rc" : ori_counts.rc,
+            "both" : ori_counts.both,
+            "orientation_consistent" : num_orientation_compat_reads,
+            "expected_ori_inferred" : ori_inferred,
+            "per_barcode_file" : "orientation_counts.tsv"
+        }),
+    )?;
+    // used by collate to translate its memory budget into records
+    afutils::add_meta_entry(
+        &m_path,
+        "record-stats",
+        json!({
+            "num_records" : num_reads,
+            "num_record_bytes" : num_record_bytes
+        }),
+    )?;
+
+    // record the sizes of the input and output files, so that
+    // collate can detect if they have changed since
+    afutils::record_file_sizes(
+        &m_path,
+        "input_file_sizes",
+        std::path::Path::new(""),
+        &io_utils::rad_file_keys(&rad_files),
+    )?;
+    afutils::record_file_sizes(
+        &m_path,
+        "output_file_sizes",
+        parent,
+        &["permit_freq.bin", "all_freq.bin", "permit_map.bin"],
+    )?;
+    metrics.end_phase("writing");
+    metrics.add_files_written_in(parent);
+    metrics.log(log);
+    metrics.write(&m_path)?;
+    Ok(PermitListStats {
+        num_reads,
+        num_chunks,
+        orientation_counts: ori_counts,
+        expected_ori,
+        num_orientation_consistent_reads: num_orientation_compat_reads,
+        max_ambiguity_read,
+        num_corrected_barcodes: num_permitted,
+    })
 
     /*
     let valid_bc: Vec<u64>;
@@ -872,99 +1865,17 @@ pub fn generate_permit_list(
     */
 }
 
-pub fn update_barcode_hist_unfiltered(
-    hist: &mut HashMap<u64, u64, ahash::RandomState>,
-    unmatched_bc: &mut Vec<u64>,
-    max_ambiguity_read: &mut usize,
-    chunk: &rad_types::Chunk,
-    expected_ori: &Strand,
-) -> usize {
-    let mut num_strand_compat_reads = 0usize;
-    match expected_ori {
-        Strand::Unknown => {
-            for r in &chunk.reads {
-                num_strand_compat_reads += 1;
-                *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                // lookup the barcode in the map of unfiltered known
-                // barcodes
-                match hist.get_mut(&r.bc) {
-                    // if we find a match, increment the count
-                    Some(c) => *c += 1,
-                    // otherwise, push this into the unmatched list
-                    None => {
-                        unmatched_bc.push(r.bc);
-                    }
-                }
-            }
-        }
-        Strand::Forward => {
-            for r in &chunk.reads {
-                if r.dirs.iter().any(|&x| x) {
-                    num_strand_compat_reads += 1;
-                    *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                    // lookup the barcode in the map of unfiltered known
-                    // barcodes
-                    match hist.get_mut(&r.bc) {
-                        // if we find a match, increment the count
-                        Some(c) => *c += 1,
-                        // otherwise, push this into the unmatched list
-                        None => {
-                            unmatched_bc.push(r.bc);
-                        }
-                    }
-                }
-            }
-        }
-        Strand::Reverse => {
-            for r in &chunk.reads {
-                if r.dirs.iter().any(|&x| !x) {
-                    num_strand_compat_reads += 1;
-                    *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                    // lookup the barcode in the map of unfiltered known
-                    // barcodes
-                    match hist.get_mut(&r.bc) {
-                        // if we find a match, increment the count
-                        Some(c) => *c += 1,
-                        // otherwise, push this into the unmatched list
-                        None => {
-                            unmatched_bc.push(r.bc);
-                        }
-                    }
-                }
-            }
-        }
-    }
-    num_strand_compat_reads
-}
-
+/// Count the reads of `chunk` in `hist` by barcode and orientation class,
+/// and update the largest number of references of any read in each class.
 pub fn update_barcode_hist(
-    hist: &mut HashMap<u64, u64, ahash::RandomState>,
-    max_ambiguity_read: &mut usize,
+    hist: &mut HashMap<u64, OrientationCounts, ahash::RandomState>,
+    max_ambiguity_read: &mut [usize; 3],
     chunk: &rad_types::Chunk,
-    expected_ori: &Strand,
 ) {
-    match expected_ori {
-        Strand::Unknown => {
-            for r in &chunk.reads {
-                *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                *hist.entry(r.bc).or_insert(0) += 1;
-            }
-        }
-        Strand::Forward => {
-            for r in &chunk.reads {
-                if r.dirs.iter().any(|&x| x) {
-                    *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                    *hist.entry(r.bc).or_insert(0) += 1;
-                }
-            }
-        }
-        Strand::Reverse => {
-            for r in &chunk.reads {
-                if r.dirs.iter().any(|&x| !x) {
-                    *max_ambiguity_read = r.refs.len().max(*max_ambiguity_read);
-                    *hist.entry(r.bc).or_insert(0) += 1;
-                }
-            }
+    for r in &chunk.reads {
+        if let Some(class) = OrientationCounts::class(&r.dirs) {
+            max_ambiguity_read[class] = r.refs.len().max(max_ambiguity_read[class]);
+            hist.entry(r.bc).or_default().add_class(class, 1);
         }
     }
 }
@@ -993,3 +1904,269 @@ pub fn permit_list_from_file(ifile: String, bclen: u16) -> Vec<u64> {
     }
     bc
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn permit_list_builder_rejects_invalid_values() {
+        let builder = || PermitListConfig::builder(vec!["map".to_string()], "out");
+        assert!(builder().build().is_ok());
+        assert!(PermitListConfig::builder(Vec::new(), "out")
+            .build()
+            .is_err());
+        assert!(matches!(
+            builder().num_threads(0).build(),
+            Err(afutils::ConfigError::InvalidValue {
+                name: "threads",
+                ..
+            })
+        ));
+        assert!(matches!(
+            builder()
+                .filter_method(CellFilterMethod::UnfilteredExternalList(
+                    "pl.txt".to_string(),
+                    0
+                ))
+                .build(),
+            Err(afutils::ConfigError::InvalidValue {
+                name: "min-reads",
+                ..
+            })
+        ));
+        for min_posterior in [0.5, 1.5] {
+            let params = BarcodeCorrectionParams {
+                allow_indels: false,
+                min_posterior,
+            };
+            assert!(matches!(
+                builder().correction_params(params).build(),
+                Err(afutils::ConfigError::InvalidValue {
+                    name: "min-correction-posterior",
+                    ..
+                })
+            ));
+        }
+    }
+
+    /// A fresh scratch directory for the test `name`.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let d = std::env::temp_dir().join(format!("af_cellfilter_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&d);
+        std::fs::create_dir_all(&d).unwrap();
+        d
+    }
+
+    fn push_str(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(&(s.len() as u16).to_le_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    /// Write a RAD file with 16bp barcodes and 10bp UMIs holding the
+    /// (barcode, umi, [compressed orientation and reference]) `records`,
+    /// `chunk_size` records per chunk, to `dir`/map.rad.
+    fn write_rad(dir: &Path, records: &[(u32, u32, Vec<u32>)], chunk_size: usize) {
+        let mut buf = Vec::<u8>::new();
+        buf.push(0u8);
+        buf.extend_from_slice(&2u64.to_le_bytes());
+        push_str(&mut buf, "t1");
+        push_str(&mut buf, "t2");
+        let num_chunks = (records.len() + chunk_size - 1) / chunk_size;
+        buf.extend_from_slice(&(num_chunks as u64).to_le_bytes());
+        for tags in [
+            &[("cblen", 2u8), ("ulen", 2u8)][..],
+            &[("b", 3u8), ("u", 3u8)][..],
+            &[("compressed_ori_refid", 3u8)][..],
+        ] {
+            buf.extend_from_slice(&(tags.len() as u16).to_le_bytes());
+            for (name, typeid) in tags {
+                push_str(&mut buf, name);
+                buf.push(*typeid);
+            }
+        }
+        buf.extend_from_slice(&16u16.to_le_bytes());
+        buf.extend_from_slice(&10u16.to_le_bytes());
+
+        for chunk in records.chunks(chunk_size) {
+            let mut cbuf = Vec::<u8>::new();
+            for (bc, umi, refs) in chunk {
+                cbuf.extend_from_slice(&(refs.len() as u32).to_le_bytes());
+                cbuf.extend_from_slice(&bc.to_le_bytes());
+                cbuf.extend_from_slice(&umi.to_le_bytes());
+                for r in refs {
+                    cbuf.extend_from_slice(&r.to_le_bytes());
+                }
+            }
+            buf.extend_from_slice(&(cbuf.len() as u32 + 8).to_le_bytes());
+            buf.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&cbuf);
+        }
+        std::fs::write(dir.join("map.rad"), buf).unwrap();
+    }
+
+    /// Records of a few hundred "cells" with many reads, and of many
+    /// barcodes with a few reads (some a substitution away from a cell).
+    fn simulated_records() -> Vec<(u32, u32, Vec<u32>)> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let cells: Vec<u32> = (0..300).map(|_| next() as u32).collect();
+        let mut records = Vec::new();
+        for i in 0..20_000 {
+            let r = next();
+            let bc = match i % 4 {
+                0 => r as u32,
+                1 => cells[(r % 300) as usize] ^ (1 << (2 * (r >> 40) % 32)),
+                _ => cells[(r % 300) as usize],
+            };
+            let fw = 0x8000_0000u32 * ((r >> 20) % 5 != 0) as u32;
+            records.push((
+                bc,
+                (r >> 32) as u32 & 0xfffff,
+                vec![fw | ((r >> 8) % 2) as u32],
+            ));
+        }
+        records
+    }
+
+    /// Run generate-permit-list on `rad_dir` with `num_threads` threads, and
+    /// return the permit_freq.bin and permit_map.bin files it wrote.
+    fn permit_list_files(
+        rad_dir: &Path,
+        out_dir: &Path,
+        filter_meth: CellFilterMethod,
+        num_threads: u32,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let config = PermitListConfig::builder(
+            vec![rad_dir.to_str().unwrap().to_string()],
+            out_dir.to_str().unwrap(),
+        )
+        .filter_method(filter_meth)
+        .expected_ori(Some(Strand::Forward))
+        .num_threads(num_threads)
+        .build()
+        .unwrap();
+        generate_permit_list(config, &log).unwrap();
+        (
+            std::fs::read(out_dir.join("permit_freq.bin")).unwrap(),
+            std::fs::read(out_dir.join("permit_map.bin")).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_permit_list_independent_of_threads() {
+        let d = scratch_dir("threads");
+        let records = simulated_records();
+        write_rad(&d, &records, 37);
+
+        // the unfiltered list holds every other cell barcode
+        let list_path = d.join("unfiltered.txt");
+        let mut cells: Vec<u32> = records.iter().skip(2).step_by(4).map(|r| r.0).collect();
+        cells.sort_unstable();
+        cells.dedup();
+        let list: String = cells
+            .iter()
+            .step_by(2)
+            .map(|bc| format!("{}\n", bc_to_string(*bc as u64, 16)))
+            .collect();
+        std::fs::write(&list_path, list).unwrap();
+
+        let methods = [
+            CellFilterMethod::ForceCells(200),
+            CellFilterMethod::UnfilteredExternalList(list_path.to_str().unwrap().to_string(), 5),
+        ];
+        for (i, meth) in methods.iter().enumerate() {
+            let single = permit_list_files(&d, &d.join(format!("out_{}_1", i)), meth.clone(), 1);
+            let multi = permit_list_files(&d, &d.join(format!("out_{}_4", i)), meth.clone(), 4);
+            assert!(!single.0.is_empty() && !single.1.is_empty());
+            assert!(
+                single == multi,
+                "the output of {:?} depends on the number of threads",
+                meth
+            );
+            // the correction tables are only written on request
+            assert!(!d
+                .join(format!("out_{}_1", i))
+                .join("ambiguous_barcodes.tsv")
+                .exists());
+        }
+        let _ = std::fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn test_filtered_ties() {
+        let d = scratch_dir("ties");
+        // t is a substitution away from both of the permitted barcodes a and b
+        let a = 0x1b2d_4e87u32;
+        let t = a ^ 0b11;
+        let b = t ^ (0b01 << 10);
+        let mut records = Vec::new();
+        for (bc, n) in [(a, 10), (b, 10), (t, 3)] {
+            for i in 0..n {
+                records.push((bc, i, vec![0x8000_0000u32]));
+            }
+        }
+        write_rad(&d, &records, 8);
+        let list_path = d.join("valid.txt");
+        std::fs::write(
+            &list_path,
+            format!(
+                "{}\n{}\n",
+                bc_to_string(a as u64, 16),
+                bc_to_string(b as u64, 16)
+            ),
+        )
+        .unwrap();
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let run = |out_dir: &Path, min_posterior: f64| {
+            let config = PermitListConfig::builder(
+                vec![d.to_str().unwrap().to_string()],
+                out_dir.to_str().unwrap(),
+            )
+            .filter_method(CellFilterMethod::ExplicitList(
+                list_path.to_str().unwrap().to_string(),
+            ))
+            .expected_ori(Some(Strand::Forward))
+            .correction_params(BarcodeCorrectionParams {
+                allow_indels: false,
+                min_posterior,
+            })
+            .write_corrections(true)
+            .build()
+            .unwrap();
+            generate_permit_list(config, &log).unwrap();
+            let pm: HashMap<u64, u64> =
+                bincode::deserialize(&std::fs::read(out_dir.join("permit_map.bin")).unwrap())
+                    .unwrap();
+            let amb = std::fs::read_to_string(out_dir.join("ambiguous_barcodes.tsv")).unwrap();
+            (pm, amb)
+        };
+
+        // by default, the tie goes to the first permitted barcode
+        let (pm, amb) = run(&d.join("out_default"), 1.0);
+        assert_eq!(pm.get(&(t as u64)), Some(&(a as u64)));
+        assert_eq!(amb.lines().count(), 1);
+
+        // the candidates are equally likely, so the correction is ambiguous
+        let (pm, amb) = run(&d.join("out_posterior"), 0.6);
+        assert!(!pm.contains_key(&(t as u64)));
+        assert_eq!(
+            amb.lines().nth(1).unwrap(),
+            format!(
+                "{}\t3\t{}:0.5000,{}:0.5000",
+                bc_to_string(t as u64, 16),
+                bc_to_string(a as u64, 16),
+                bc_to_string(b as u64, 16)
+            )
+        );
+        let _ = std::fs::remove_dir_all(&d);
+    }
+}
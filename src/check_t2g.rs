@@ -0,0 +1,283 @@
+/*
+ * Copyright (c) 2020-2022 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+use libradicl::rad_types;
+use serde_json::json;
+use slog::{crit, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Write};
+
+use crate::utils::{read_tg_map, TgEntry, TgMapRecords};
+
+// the number of examples of each kind of problem that are logged
+const NUM_EXAMPLES: usize = 5;
+
+/// The result of comparing a transcript-to-gene map against
+/// the reference names recorded in a RAD file.
+#[derive(Debug, Default)]
+pub struct TgMapReport {
+    pub num_refs: usize,
+    pub num_entries: usize,
+    // references in the RAD file that the map doesn't cover
+    pub missing: Vec<String>,
+    // map entries that aren't references in the RAD file
+    pub extra: Vec<String>,
+    // transcripts listed more than once with the same gene and status
+    pub duplicates: Vec<String>,
+    // transcripts listed more than once with a different gene, status
+    // or category, or with a status other than S or U, or an empty category
+    pub inconsistent: Vec<String>,
+    // the feature categories of a 4 column map, in order of appearance
+    pub categories: Vec<String>,
+}
+
+impl TgMapReport {
+    /// Is the map usable for these references (i.e. does it
+    /// cover all of them, and do so unambiguously)?
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.inconsistent.is_empty()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "num_refs" : self.num_refs,
+            "num_entries" : self.num_entries,
+            "num_missing" : self.missing.len(),
+            "num_extra" : self.extra.len(),
+            "num_duplicates" : self.duplicates.len(),
+            "num_inconsistent" : self.inconsistent.len(),
+            "missing" : self.missing,
+            "extra" : self.extra,
+            "duplicates" : self.duplicates,
+            "inconsistent" : self.inconsistent,
+            "categories" : self.categories
+        })
+    }
+
+    /// Log the number of each kind of problem, with a few examples.
+    pub fn log(&self, log: &slog::Logger) {
+        let examples = |v: &[String]| -> String {
+            let mut s = v
+                .iter()
+                .take(NUM_EXAMPLES)
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", ");
+            if v.len() > NUM_EXAMPLES {
+                s.push_str(", ...");
+            }
+            s
+        };
+        info!(
+            log,
+            "checked {} tg-map entries against {} reference sequences",
+            self.num_entries,
+            self.num_refs
+        );
+        if !self.categories.is_empty() {
+            info!(
+                log,
+                "the tg-map assigns features to {} categories: {}",
+                self.categories.len(),
+                self.categories.join(", ")
+            );
+        }
+        if !self.missing.is_empty() {
+            crit!(
+                log,
+                "{} reference sequences are missing from the tg-map (e.g. {})",
+                self.missing.len(),
+                examples(&self.missing)
+            );
+        }
+        if !self.inconsistent.is_empty() {
+            crit!(
+                log,
+                "{} transcripts have inconsistent tg-map entries (e.g. {})",
+                self.inconsistent.len(),
+                examples(&self.inconsistent)
+            );
+        }
+        if !self.duplicates.is_empty() {
+            warn!(
+                log,
+                "{} transcripts are listed more than once in the tg-map (e.g. {})",
+                self.duplicates.len(),
+                examples(&self.duplicates)
+            );
+        }
+        if !self.extra.is_empty() {
+            warn!(
+                log,
+                "{} tg-map entries are not reference sequences (e.g. {})",
+                self.extra.len(),
+                examples(&self.extra)
+            );
+        }
+    }
+}
+
+/// Compare the transcript-to-gene map `tg_map` (a 2, 3 or 4 column tsv
+/// file, or a GTF / GFF3 annotation) against the reference names
+/// `ref_names` of a RAD file.
+pub fn check_tg_map(
+    tg_map: &str,
+    ref_names: &[String],
+) -> Result<TgMapReport, Box<dyn std::error::Error>> {
+    Ok(check_tg_records(&read_tg_map(tg_map)?, ref_names))
+}
+
+/// As `check_tg_map`, for the `records` already read from a tg-map.
+pub fn check_tg_records(records: &TgMapRecords, ref_names: &[String]) -> TgMapReport {
+    let mut report = TgMapReport {
+        num_refs: ref_names.len(),
+        ..Default::default()
+    };
+
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut genes = HashSet::<&str, ahash::RandomState>::with_hasher(s);
+    // the (transcript, gene, status, category) of each entry in the map
+    let annot_entries: Vec<TgEntry>;
+    let (entries, is_annotation) = match records {
+        TgMapRecords::Annotation((txp_gene, _)) => {
+            annot_entries = txp_gene
+                .iter()
+                .map(|(t, g)| (t.clone(), g.clone(), None, None))
+                .collect();
+            (&annot_entries, true)
+        }
+        TgMapRecords::Table { entries, .. } => (entries, false),
+    };
+    if is_annotation {
+        genes.extend(entries.iter().map(|e| e.1.as_str()));
+    }
+    report.num_entries = entries.len();
+
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut first_entry = HashMap::<&str, usize, ahash::RandomState>::with_hasher(s);
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut reported = HashSet::<&str, ahash::RandomState>::with_hasher(s);
+    for (i, (t, g, status, cat)) in entries.iter().enumerate() {
+        if let Some(st) = status {
+            if !st.eq_ignore_ascii_case("S") && !st.eq_ignore_ascii_case("U") {
+                report
+                    .inconsistent
+                    .push(format!("{} has status {} (must be S or U)", t, st));
+            }
+        }
+        if let Some(c) = cat {
+            if c.is_empty() {
+                report
+                    .inconsistent
+                    .push(format!("{} has an empty category", t));
+            } else if !report.categories.contains(c) {
+                report.categories.push(c.clone());
+            }
+        }
+        match first_entry.get(t.as_str()) {
+            Some(j) => {
+                if !reported.insert(t.as_str()) {
+                    continue;
+                }
+                let (_, g0, status0, cat0) = &entries[*j];
+                let same_status = match (status0, status) {
+                    (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+                    _ => true,
+                };
+                if g0 != g {
+                    report
+                        .inconsistent
+                        .push(format!("{} maps to both {} and {}", t, g0, g));
+                } else if !same_status {
+                    report.inconsistent.push(format!(
+                        "{} is labeled both {} and {}",
+                        t,
+                        status0.as_deref().unwrap_or(""),
+                        status.as_deref().unwrap_or("")
+                    ));
+                } else if cat0 != cat {
+                    report.inconsistent.push(format!(
+                        "{} is in both categories {} and {}",
+                        t,
+                        cat0.as_deref().unwrap_or(""),
+                        cat.as_deref().unwrap_or("")
+                    ));
+                } else {
+                    report.duplicates.push(t.clone());
+                }
+            }
+            None => {
+                first_entry.insert(t.as_str(), i);
+            }
+        }
+    }
+
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let ref_set: HashSet<&str, ahash::RandomState> = {
+        let mut rs = HashSet::with_capacity_and_hasher(ref_names.len(), s);
+        rs.extend(ref_names.iter().map(|r| r.as_str()));
+        rs
+    };
+    for rn in ref_names {
+        if first_entry.contains_key(rn.as_str()) {
+            continue;
+        }
+        // with an annotation, the unspliced sequences of a splici
+        // reference are named after their gene or transcript
+        let unspliced_known = is_annotation
+            && matches!(
+                rn.strip_suffix("-I").or_else(|| rn.strip_suffix("-U")),
+                Some(b) if genes.contains(b) || first_entry.contains_key(b)
+            );
+        if !unspliced_known {
+            report.missing.push(rn.clone());
+        }
+    }
+    for (i, (t, _, _, _)) in entries.iter().enumerate() {
+        if first_entry.get(t.as_str()) == Some(&i) && !ref_set.contains(t.as_str()) {
+            report.extra.push(t.clone());
+        }
+    }
+
+    report
+}
+
+/// Check the transcript-to-gene map `tg_map` against the reference
+/// names in the header of `rad_file`, log a summary of any problems,
+/// and (optionally) write the full report as JSON to `output`.
+pub fn check_t2g(
+    rad_file: &str,
+    tg_map: &str,
+    output: Option<&str>,
+    log: &slog::Logger,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let i_file = File::open(rad_file)?;
+    let hdr = if rad_file.ends_with(".sz") {
+        let mut br = snap::read::FrameDecoder::new(BufReader::new(&i_file));
+        rad_types::RadHeader::from_bytes(&mut br)
+    } else {
+        let mut br = BufReader::new(&i_file);
+        rad_types::RadHeader::from_bytes(&mut br)
+    };
+
+    let report = check_tg_map(tg_map, &hdr.ref_names)?;
+    report.log(log);
+    if report.is_ok() {
+        info!(log, "the tg-map covers all reference sequences.");
+    }
+
+    if let Some(ofile) = output {
+        let mut f = File::create(ofile)?;
+        let report_str =
+            serde_json::to_string_pretty(&report.to_json()).expect("could not format json.");
+        f.write_all(report_str.as_bytes())?;
+    }
+    Ok(report.is_ok())
+}
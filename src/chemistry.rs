@@ -0,0 +1,212 @@
+/*
+ * Copyright (c) 2020-2022 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+use bio_types::strand::Strand;
+use libradicl::rad_types;
+use std::path::PathBuf;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// The environment variable naming the directory in which
+/// permit lists for the known chemistries are cached; a permit
+/// list is looked for in the `plist` sub-directory of this directory.
+pub const PERMIT_LIST_CACHE_VAR: &str = "ALEVIN_FRY_HOME";
+
+/// The barcode and UMI geometry of a known single-cell protocol.
+#[derive(Debug, PartialEq)]
+pub struct Chemistry {
+    pub name: &'static str,
+    pub bclen: u16,
+    pub umilen: u16,
+    pub expected_ori: Strand,
+    // the file name of the permit list for this
+    // chemistry (if it has one) in the permit list cache
+    pub permit_list: Option<&'static str>,
+}
+
+pub static KNOWN_CHEMISTRIES: [Chemistry; 6] = [
+    Chemistry {
+        name: "10xv2",
+        bclen: 16,
+        umilen: 10,
+        expected_ori: Strand::Forward,
+        permit_list: Some("737K-august-2016.txt"),
+    },
+    Chemistry {
+        name: "10xv3",
+        bclen: 16,
+        umilen: 12,
+        expected_ori: Strand::Forward,
+        permit_list: Some("3M-february-2018.txt"),
+    },
+    Chemistry {
+        name: "10xv4",
+        bclen: 16,
+        umilen: 12,
+        expected_ori: Strand::Forward,
+        permit_list: Some("3M-3pgex-may-2023.txt"),
+    },
+    Chemistry {
+        name: "dropseq",
+        bclen: 12,
+        umilen: 8,
+        expected_ori: Strand::Forward,
+        permit_list: None,
+    },
+    // inDrop v3; the 2 8bp cell barcodes are concatenated
+    Chemistry {
+        name: "indrop",
+        bclen: 16,
+        umilen: 6,
+        expected_ori: Strand::Forward,
+        permit_list: None,
+    },
+    // the 3 8bp round barcodes are concatenated
+    Chemistry {
+        name: "splitseq",
+        bclen: 24,
+        umilen: 10,
+        expected_ori: Strand::Forward,
+        permit_list: None,
+    },
+];
+
+#[derive(Error, Debug)]
+pub enum ChemistryError {
+    #[error("unknown chemistry `{0}`")]
+    UnknownChemistry(String),
+    #[error("the RAD file has {what} length {found}, but the {chem} chemistry has {what} length {expected}")]
+    GeometryMismatch {
+        chem: &'static str,
+        what: &'static str,
+        expected: u16,
+        found: u16,
+    },
+}
+
+impl Chemistry {
+    /// The names of all known chemistries.
+    pub fn names() -> Vec<&'static str> {
+        KNOWN_CHEMISTRIES.iter().map(|c| c.name).collect()
+    }
+
+    /// Checks that the barcode and UMI lengths recorded in the
+    /// file-level tags of a RAD file match this chemistry.
+    pub fn validate_file_tags(&self, ft_vals: &rad_types::FileTags) -> Result<(), ChemistryError> {
+        if ft_vals.bclen != self.bclen {
+            return Err(ChemistryError::GeometryMismatch {
+                chem: self.name,
+                what: "barcode",
+                expected: self.bclen,
+                found: ft_vals.bclen,
+            });
+        }
+        if ft_vals.umilen != self.umilen {
+            return Err(ChemistryError::GeometryMismatch {
+                chem: self.name,
+                what: "UMI",
+                expected: self.umilen,
+                found: ft_vals.umilen,
+            });
+        }
+        Ok(())
+    }
+
+    /// The path of the cached permit list for this chemistry,
+    /// if it has one and it is present in the cache.
+    pub fn cached_permit_list(&self) -> Option<PathBuf> {
+        let fname = self.permit_list?;
+        let home = std::env::var_os(PERMIT_LIST_CACHE_VAR)?;
+        let p = PathBuf::from(home).join("plist").join(fname);
+        if p.exists() {
+            Some(p)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for &'static Chemistry {
+    type Err = ChemistryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ls = s.to_lowercase();
+        KNOWN_CHEMISTRIES
+            .iter()
+            .find(|c| c.name == ls)
+            .ok_or_else(|| ChemistryError::UnknownChemistry(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chemistry() {
+        for c in KNOWN_CHEMISTRIES.iter() {
+            let parsed: &'static Chemistry = c.name.parse().unwrap();
+            assert_eq!(parsed, c);
+        }
+        // names are case insensitive
+        let c: &'static Chemistry = "10xV3".parse().unwrap();
+        assert_eq!((c.name, c.bclen, c.umilen), ("10xv3", 16, 12));
+        let c: &'static Chemistry = "DropSeq".parse().unwrap();
+        assert_eq!((c.name, c.bclen, c.umilen), ("dropseq", 12, 8));
+        match "10xv9".parse::<&'static Chemistry>() {
+            Err(ChemistryError::UnknownChemistry(n)) => assert_eq!(n, "10xv9"),
+            r => panic!("expected an unknown chemistry, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_validate_file_tags() {
+        let chem: &'static Chemistry = "10xv3".parse().unwrap();
+        let tags = |bclen, umilen| rad_types::FileTags { bclen, umilen };
+        assert!(chem.validate_file_tags(&tags(16, 12)).is_ok());
+        match chem.validate_file_tags(&tags(14, 12)) {
+            Err(ChemistryError::GeometryMismatch {
+                what,
+                expected,
+                found,
+                ..
+            }) => assert_eq!((what, expected, found), ("barcode", 16, 14)),
+            r => panic!("expected a barcode length mismatch, got {:?}", r),
+        }
+        match chem.validate_file_tags(&tags(16, 10)) {
+            Err(ChemistryError::GeometryMismatch {
+                what,
+                expected,
+                found,
+                ..
+            }) => assert_eq!((what, expected, found), ("UMI", 12, 10)),
+            r => panic!("expected a UMI length mismatch, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_cached_permit_list() {
+        let home = std::env::temp_dir().join(format!("af_chemistry_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&home);
+        std::fs::create_dir_all(home.join("plist")).unwrap();
+        std::env::set_var(PERMIT_LIST_CACHE_VAR, &home);
+
+        let v3: &'static Chemistry = "10xv3".parse().unwrap();
+        assert_eq!(v3.cached_permit_list(), None);
+        let pl = home.join("plist").join("3M-february-2018.txt");
+        std::fs::write(&pl, "AAAAAAAAAAAAAAAA\n").unwrap();
+        assert_eq!(v3.cached_permit_list(), Some(pl));
+        // a chemistry without a permit list
+        let dropseq: &'static Chemistry = "dropseq".parse().unwrap();
+        assert_eq!(dropseq.cached_permit_list(), None);
+
+        std::env::remove_var(PERMIT_LIST_CACHE_VAR);
+        let _ = std::fs::remove_dir_all(&home);
+    }
+}
@@ -7,11 +7,12 @@
  * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
  */
 
-use indicatif::{ProgressBar, ProgressStyle};
-use slog::{crit, info};
+use indicatif::ProgressStyle;
+use slog::{crit, info, warn};
 //use anyhow::{anyhow, Result};
-use crate::constants as afconst;
-use crate::utils::InternalVersionInfo;
+use crate::io_utils;
+use crate::logging;
+use crate::utils as afutils;
 use bio_types::strand::{Strand, StrandError};
 use crossbeam_queue::ArrayQueue;
 // use dashmap::DashMap;
@@ -25,84 +26,180 @@ use std::fs::File;
 use std::io::BufReader;
 use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::iter::FromIterator;
-use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-#[allow(clippy::too_many_arguments)]
-pub fn collate(
-    input_dir: String,
-    rad_dir: String,
-    num_threads: u32,
-    max_records: u32,
-    compress_out: bool,
-    cmdline: &str,
-    version_str: &str,
-    //expected_ori: Strand,
-    log: &slog::Logger,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let parent = std::path::Path::new(&input_dir);
+// smallest size an individual record can be loaded in memory
+const MIN_REC_LEN: usize = 24;
+// the capacity of the buffered writer of the collated output
+const OUTPUT_BUFFER_BYTES: usize = 1048576;
+
+/// The configuration of collate; see [`CollateConfig::builder`].
+/// The default number of records kept in memory at once.
+pub const DEFAULT_MAX_RECORDS: u32 = 30_000_000;
+
+#[derive(Debug, Clone)]
+pub struct CollateConfig {
+    /// the output directory of generate-permit-list
+    pub input_dir: String,
+    /// the directories holding the (mapped) RAD files
+    pub rad_dirs: Vec<String>,
+    pub num_threads: u32,
+    /// the number of records kept in memory at once
+    pub max_records: u32,
+    /// the memory budget (in bytes), from which the number of
+    /// records kept in memory is derived if it is given
+    pub max_memory: Option<u64>,
+    pub compress_out: bool,
+    /// the command line recorded in the metadata
+    pub cmdline: String,
+    pub version: String,
+}
 
-    // open the metadata file and read the json
-    let gpl_path = parent.join("generate_permit_list.json");
-    let meta_data_file =
-        File::open(&gpl_path).expect(&format!("Could not open the file {:?}.", gpl_path)[..]);
-    let mdata: serde_json::Value = serde_json::from_reader(meta_data_file)?;
-
-    let calling_version = InternalVersionInfo::from_str(version_str)?;
-    let vd: InternalVersionInfo;
-    match mdata.get("version_str") {
-        Some(vs) => match vs.as_str() {
-            Some(s) => {
-                vd = InternalVersionInfo::from_str(s)?;
-            }
-            None => {
-                return Err("The version_str field must be a string".into());
-            }
-        },
-        None => {
-            return Err("The generate_permit_list.json file does not contain a version_str field. Please re-run the generate-permit-list step with a newer version of alevin-fry".into());
+impl CollateConfig {
+    /// A builder of the configuration collating the RAD files in `rad_dirs`
+    /// with the permit list in `input_dir`; by default, 30,000,000 records
+    /// are kept in memory at once, the output is not compressed, and 1
+    /// thread is used.
+    pub fn builder(input_dir: &str, rad_dirs: Vec<String>) -> CollateConfigBuilder {
+        CollateConfigBuilder {
+            config: CollateConfig {
+                input_dir: input_dir.to_string(),
+                rad_dirs,
+                num_threads: 1,
+                max_records: DEFAULT_MAX_RECORDS,
+                max_memory: None,
+                compress_out: false,
+                cmdline: String::new(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
         }
-    };
+    }
+}
+
+pub struct CollateConfigBuilder {
+    config: CollateConfig,
+}
 
-    if let Err(es) = calling_version.is_compatible_with(&vd) {
-        return Err(es.into());
+impl CollateConfigBuilder {
+    pub fn num_threads(mut self, num_threads: u32) -> Self {
+        self.config.num_threads = num_threads;
+        self
+    }
+    pub fn max_records(mut self, max_records: u32) -> Self {
+        self.config.max_records = max_records;
+        self
+    }
+    pub fn max_memory(mut self, max_memory: Option<u64>) -> Self {
+        self.config.max_memory = max_memory;
+        self
+    }
+    pub fn compress_out(mut self, compress_out: bool) -> Self {
+        self.config.compress_out = compress_out;
+        self
+    }
+    pub fn cmdline(mut self, cmdline: &str) -> Self {
+        self.config.cmdline = cmdline.to_string();
+        self
+    }
+    pub fn version(mut self, version: &str) -> Self {
+        self.config.version = version.to_string();
+        self
     }
 
-    // if only an *old* version of the permit_freq is present, then complain and exit
-    if parent.join("permit_freq.tsv").exists() && !parent.join("permit_freq.bin").exists() {
-        crit!(log, "The file permit_freq.bin doesn't exist, please rerun alevin-fry generate-permit-list command.");
-        // std::process::exit(1);
-        return Err("execution terminated unexpectedly".into());
+    /// Check the configuration, and return it if it is valid.
+    pub fn build(self) -> Result<CollateConfig, afutils::ConfigError> {
+        let c = self.config;
+        if c.rad_dirs.is_empty() {
+            return Err(afutils::ConfigError::Incompatible(
+                "at least one input RAD directory must be given".to_string(),
+            ));
+        }
+        if c.max_records < 1 {
+            return Err(afutils::ConfigError::invalid(
+                "max-records",
+                ">= 1",
+                c.max_records,
+            ));
+        }
+        if c.num_threads < 1 {
+            return Err(afutils::ConfigError::invalid(
+                "threads",
+                ">= 1",
+                c.num_threads,
+            ));
+        }
+        Ok(c)
     }
+}
 
-    // open file
-    let freq_file =
-        std::fs::File::open(parent.join("permit_freq.bin")).expect("couldn't open file");
+/// The statistics of a collate run.
+#[derive(Debug, Clone)]
+pub struct CollateStats {
+    pub num_records: u64,
+    /// the number of corrected barcodes (chunks) written
+    pub num_cells: u64,
+    pub num_temp_buckets: usize,
+    /// the bytes written to (and read back from) the temporary buckets
+    pub temp_bucket_bytes: u64,
+    pub peak_memory_bytes: Option<u64>,
+}
 
-    // header buffer
-    let mut rbuf = [0u8; 8];
+/// Collate the records of the RAD files (the map.rad files in each of
+/// the `rad_dirs` of `config`) by their corrected barcode, using the
+/// permit list in its `input_dir`. If its `max_memory` (in bytes) is
+/// given, the number of records kept in memory at once is derived from
+/// it rather than `max_records`.
+pub fn collate(
+    config: CollateConfig,
+    log: &slog::Logger,
+) -> Result<CollateStats, Box<dyn std::error::Error>> {
+    let parent = std::path::Path::new(&config.input_dir);
+    let version_str = config.version.as_str();
 
-    // read header
-    let mut rdr = BufReader::new(&freq_file);
-    rdr.read_exact(&mut rbuf).unwrap();
-    let freq_file_version = rbuf.pread::<u64>(0).unwrap();
-    // make sure versions match
-    if freq_file_version > afconst::PERMIT_FILE_VER {
-        crit!(log,
-              "The permit_freq.bin file had version {}, but this version of alevin-fry requires version {}",
-              freq_file_version, afconst::PERMIT_FILE_VER
-        );
+    // open the metadata file, check the version that wrote it, and
+    // make sure the permit list is not stale w.r.t. the RAD file
+    let gpl_path = parent.join("generate_permit_list.json");
+    let mdata = afutils::read_step_meta(&gpl_path, "generate-permit-list", version_str)?;
+    afutils::check_file_sizes(
+        &mdata,
+        "input_file_sizes",
+        std::path::Path::new(""),
+        "generate-permit-list",
+        log,
+    )?;
+    // and that it was built from the RAD files being collated
+    afutils::check_recorded_files(
+        &mdata,
+        "input_file_sizes",
+        &io_utils::rad_file_keys(&io_utils::rad_file_paths(&config.rad_dirs)),
+        "generate-permit-list",
+        log,
+    )?;
+    afutils::check_file_sizes(
+        &mdata,
+        "output_file_sizes",
+        parent,
+        "generate-permit-list",
+        log,
+    )?;
+
+    // if only an *old* version of the permit_freq is present, then complain and exit
+    if parent.join("permit_freq.tsv").exists() && !parent.join("permit_freq.bin").exists() {
+        crit!(log, "The file permit_freq.bin doesn't exist, please rerun alevin-fry generate-permit-list command.");
+        // std::process::exit(1);
         return Err("execution terminated unexpectedly".into());
     }
 
-    // read the barcode length
-    rdr.read_exact(&mut rbuf).unwrap();
-    let _bc_len = rbuf.pread::<u64>(0).unwrap();
-
-    // read the barcode -> frequency hashmap
-    let freq_hm: HashMap<u64, u64> = bincode::deserialize_from(rdr).unwrap();
+    // read the barcode -> frequency hashmap (checking the file version)
+    let freq_hm = match afutils::read_permit_list_freq(&parent.join("permit_freq.bin")) {
+        Ok((_version, _bc_len, freq_hm)) => freq_hm,
+        Err(e) => {
+            crit!(log, "could not read the permit_freq.bin file :: {}", e);
+            return Err(e);
+        }
+    };
     let total_to_collate = freq_hm.values().sum();
     let mut tsv_map = Vec::from_iter(freq_hm.into_iter());
 
@@ -120,18 +217,7 @@ pub fn collate(
     info!(log, "executing temporary file scatter-gather strategy.");
     */
 
-    collate_with_temp(
-        input_dir,
-        rad_dir,
-        num_threads,
-        max_records,
-        tsv_map,
-        total_to_collate,
-        compress_out,
-        cmdline,
-        version_str,
-        log,
-    )
+    collate_with_temp(config, tsv_map, total_to_collate, log)
 
     /*} else {
     info!(log, "executing multi-pass strategy.");
@@ -198,14 +284,70 @@ fn get_most_ambiguous_record(mdata: &serde_json::Value, log: &slog::Logger) -> u
     }
 }
 
+/// The mean size (in bytes) of a record of the RAD input(s), from the
+/// statistics recorded by generate-permit-list or, if they are missing,
+/// estimated (conservatively) from the sizes of the input files.
+fn get_mean_record_bytes(
+    mdata: &serde_json::Value,
+    rad_files: &[std::path::PathBuf],
+    total_to_collate: u64,
+) -> f64 {
+    if let Some(rs) = mdata.get("record-stats") {
+        let num_records = rs["num_records"].as_u64().unwrap_or(0);
+        let num_bytes = rs["num_record_bytes"].as_u64().unwrap_or(0);
+        if num_records > 0 && num_bytes > 0 {
+            return num_bytes as f64 / num_records as f64;
+        }
+    }
+    let total_bytes: u64 = rad_files
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
+        .map(|md| md.len())
+        .sum();
+    (total_bytes as f64 / total_to_collate.max(1) as f64).max(MIN_REC_LEN as f64)
+}
+
+/// The number of records that can be kept in memory at once (across all
+/// `n_workers` threads) within `max_memory` bytes, given the mean size of a
+/// record, the number of entries of the correction map and the number of
+/// corrected barcodes.
+fn max_records_for_budget(
+    max_memory: u64,
+    mean_record_bytes: f64,
+    num_corrections: usize,
+    num_cells: usize,
+    n_workers: usize,
+) -> Result<u32, String> {
+    // the memory used however many records are in flight: the correction
+    // map and the map from corrected barcodes to buckets (~32 bytes per
+    // entry), the queue of input chunks and the output buffer.
+    let fixed = 32 * (num_corrections + num_cells) as u64
+        + (4 * n_workers as u64) * 65_536
+        + OUTPUT_BUFFER_BYTES as u64;
+    if fixed >= max_memory {
+        return Err(format!(
+            "a memory budget of {} is too small; at least {} are needed",
+            format_bytes(max_memory),
+            format_bytes(fixed)
+        ));
+    }
+    let num_records = ((max_memory - fixed) as f64 / mean_record_bytes) as u64;
+    Ok(num_records.clamp(n_workers as u64, u32::MAX as u64) as u32)
+}
+
+fn format_bytes(nbytes: u64) -> String {
+    if nbytes >= (1u64 << 30) {
+        format!("{:.2} GB", nbytes as f64 / (1u64 << 30) as f64)
+    } else {
+        format!("{:.2} MB", nbytes as f64 / (1u64 << 20) as f64)
+    }
+}
+
 fn correct_unmapped_counts(
     correct_map: &Arc<HashMap<u64, u64>>,
-    unmapped_file: &std::path::Path,
+    unmapped_files: &[std::path::PathBuf],
     parent: &std::path::Path,
-) {
-    let i_file = File::open(&unmapped_file).unwrap();
-    let mut br = BufReader::new(i_file);
-
+) -> Result<(), Box<dyn std::error::Error>> {
     // enough to hold a key value pair (a u64 key and u32 value)
     let mut rbuf = [0u8; std::mem::size_of::<u64>() + std::mem::size_of::<u32>()];
 
@@ -219,40 +361,49 @@ fn correct_unmapped_counts(
     //}
 
     // collect all of the information from the existing
-    // serialized map (that may contain repeats)
-    while br.read_exact(&mut rbuf[..]).is_ok() {
-        let k = rbuf.pread::<u64>(0).unwrap();
-        let v = rbuf.pread::<u32>(std::mem::size_of::<u64>()).unwrap();
-        // get the corrected key for the raw key
-        if let Some((&_rk, &ck)) = correct_map.get_key_value(&k) {
-            *unmapped_count.entry(ck).or_insert(0) += v;
+    // serialized maps (that may contain repeats)
+    for unmapped_file in unmapped_files {
+        let i_file = File::open(&unmapped_file)?;
+        let mut br = BufReader::new(i_file);
+        while br.read_exact(&mut rbuf[..]).is_ok() {
+            let k = rbuf.pread::<u64>(0).unwrap();
+            let v = rbuf.pread::<u32>(std::mem::size_of::<u64>()).unwrap();
+            // get the corrected key for the raw key
+            if let Some((&_rk, &ck)) = correct_map.get_key_value(&k) {
+                *unmapped_count.entry(ck).or_insert(0) += v;
+            }
         }
     }
 
     let s_path = parent.join("unmapped_bc_count_collated.bin");
-    let s_file = std::fs::File::create(&s_path).expect("could not create serialization file.");
+    let s_file = std::fs::File::create(&s_path)?;
     let mut s_writer = BufWriter::new(&s_file);
-    bincode::serialize_into(&mut s_writer, &unmapped_count)
-        .expect("couldn't serialize corrected unmapped bc count.");
+    bincode::serialize_into(&mut s_writer, &unmapped_count)?;
+    Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
 pub fn collate_with_temp(
-    input_dir: String,
-    rad_dir: String,
-    num_threads: u32,
-    max_records: u32,
+    config: CollateConfig,
     tsv_map: Vec<(u64, u64)>,
     total_to_collate: u64,
-    compress_out: bool,
-    cmdline: &str,
-    version: &str,
     log: &slog::Logger,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<CollateStats, Box<dyn std::error::Error>> {
+    let CollateConfig {
+        input_dir,
+        rad_dirs,
+        num_threads,
+        max_records,
+        max_memory,
+        compress_out,
+        cmdline,
+        version,
+    } = config;
     // the number of corrected cells we'll write
     let expected_output_chunks = tsv_map.len() as u64;
     // the parent input directory
     let parent = std::path::Path::new(&input_dir);
+    let mut metrics = afutils::StageMetrics::new(num_threads);
+    metrics.add_files_read(&[parent.join("permit_freq.bin")]);
 
     let n_workers = if num_threads > 1 {
         (num_threads - 1) as usize
@@ -261,8 +412,7 @@ pub fn collate_with_temp(
     };
 
     // open the metadata file and read the json
-    let meta_data_file = File::open(parent.join("generate_permit_list.json"))
-        .expect("could not open the generate_permit_list.json file.");
+    let meta_data_file = File::open(parent.join("generate_permit_list.json"))?;
     let mdata: serde_json::Value = serde_json::from_reader(&meta_data_file)?;
 
     // velo_mode
@@ -312,13 +462,10 @@ pub fn collate_with_temp(
         });
 
         let cm_path = parent.join("collate.json");
-        let mut cm_file = std::fs::File::create(&cm_path).expect("could not create metadata file.");
+        let mut cm_file = std::fs::File::create(&cm_path)?;
 
-        let cm_info_string =
-            serde_json::to_string_pretty(&collate_meta).expect("could not format json.");
-        cm_file
-            .write_all(cm_info_string.as_bytes())
-            .expect("cannot write to collate.json file");
+        let cm_info_string = serde_json::to_string_pretty(&collate_meta)?;
+        cm_file.write_all(cm_info_string.as_bytes())?;
     }
 
     let oname = parent.join(cfname);
@@ -326,26 +473,30 @@ pub fn collate_with_temp(
         std::fs::remove_file(oname)?;
     }
 
-    let ofile = File::create(parent.join(cfname)).unwrap();
-    let owriter = Arc::new(Mutex::new(BufWriter::with_capacity(1048576, ofile)));
-
-    let i_dir = std::path::Path::new(&rad_dir);
+    let ofile = File::create(parent.join(cfname))?;
+    let owriter = Arc::new(Mutex::new(BufWriter::with_capacity(
+        OUTPUT_BUFFER_BYTES,
+        ofile,
+    )));
 
-    if !i_dir.exists() {
-        crit!(log, "the input RAD path {} does not exist", rad_dir);
-        return Err("invalid input".into());
+    for rad_dir in &rad_dirs {
+        if !std::path::Path::new(rad_dir).exists() {
+            crit!(log, "the input RAD path {} does not exist", rad_dir);
+            return Err("invalid input".into());
+        }
     }
 
-    let input_rad_path = i_dir.join("map.rad");
-    let i_file = File::open(&input_rad_path).unwrap();
+    let rad_files = io_utils::rad_file_paths(&rad_dirs);
+    metrics.add_files_read(&rad_files);
+    let input_rad_path = &rad_files[0];
+    let i_file = File::open(input_rad_path)?;
     let mut br = BufReader::new(i_file);
 
     let hdr = rad_types::RadHeader::from_bytes(&mut br);
 
     // the exact position at the end of the header,
     // precisely sizeof(u64) bytes beyond the num_chunks field.
-    let end_header_pos =
-        br.get_ref().seek(SeekFrom::Current(0)).unwrap() - (br.buffer().len() as u64);
+    let end_header_pos = br.get_ref().seek(SeekFrom::Current(0))? - (br.buffer().len() as u64);
 
     info!(
         log,
@@ -373,7 +524,25 @@ pub fn collate_with_temp(
     let umit = rl_tags.tags[1].typeid;
 
     // the exact position at the end of the header + file tags
-    let pos = br.get_ref().seek(SeekFrom::Current(0)).unwrap() - (br.buffer().len() as u64);
+    let pos = br.get_ref().seek(SeekFrom::Current(0))? - (br.buffer().len() as u64);
+
+    // the records of the other inputs are read after those of the first
+    let prelude = io_utils::RadPrelude {
+        hdr,
+        fl_tags,
+        rl_tags,
+        al_tags,
+        ft_vals,
+    };
+    let (mut br, num_chunks) = io_utils::chain_rad_inputs(br, &prelude, &rad_files[1..])?;
+    if rad_files.len() > 1 {
+        info!(
+            log,
+            "collating {} chunks from {} RAD files",
+            num_chunks.to_formatted_string(&Locale::en),
+            rad_files.len()
+        );
+    }
 
     // copy the header
     {
@@ -385,58 +554,80 @@ pub fn collate_with_temp(
 
         // This temporary file pointer and buffer will be dropped
         // at the end of this block (scope).
-        let mut rfile = File::open(&input_rad_path).unwrap();
+        let mut rfile = File::open(input_rad_path)?;
         let mut hdr_buf = Cursor::new(vec![0u8; pos as usize]);
 
-        rfile
-            .read_exact(hdr_buf.get_mut())
-            .expect("couldn't read input file header");
+        rfile.read_exact(hdr_buf.get_mut())?;
         hdr_buf.set_position(take_pos);
-        hdr_buf
-            .write_all(&expected_output_chunks.to_le_bytes())
-            .expect("couldn't write num_chunks");
+        hdr_buf.write_all(&expected_output_chunks.to_le_bytes())?;
         hdr_buf.set_position(0);
 
         // compress the header buffer to a compressed buffer
         if compress_out {
             let mut compressed_buf =
                 snap::write::FrameEncoder::new(Cursor::new(Vec::<u8>::with_capacity(pos as usize)));
-            compressed_buf
-                .write_all(hdr_buf.get_ref())
-                .expect("could not compress the output header.");
-            hdr_buf = compressed_buf
-                .into_inner()
-                .expect("couldn't unwrap the FrameEncoder.");
+            compressed_buf.write_all(hdr_buf.get_ref())?;
+            hdr_buf = compressed_buf.into_inner()?;
             hdr_buf.set_position(0);
         }
 
         if let Ok(mut oput) = owriter.lock() {
-            oput.write_all(hdr_buf.get_ref())
-                .expect("could not write the output header.");
+            oput.write_all(hdr_buf.get_ref())?;
         }
     }
 
     // get the correction map
-    let cmfile = std::fs::File::open(parent.join("permit_map.bin")).unwrap();
-    let correct_map: Arc<HashMap<u64, u64>> = Arc::new(bincode::deserialize_from(&cmfile).unwrap());
+    let cmfile = std::fs::File::open(parent.join("permit_map.bin"))?;
+    let correct_map: Arc<HashMap<u64, u64>> = Arc::new(bincode::deserialize_from(&cmfile)?);
 
     // NOTE: the assumption of where the unmapped file will be
     // should be robustified
-    let unmapped_file = i_dir.join("unmapped_bc_count.bin");
-    correct_unmapped_counts(&correct_map, &unmapped_file, parent);
+    let unmapped_files: Vec<std::path::PathBuf> = rad_dirs
+        .iter()
+        .map(|d| std::path::Path::new(d).join("unmapped_bc_count.bin"))
+        .collect();
+    correct_unmapped_counts(&correct_map, &unmapped_files, parent)?;
 
     info!(
         log,
         "deserialized correction map of length : {}",
-        correct_map.len().to_formatted_string(&Locale::en)
+        correct_map.len().to_formatted_string(&Locale::en);
+        "num_corrected_barcodes" => correct_map.len()
     );
 
     let cc = rad_types::ChunkConfig {
-        num_chunks: hdr.num_chunks,
+        num_chunks,
         bc_type: bct,
         umi_type: umit,
     };
 
+    // if a memory budget is given, it determines the number of records
+    // (and so the number and size of the temporary buckets) kept in memory
+    let mean_record_bytes = get_mean_record_bytes(&mdata, &rad_files, total_to_collate);
+    let max_records = match max_memory {
+        Some(budget) => {
+            let mr = max_records_for_budget(
+                budget,
+                mean_record_bytes,
+                correct_map.len(),
+                tsv_map.len(),
+                n_workers,
+            )?;
+            info!(
+                log,
+                "with a memory budget of {} and a mean record size of {:.1} bytes, keeping {} records in memory at once",
+                format_bytes(budget),
+                mean_record_bytes,
+                mr.to_formatted_string(&Locale::en);
+                "max_memory_bytes" => budget,
+                "mean_record_bytes" => mean_record_bytes,
+                "max_records" => mr
+            );
+            mr
+        }
+        None => max_records,
+    };
+
     // TODO: see if we can do this without the Arc
     let mut output_cache = Arc::new(HashMap::<u64, Arc<libradicl::TempBucket>>::new());
 
@@ -482,7 +673,22 @@ pub fn collate_with_temp(
         temp_buckets.last_mut().unwrap().1 = allocated_records as u32;
     }
     total_allocated_records += allocated_records;
-    info!(log, "Generated {} temporary buckets.", temp_buckets.len());
+    let num_temp_buckets = temp_buckets.len();
+    info!(log, "Generated {} temporary buckets.", num_temp_buckets; "num_temp_buckets" => num_temp_buckets);
+    if max_memory.is_some() {
+        // a cell is never split across buckets, so the largest one can
+        // make its bucket exceed the budget
+        if let Some(&(_, largest)) = tsv_map.first() {
+            if largest > max_records_per_thread as u64 {
+                warn!(
+                    log,
+                    "the largest cell has {} records, more than the {} that fit in a temporary bucket within the memory budget; its bucket will exceed it",
+                    largest.to_formatted_string(&Locale::en),
+                    max_records_per_thread.to_formatted_string(&Locale::en)
+                );
+            }
+        }
+    }
 
     let sty = ProgressStyle::default_bar()
         .template(
@@ -490,7 +696,9 @@ pub fn collate_with_temp(
         )
         .progress_chars("╢▌▌░╟");
 
-    let pbar_inner = ProgressBar::new(cc.num_chunks);
+    metrics.end_phase("reading");
+
+    let pbar_inner = logging::progress_bar(cc.num_chunks, "scattering chunks", log);
     pbar_inner.set_style(sty.clone());
     pbar_inner.tick();
 
@@ -500,15 +708,33 @@ pub fn collate_with_temp(
     // the number of cells left to process
     let chunks_to_process = Arc::new(AtomicUsize::new(cc.num_chunks as usize));
 
-    let mut thread_handles: Vec<thread::JoinHandle<u64>> = Vec::with_capacity(n_workers);
+    let mut thread_handles: Vec<thread::JoinHandle<std::io::Result<u64>>> =
+        Vec::with_capacity(n_workers);
 
-    let min_rec_len = 24usize; // smallest size an individual record can be loaded in memory
+    let min_rec_len = MIN_REC_LEN;
+    // with a memory budget, the buffers are sized by the mean record size
+    let rec_len = if max_memory.is_some() {
+        (mean_record_bytes.ceil() as usize).max(min_rec_len)
+    } else {
+        min_rec_len
+    };
     let max_rec = max_records as usize;
     let num_buckets = temp_buckets.len();
     let num_threads = n_workers as usize;
     let loc_buffer_size = (min_rec_len + (most_ambig_record * 4_usize) - 4_usize).max(
-        (1000_usize.max((min_rec_len * max_rec) / (num_buckets * num_threads))).min(262_144_usize),
+        (1000_usize.max((rec_len * max_rec) / (num_buckets * num_threads))).min(262_144_usize),
     ); //131072_usize);
+    if let Some(budget) = max_memory {
+        let buffer_bytes = (loc_buffer_size * num_buckets * num_threads) as u64;
+        if buffer_bytes > budget {
+            warn!(
+                log,
+                "the temporary buffers of the {} buckets need {}, more than the memory budget",
+                num_buckets,
+                format_bytes(buffer_bytes)
+            );
+        }
+    }
 
     // for each worker, spawn off a thread
     for _worker in 0..n_workers {
@@ -527,7 +753,7 @@ pub fn collate_with_temp(
         let loc_temp_buckets = temp_buckets.clone();
         //let owrite = owriter.clone();
         // now, make the worker thread
-        let handle = std::thread::spawn(move || {
+        let handle = std::thread::spawn(move || -> std::io::Result<u64> {
             // old code
             //let mut local_buffers = vec![Cursor::new(vec![0u8; loc_buffer_size]); nbuckets];
 
@@ -579,11 +805,11 @@ pub fn collate_with_temp(
                 let len = lb.position() as usize;
                 if len > 0 {
                     let mut filebuf = loc_temp_buckets[bucket_id].2.bucket_writer.lock().unwrap();
-                    filebuf.write_all(&lb.get_ref()[0..len]).unwrap();
+                    filebuf.write_all(&lb.get_ref()[0..len])?;
                 }
             }
             // return something more meaningful
-            0
+            Ok(0)
         });
 
         thread_handles.push(handle);
@@ -605,7 +831,7 @@ pub fn collate_with_temp(
         buf.resize(nbytes_chunk as usize, 0);
         buf.pwrite::<u32>(nbytes_chunk, 0)?;
         buf.pwrite::<u32>(nrec_chunk, 4)?;
-        br.read_exact(&mut buf[8..]).unwrap();
+        br.read_exact(&mut buf[8..])?;
 
         let mut bclone = (cell_num, buf.clone());
         // keep trying until we can push this payload
@@ -621,7 +847,11 @@ pub fn collate_with_temp(
     // wait for the worker threads to finish
     for h in thread_handles.drain(0..) {
         match h.join() {
-            Ok(_) => {}
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                crit!(log, "could not write a temporary bucket file :: {}", e);
+                return Err(e.into());
+            }
             Err(_e) => {
                 info!(log, "thread panicked");
             }
@@ -629,6 +859,7 @@ pub fn collate_with_temp(
     }
     pbar_inner.finish_with_message("partitioned records into temporary files.");
     drop(q);
+    metrics.end_phase("bucketing");
 
     // At this point, we are done with the "scatter"
     // phase of writing the records to the corresponding
@@ -638,13 +869,7 @@ pub fn collate_with_temp(
 
     for (i, temp_bucket) in temp_buckets.iter().enumerate() {
         // make sure we flush each temp bucket
-        temp_bucket
-            .2
-            .bucket_writer
-            .lock()
-            .unwrap()
-            .flush()
-            .expect("could not flush temporary output file!");
+        temp_bucket.2.bucket_writer.lock().unwrap().flush()?;
         // a sanity check that we have the correct number of records
         // and the expected number of bytes in each file
         let expected = temp_bucket.1;
@@ -657,6 +882,26 @@ pub fn collate_with_temp(
         assert!(expected_bytes == observed_bytes);
     }
 
+    // each worker holds one whole bucket in memory while gathering it, so
+    // at worst the largest ones are gathered at the same time
+    let mut bucket_bytes: Vec<u64> = temp_buckets
+        .iter()
+        .map(|tb| tb.2.num_bytes_written.load(Ordering::SeqCst))
+        .collect();
+    bucket_bytes.sort_unstable_by(|a, b| b.cmp(a));
+    // the temporary buckets are written, and then read back
+    let temp_bytes: u64 = bucket_bytes.iter().sum();
+    metrics.bytes_written += temp_bytes;
+    metrics.bytes_read += temp_bytes;
+    let gather_bytes: u64 = bucket_bytes.iter().take(n_workers).sum();
+    info!(
+        log,
+        "the {} largest temporary buckets hold {} of records",
+        n_workers.min(bucket_bytes.len()),
+        format_bytes(gather_bytes);
+        "largest_buckets_bytes" => gather_bytes
+    );
+
     //std::process::exit(1);
 
     // to hold the temp buckets threads will process
@@ -670,7 +915,7 @@ pub fn collate_with_temp(
     // the number of cells left to process
     let buckets_to_process = Arc::new(AtomicUsize::new(temp_buckets.len()));
 
-    let pbar_gather = ProgressBar::new(temp_buckets.len() as u64);
+    let pbar_gather = logging::progress_bar(temp_buckets.len() as u64, "gathering buckets", log);
     pbar_gather.set_style(sty);
     pbar_gather.tick();
 
@@ -698,19 +943,31 @@ pub fn collate_with_temp(
         let pbar_gather = pbar_gather.clone();
 
         // now, make the worker threads
-        let handle = std::thread::spawn(move || {
+        let handle = std::thread::spawn(move || -> std::io::Result<u64> {
             let mut local_chunks = 0u64;
             let parent = std::path::Path::new(&input_dir);
+            // the first error this worker hits; once set, the remaining
+            // buckets are only drained from the queue so the reader can finish
+            let mut worker_err = None;
             // pop from the work queue until everything is
             // processed
             while buckets_remaining.load(Ordering::SeqCst) > 0 {
                 if let Some(temp_bucket) = in_q.pop() {
                     buckets_remaining.fetch_sub(1, Ordering::SeqCst);
+                    if worker_err.is_some() {
+                        continue;
+                    }
                     cmap.clear();
 
                     let fname = parent.join(&format!("bucket_{}.tmp", temp_bucket.2.bucket_id));
                     // create a new handle for reading
-                    let tfile = std::fs::File::open(&fname).expect("couldn't open temporary file.");
+                    let tfile = match std::fs::File::open(&fname) {
+                        Ok(f) => f,
+                        Err(e) => {
+                            worker_err = Some(e);
+                            continue;
+                        }
+                    };
                     let mut treader = BufReader::new(tfile);
 
                     local_chunks += libradicl::collate_temporary_bucket_twopass(
@@ -725,12 +982,17 @@ pub fn collate_with_temp(
 
                     // we don't need the file or reader anymore
                     drop(treader);
-                    std::fs::remove_file(fname).expect("could not delete temporary file.");
+                    if let Err(e) = std::fs::remove_file(fname) {
+                        worker_err = Some(e);
+                    }
 
                     pbar_gather.inc(1);
                 }
             }
-            local_chunks
+            match worker_err {
+                Some(e) => Err(e),
+                None => Ok(local_chunks),
+            }
         });
         thread_handles.push(handle);
     } // for each worker
@@ -754,15 +1016,20 @@ pub fn collate_with_temp(
     let mut num_output_chunks = 0u64;
     for h in thread_handles.drain(0..) {
         match h.join() {
-            Ok(c) => {
+            Ok(Ok(c)) => {
                 num_output_chunks += c;
             }
+            Ok(Err(e)) => {
+                crit!(log, "could not gather a temporary bucket file :: {}", e);
+                return Err(e.into());
+            }
             Err(_e) => {
                 info!(log, "thread panicked");
             }
         }
     }
     pbar_gather.finish_with_message("gathered all temp files.");
+    metrics.end_phase("twopass_collation");
 
     // make sure we wrote the same number of records that our
     // file suggested we should.
@@ -771,7 +1038,9 @@ pub fn collate_with_temp(
     info!(
         log,
         "writing num output chunks ({}) to header",
-        num_output_chunks.to_formatted_string(&Locale::en)
+        num_output_chunks.to_formatted_string(&Locale::en);
+        "num_output_chunks" => num_output_chunks,
+        "num_records" => total_to_collate
     );
 
     info!(
@@ -789,10 +1058,70 @@ pub fn collate_with_temp(
     );
 
     owriter.lock().unwrap().flush()?;
-    info!(
-        log,
-        "finished collating input rad file {:?}.",
-        i_dir.join("map.rad")
-    );
-    Ok(())
+
+    // record the sizes of the input and output files, so that
+    // quant can detect if they have changed since
+    let cm_path = parent.join("collate.json");
+    afutils::record_file_sizes(
+        &cm_path,
+        "input_file_sizes",
+        std::path::Path::new(""),
+        &io_utils::rad_file_keys(&rad_files),
+    )?;
+    afutils::record_file_sizes(&cm_path, "output_file_sizes", parent, &[cfname])?;
+    metrics.end_phase("writing");
+    metrics.add_files_written(&[parent.join(cfname)]);
+    metrics.log(log);
+    metrics.write(&cm_path)?;
+    let peak_memory_bytes = afutils::peak_memory_bytes();
+    if let Some(peak) = peak_memory_bytes {
+        match max_memory {
+            Some(budget) => info!(
+                log,
+                "peak memory usage : {} (budget : {})",
+                format_bytes(peak),
+                format_bytes(budget);
+                "peak_rss_bytes" => peak,
+                "max_memory_bytes" => budget
+            ),
+            None => {
+                info!(log, "peak memory usage : {}", format_bytes(peak); "peak_rss_bytes" => peak)
+            }
+        }
+    }
+    info!(log, "finished collating input rad file(s) {:?}.", rad_files);
+    Ok(CollateStats {
+        num_records: total_to_collate,
+        num_cells: num_output_chunks,
+        num_temp_buckets,
+        temp_bucket_bytes: temp_bytes,
+        peak_memory_bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collate_builder_rejects_invalid_values() {
+        let builder = || CollateConfig::builder("gpl", vec!["map".to_string()]);
+        let c = builder().build().unwrap();
+        assert_eq!(c.max_records, DEFAULT_MAX_RECORDS);
+        assert!(CollateConfig::builder("gpl", Vec::new()).build().is_err());
+        assert!(matches!(
+            builder().num_threads(0).build(),
+            Err(afutils::ConfigError::InvalidValue {
+                name: "threads",
+                ..
+            })
+        ));
+        assert!(matches!(
+            builder().max_records(0).build(),
+            Err(afutils::ConfigError::InvalidValue {
+                name: "max-records",
+                ..
+            })
+        ));
+    }
 }
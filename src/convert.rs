@@ -6,7 +6,8 @@
  *
  * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
  */
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::logging;
+use indicatif::ProgressStyle;
 use slog::{crit, info};
 //use num_format::{Locale};
 use std::fs;
@@ -300,7 +301,7 @@ pub fn bam2rad(input_file: String, rad_file: String, num_threads: u32, log: &slo
     let expected_bar_length = bam_bytes / ((buf_limit as u64) * 24);
     // let expected_bar_length = 50u64 ;// bam_bytes / ((buf_limit as u64) * 24);
 
-    let pbar_inner = ProgressBar::new(expected_bar_length as u64);
+    let pbar_inner = logging::progress_bar(expected_bar_length as u64, "converting records", log);
     pbar_inner.set_style(sty);
     pbar_inner.tick();
 
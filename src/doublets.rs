@@ -0,0 +1,1001 @@
+/*
+ * Copyright (c) 2020-2022 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+use crate::utils as afutils;
+use flate2::read::MultiGzDecoder;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::json;
+use slog::{info, warn};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+// the total count to which each cell is scaled before the log transform
+const TARGET_SUM: f32 = 1e4;
+// standardized expression values are clipped to this magnitude
+const MAX_Z: f32 = 10.0;
+// the number of extra dimensions and power iterations used by the randomized PCA
+const PCA_OVERSAMPLE: usize = 10;
+const PCA_POWER_ITERS: usize = 4;
+
+/// Parameters controlling the simulation of doublets and the
+/// computation of the doublet score of each cell.
+#[derive(Debug, Clone, Copy)]
+pub struct DoubletParams {
+    /// the number of simulated doublets per observed cell
+    pub sim_ratio: f64,
+    /// the number of highly variable genes used for the embedding
+    pub num_hvg: usize,
+    /// the number of principal components in the embedding
+    pub num_pcs: usize,
+    /// the number of neighbors of each cell among the observed cells
+    /// (the actual number also accounts for the simulated doublets);
+    /// if `None`, this is chosen based on the number of cells
+    pub num_neighbors: Option<usize>,
+    /// the expected fraction of doublets among the observed cells
+    pub expected_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for DoubletParams {
+    fn default() -> Self {
+        Self {
+            sim_ratio: 2.0,
+            num_hvg: 2000,
+            num_pcs: 30,
+            num_neighbors: None,
+            expected_rate: 0.1,
+            seed: 0,
+        }
+    }
+}
+
+impl DoubletParams {
+    /// Check that the parameters are in their valid ranges.
+    pub fn validate(&self) -> Result<(), afutils::ConfigError> {
+        if self.sim_ratio <= 0.0 {
+            return Err(afutils::ConfigError::invalid(
+                "sim-ratio",
+                "> 0",
+                self.sim_ratio,
+            ));
+        }
+        if self.expected_rate <= 0.0 || self.expected_rate >= 1.0 {
+            return Err(afutils::ConfigError::invalid(
+                "expected-rate",
+                "in (0, 1)",
+                self.expected_rate,
+            ));
+        }
+        if self.num_hvg == 0 {
+            return Err(afutils::ConfigError::invalid(
+                "num-hvg",
+                "> 0",
+                self.num_hvg,
+            ));
+        }
+        if self.num_pcs == 0 {
+            return Err(afutils::ConfigError::invalid(
+                "num-pcs",
+                "> 0",
+                self.num_pcs,
+            ));
+        }
+        Ok(())
+    }
+}
+
+// the (gene, count) pairs of the non-zero entries of a cell
+type SparseRow = Vec<(u32, f32)>;
+// the barcodes, number of genes and gene-level counts of the cells
+type QuantCounts = (Vec<String>, usize, Vec<SparseRow>);
+
+fn read_lines(path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let rdr = BufReader::new(File::open(path)?);
+    let mut lines = Vec::new();
+    for l in rdr.lines() {
+        lines.push(l?);
+    }
+    Ok(lines)
+}
+
+/// Read the `num_cells` rows of the EDS count matrix `path`, each of
+/// which is a bit vector of the non-zero columns followed by their values.
+fn read_eds(
+    path: &Path,
+    num_cells: usize,
+    num_cols: usize,
+) -> Result<Vec<SparseRow>, Box<dyn std::error::Error>> {
+    let mut rdr = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+    let mut flags = vec![0u8; (num_cols + 7) / 8];
+    let mut vals = Vec::<u8>::new();
+    let mut rows = Vec::with_capacity(num_cells);
+    for _ in 0..num_cells {
+        rdr.read_exact(&mut flags)?;
+        let mut cols = Vec::new();
+        for (i, b) in flags.iter().enumerate() {
+            for j in 0..8 {
+                if b & (128u8 >> j) != 0 {
+                    cols.push((i * 8 + j) as u32);
+                }
+            }
+        }
+        vals.resize(4 * cols.len(), 0);
+        rdr.read_exact(&mut vals)?;
+        rows.push(
+            cols.into_iter()
+                .zip(vals.chunks_exact(4))
+                .map(|(c, v)| (c, f32::from_le_bytes([v[0], v[1], v[2], v[3]])))
+                .collect(),
+        );
+    }
+    Ok(rows)
+}
+
+/// Read the count matrix written by `quant` to `input_dir`, returning the
+/// barcodes and the gene-level counts of each cell (i.e. summing the
+/// spliced, unspliced and ambiguous, or category, columns of each gene).
+fn read_quant_output(
+    input_dir: &Path,
+    log: &slog::Logger,
+) -> Result<QuantCounts, Box<dyn std::error::Error>> {
+    let quant_json: serde_json::Value =
+        serde_json::from_reader(File::open(input_dir.join("quant.json"))?)?;
+    let blocks = if quant_json["usa_mode"].as_bool().unwrap_or(false) {
+        3
+    } else {
+        match quant_json["feature_categories"].as_array() {
+            Some(cats) => cats.len() + 1,
+            None => 1,
+        }
+    };
+
+    let mat_dir = input_dir.join("alevin");
+    let barcodes = read_lines(&mat_dir.join("quants_mat_rows.txt"))?;
+    let num_cols = read_lines(&mat_dir.join("quants_mat_cols.txt"))?.len();
+    if num_cols % blocks != 0 {
+        return Err(format!(
+            "the {} columns of the count matrix can't be split into {} blocks.",
+            num_cols, blocks
+        )
+        .into());
+    }
+    let num_genes = num_cols / blocks;
+
+    let mtx_path = mat_dir.join("quants_mat.mtx");
+    let rows = if mtx_path.exists() {
+        let mat = sprs::io::read_matrix_market::<f32, usize, _>(&mtx_path)?.to_csr::<usize>();
+        if mat.rows() != barcodes.len() || mat.cols() != num_cols {
+            return Err(format!(
+                "the count matrix is {} x {}, but there are {} barcodes and {} columns.",
+                mat.rows(),
+                mat.cols(),
+                barcodes.len(),
+                num_cols
+            )
+            .into());
+        }
+        mat.outer_iterator()
+            .map(|r| r.iter().map(|(c, v)| (c as u32, *v)).collect())
+            .collect()
+    } else {
+        read_eds(&mat_dir.join("quants_mat.gz"), barcodes.len(), num_cols)?
+    };
+    info!(
+        log,
+        "read counts of {} genes in {} cells.",
+        num_genes,
+        barcodes.len()
+    );
+
+    let gene_rows = rows
+        .into_iter()
+        .map(|r: SparseRow| {
+            if blocks == 1 {
+                return r;
+            }
+            let mut g = r
+                .into_iter()
+                .map(|(c, v)| (c % num_genes as u32, v))
+                .collect::<SparseRow>();
+            g.sort_unstable_by_key(|x| x.0);
+            g.dedup_by(|a, b| {
+                if a.0 == b.0 {
+                    b.1 += a.1;
+                    true
+                } else {
+                    false
+                }
+            });
+            g
+        })
+        .collect();
+    Ok((barcodes, num_genes, gene_rows))
+}
+
+/// Scale the counts of a cell to `TARGET_SUM` and log transform them.
+fn log_normalize(row: &[(u32, f32)]) -> SparseRow {
+    let tot: f32 = row.iter().map(|x| x.1).sum();
+    if tot <= 0.0 {
+        return Vec::new();
+    }
+    row.iter()
+        .map(|(g, c)| (*g, (c * TARGET_SUM / tot).ln_1p()))
+        .collect()
+}
+
+/// The genes selected for the embedding, with the mean and standard
+/// deviation of their normalized expression over the observed cells.
+struct GeneScaling {
+    // the column of each gene in the embedding input, if selected
+    column: Vec<Option<usize>>,
+    mean: Vec<f32>,
+    sd: Vec<f32>,
+}
+
+impl GeneScaling {
+    /// Select the (at most) `num_hvg` genes with the largest variance of
+    /// normalized expression over the cells `rows`.
+    fn fit(rows: &[SparseRow], num_genes: usize, num_hvg: usize) -> Self {
+        let n = rows.len() as f64;
+        let mut sums = vec![0f64; num_genes];
+        let mut sq_sums = vec![0f64; num_genes];
+        for r in rows {
+            for (g, v) in r {
+                sums[*g as usize] += *v as f64;
+                sq_sums[*g as usize] += (*v as f64) * (*v as f64);
+            }
+        }
+        let mut var: Vec<(usize, f64)> = (0..num_genes)
+            .map(|g| {
+                let m = sums[g] / n;
+                (g, (sq_sums[g] / n - m * m).max(0.0))
+            })
+            .filter(|x| x.1 > 0.0)
+            .collect();
+        var.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        var.truncate(num_hvg);
+
+        let mut column = vec![None; num_genes];
+        let mut mean = Vec::with_capacity(var.len());
+        let mut sd = Vec::with_capacity(var.len());
+        for (i, (g, v)) in var.iter().enumerate() {
+            column[*g] = Some(i);
+            mean.push((sums[*g] / n) as f32);
+            sd.push(v.sqrt() as f32);
+        }
+        Self { column, mean, sd }
+    }
+
+    fn len(&self) -> usize {
+        self.mean.len()
+    }
+
+    /// The standardized (and clipped) expression of the selected genes in
+    /// the normalized cell `row`.
+    fn scale(&self, row: &[(u32, f32)]) -> Vec<f32> {
+        let mut z: Vec<f32> = self
+            .mean
+            .iter()
+            .zip(self.sd.iter())
+            .map(|(m, s)| (-m / s).max(-MAX_Z))
+            .collect();
+        for (g, v) in row {
+            if let Some(i) = self.column[*g as usize] {
+                z[i] = ((v - self.mean[i]) / self.sd[i]).clamp(-MAX_Z, MAX_Z);
+            }
+        }
+        z
+    }
+}
+
+/// Orthonormalize (with modified Gram-Schmidt) the `l` columns
+/// of the row-major `r` x `l` matrix `m`.
+fn orthonormalize(m: &mut [f64], r: usize, l: usize) {
+    for c in 0..l {
+        for p in 0..c {
+            let dot: f64 = (0..r).map(|i| m[i * l + c] * m[i * l + p]).sum();
+            for i in 0..r {
+                m[i * l + c] -= dot * m[i * l + p];
+            }
+        }
+        let norm = (0..r).map(|i| m[i * l + c].powi(2)).sum::<f64>().sqrt();
+        let scale = if norm > 1e-10 { 1.0 / norm } else { 0.0 };
+        for i in 0..r {
+            m[i * l + c] *= scale;
+        }
+    }
+}
+
+/// The product of the `n` x `h` matrix `x` and the `h` x `l` matrix `m`.
+fn x_times(x: &[f32], n: usize, h: usize, m: &[f64], l: usize) -> Vec<f64> {
+    let mut y = vec![0f64; n * l];
+    for i in 0..n {
+        let yi = &mut y[i * l..(i + 1) * l];
+        for (g, xv) in x[i * h..(i + 1) * h].iter().enumerate() {
+            let xv = *xv as f64;
+            for (yc, mc) in yi.iter_mut().zip(m[g * l..(g + 1) * l].iter()) {
+                *yc += xv * mc;
+            }
+        }
+    }
+    y
+}
+
+/// The product of the transpose of the `n` x `h` matrix `x` and the `n` x `l` matrix `q`.
+fn xt_times(x: &[f32], n: usize, h: usize, q: &[f64], l: usize) -> Vec<f64> {
+    let mut z = vec![0f64; h * l];
+    for i in 0..n {
+        let qi = &q[i * l..(i + 1) * l];
+        for (g, xv) in x[i * h..(i + 1) * h].iter().enumerate() {
+            let xv = *xv as f64;
+            for (zc, qc) in z[g * l..(g + 1) * l].iter_mut().zip(qi.iter()) {
+                *zc += xv * qc;
+            }
+        }
+    }
+    z
+}
+
+/// The eigenvalues and (the columns of the matrix of) eigenvectors of the
+/// symmetric row-major `n` x `n` matrix `a`, computed with cyclic Jacobi
+/// rotations, in order of decreasing eigenvalue.
+fn symmetric_eigen(mut a: Vec<f64>, n: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut v = vec![0f64; n * n];
+    for i in 0..n {
+        v[i * n + i] = 1.0;
+    }
+    let scale: f64 = a.iter().map(|x| x * x).sum::<f64>().max(f64::MIN_POSITIVE);
+    for _ in 0..100 {
+        let off: f64 = (0..n)
+            .flat_map(|p| (0..n).filter(move |q| *q != p).map(move |q| (p, q)))
+            .map(|(p, q)| a[p * n + q].powi(2))
+            .sum();
+        if off <= 1e-22 * scale {
+            break;
+        }
+        for p in 0..n {
+            for q in (p + 1)..n {
+                let apq = a[p * n + q];
+                if apq == 0.0 {
+                    continue;
+                }
+                let theta = (a[q * n + q] - a[p * n + p]) / (2.0 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (a[k * n + p], a[k * n + q]);
+                    a[k * n + p] = c * akp - s * akq;
+                    a[k * n + q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p * n + k], a[q * n + k]);
+                    a[p * n + k] = c * apk - s * aqk;
+                    a[q * n + k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[k * n + p], v[k * n + q]);
+                    v[k * n + p] = c * vkp - s * vkq;
+                    v[k * n + q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|i, j| a[j * n + j].total_cmp(&a[i * n + i]));
+    let vals = order.iter().map(|i| a[i * n + i]).collect();
+    let mut vecs = vec![0f64; n * n];
+    for (c, i) in order.iter().enumerate() {
+        for k in 0..n {
+            vecs[k * n + c] = v[k * n + i];
+        }
+    }
+    (vals, vecs)
+}
+
+/// The (at most) `num_pcs` leading principal axes of the (centered)
+/// `n` x `h` matrix `x`, computed with a randomized SVD.
+fn principal_axes(x: &[f32], n: usize, h: usize, num_pcs: usize, seed: u64) -> Vec<Vec<f64>> {
+    let l = (num_pcs + PCA_OVERSAMPLE).min(n).min(h);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let omega: Vec<f64> = (0..h * l).map(|_| rng.gen_range(-1.0..1.0)).collect();
+
+    // find an orthonormal basis of (approximately) the range of x
+    let mut q = x_times(x, n, h, &omega, l);
+    orthonormalize(&mut q, n, l);
+    for _ in 0..PCA_POWER_ITERS {
+        let mut z = xt_times(x, n, h, &q, l);
+        orthonormalize(&mut z, h, l);
+        q = x_times(x, n, h, &z, l);
+        orthonormalize(&mut q, n, l);
+    }
+
+    // with b = q^T x, the eigenvectors u of b b^T give the
+    // principal axes as b^T u / sqrt(eigenvalue)
+    let bt = xt_times(x, n, h, &q, l);
+    let mut bbt = vec![0f64; l * l];
+    for g in 0..h {
+        let bg = &bt[g * l..(g + 1) * l];
+        for a in 0..l {
+            for b in 0..l {
+                bbt[a * l + b] += bg[a] * bg[b];
+            }
+        }
+    }
+    let (vals, vecs) = symmetric_eigen(bbt, l);
+    let max_val = vals.first().copied().unwrap_or(0.0);
+    vals.iter()
+        .enumerate()
+        .take(num_pcs)
+        .filter(|(_, v)| **v > 1e-12 * max_val && **v > 0.0)
+        .map(|(k, v)| {
+            let s = v.sqrt();
+            (0..h)
+                .map(|g| (0..l).map(|a| bt[g * l + a] * vecs[a * l + k]).sum::<f64>() / s)
+                .collect()
+        })
+        .collect()
+}
+
+/// The coordinates of the standardized expression `z` along each of the principal `axes`.
+fn project(z: &[f32], axes: &[Vec<f64>]) -> Vec<f32> {
+    axes.iter()
+        .map(|a| {
+            z.iter()
+                .zip(a.iter())
+                .map(|(zv, av)| *zv as f64 * av)
+                .sum::<f64>() as f32
+        })
+        .collect()
+}
+
+// the largest number of points in a leaf of the kd-tree
+const KD_LEAF_SIZE: usize = 16;
+
+enum KdNode {
+    Leaf {
+        start: usize,
+        end: usize,
+    },
+    Split {
+        dim: usize,
+        value: f32,
+        left: usize,
+        right: usize,
+    },
+}
+
+/// A kd-tree over the points of a row-major embedding, so that the nearest
+/// neighbors of a cell can be found without comparing it to every other point.
+struct KdTree {
+    emb: Arc<Vec<f32>>,
+    dim: usize,
+    // the point indices, ordered so that each node covers a contiguous range
+    idx: Vec<u32>,
+    nodes: Vec<KdNode>,
+}
+
+impl KdTree {
+    fn new(emb: Arc<Vec<f32>>, dim: usize) -> Self {
+        let num_points = emb.len() / dim;
+        let mut t = Self {
+            emb,
+            dim,
+            idx: (0..num_points as u32).collect(),
+            nodes: Vec::new(),
+        };
+        t.build(0, num_points);
+        t
+    }
+
+    fn coord(&self, p: u32, d: usize) -> f32 {
+        self.emb[p as usize * self.dim + d]
+    }
+
+    fn point(&self, p: usize) -> &[f32] {
+        &self.emb[p * self.dim..(p + 1) * self.dim]
+    }
+
+    /// Add the subtree covering the points `idx[start..end]`, split along
+    /// the dimension in which they are most spread, returning its node.
+    fn build(&mut self, start: usize, end: usize) -> usize {
+        let node = self.nodes.len();
+        self.nodes.push(KdNode::Leaf { start, end });
+        if end - start <= KD_LEAF_SIZE {
+            return node;
+        }
+        let (mut split_dim, mut max_spread) = (0, 0f32);
+        for d in 0..self.dim {
+            let (lo, hi) = self.idx[start..end]
+                .iter()
+                .map(|p| self.coord(*p, d))
+                .fold((f32::MAX, f32::MIN), |(lo, hi), v| (lo.min(v), hi.max(v)));
+            if hi - lo > max_spread {
+                split_dim = d;
+                max_spread = hi - lo;
+            }
+        }
+        if max_spread <= 0.0 {
+            return node;
+        }
+
+        let mid = start + (end - start) / 2;
+        let mut idx = std::mem::take(&mut self.idx);
+        idx[start..end].select_nth_unstable_by(mid - start, |a, b| {
+            self.coord(*a, split_dim)
+                .total_cmp(&self.coord(*b, split_dim))
+        });
+        let value = self.coord(idx[mid], split_dim);
+        self.idx = idx;
+        let left = self.build(start, mid);
+        let right = self.build(mid, end);
+        self.nodes[node] = KdNode::Split {
+            dim: split_dim,
+            value,
+            left,
+            right,
+        };
+        node
+    }
+
+    /// Fill `nn` with the (squared distance, index) of the `k` nearest
+    /// neighbors of the point `i` (excluding itself), in order of distance.
+    fn nearest(&self, i: usize, k: usize, nn: &mut Vec<(f32, u32)>) {
+        nn.clear();
+        self.search(0, i, k, nn);
+    }
+
+    fn search(&self, node: usize, i: usize, k: usize, nn: &mut Vec<(f32, u32)>) {
+        match self.nodes[node] {
+            KdNode::Leaf { start, end } => {
+                let pi = self.point(i);
+                for j in self.idx[start..end].iter().filter(|j| **j as usize != i) {
+                    let d: f32 = pi
+                        .iter()
+                        .zip(self.point(*j as usize).iter())
+                        .map(|(a, b)| (a - b) * (a - b))
+                        .sum();
+                    if nn.len() < k || d < nn[nn.len() - 1].0 {
+                        let pos = nn.partition_point(|x| x.0 <= d);
+                        nn.insert(pos, (d, *j));
+                        nn.truncate(k);
+                    }
+                }
+            }
+            KdNode::Split {
+                dim,
+                value,
+                left,
+                right,
+            } => {
+                let diff = self.emb[i * self.dim + dim] - value;
+                let (near, far) = if diff < 0.0 {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                self.search(near, i, k, nn);
+                // the points on the other side are at least |diff| away
+                if nn.len() < k || diff * diff < nn[nn.len() - 1].0 {
+                    self.search(far, i, k, nn);
+                }
+            }
+        }
+    }
+}
+
+/// For each of the first `num_obs` points of the row-major `dim`-dimensional
+/// embedding `emb`, count how many of its `k` nearest neighbors (among all
+/// other points) are simulated doublets (i.e. come after the observed cells).
+/// The neighbors are found with a kd-tree, which avoids comparing each cell
+/// to every point when the embedding has few (effective) dimensions.
+fn doublet_neighbor_counts(
+    emb: Arc<Vec<f32>>,
+    dim: usize,
+    num_obs: usize,
+    k: usize,
+    num_threads: u32,
+) -> Vec<u32> {
+    let tree = Arc::new(KdTree::new(emb, dim));
+    let num_threads = num_threads.max(1) as usize;
+    let chunk_size = ((num_obs + num_threads - 1) / num_threads).max(1);
+    let mut handles = Vec::new();
+    for start in (0..num_obs).step_by(chunk_size) {
+        let tree = tree.clone();
+        let end = (start + chunk_size).min(num_obs);
+        handles.push(thread::spawn(move || {
+            let mut counts = Vec::with_capacity(end - start);
+            // the (distance, index) of the current nearest neighbors, in order
+            let mut nn = Vec::<(f32, u32)>::with_capacity(k + 1);
+            for i in start..end {
+                tree.nearest(i, k, &mut nn);
+                counts.push(nn.iter().filter(|x| x.1 as usize >= num_obs).count() as u32);
+            }
+            counts
+        }));
+    }
+    handles
+        .into_iter()
+        .flat_map(|h| h.join().expect("doublet scoring thread panicked"))
+        .collect()
+}
+
+/// Score each cell quantified in `input_dir` (the output directory of `quant`)
+/// for being a doublet. Doublets are simulated by summing the counts of random
+/// pairs of cells, and the cells and simulated doublets are embedded using PCA
+/// on the normalized counts of highly variable genes. The score of a cell is
+/// then derived from the fraction of its nearest neighbors in this embedding
+/// that are simulated doublets (as in Scrublet). The scores are written to
+/// `output_dir/doublet_scores.tsv`.
+pub fn score_doublets(
+    input_dir: &str,
+    output_dir: &str,
+    params: &DoubletParams,
+    num_threads: u32,
+    log: &slog::Logger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    params.validate()?;
+    let (barcodes, num_genes, counts) = read_quant_output(Path::new(input_dir), log)?;
+    let num_obs = counts.len();
+    if num_obs < 2 {
+        return Err("at least 2 cells are needed to simulate doublets.".into());
+    }
+
+    // simulate doublets by summing the counts of random pairs of distinct cells
+    let num_sim = ((num_obs as f64 * params.sim_ratio).round() as usize).max(1);
+    let mut rng = StdRng::seed_from_u64(params.seed);
+    let mut sim_counts = Vec::<SparseRow>::with_capacity(num_sim);
+    for _ in 0..num_sim {
+        let i = rng.gen_range(0..num_obs);
+        let j = (i + rng.gen_range(1..num_obs)) % num_obs;
+        let mut d = Vec::with_capacity(counts[i].len() + counts[j].len());
+        d.extend_from_slice(&counts[i]);
+        d.extend_from_slice(&counts[j]);
+        d.sort_unstable_by_key(|x| x.0);
+        d.dedup_by(|a, b| {
+            if a.0 == b.0 {
+                b.1 += a.1;
+                true
+            } else {
+                false
+            }
+        });
+        sim_counts.push(d);
+    }
+    info!(log, "simulated {} doublets.", num_sim);
+
+    // select and standardize genes based on the observed cells only
+    let obs_norm: Vec<SparseRow> = counts.iter().map(|r| log_normalize(r)).collect();
+    let scaling = GeneScaling::fit(&obs_norm, num_genes, params.num_hvg);
+    let h = scaling.len();
+    if h == 0 {
+        return Err("no gene has variable expression across the cells.".into());
+    }
+    let mut x = Vec::<f32>::with_capacity(num_obs * h);
+    for r in &obs_norm {
+        x.extend(scaling.scale(r));
+    }
+
+    let axes = principal_axes(&x, num_obs, h, params.num_pcs, params.seed);
+    let dim = axes.len();
+    if dim == 0 {
+        return Err("could not compute any principal components of the counts.".into());
+    }
+    if dim < params.num_pcs {
+        warn!(
+            log,
+            "only {} (of the requested {}) principal components could be computed.",
+            dim,
+            params.num_pcs
+        );
+    }
+    info!(
+        log,
+        "embedding cells using {} principal components of {} highly variable genes.", dim, h
+    );
+
+    let mut emb = Vec::<f32>::with_capacity((num_obs + num_sim) * dim);
+    for i in 0..num_obs {
+        emb.extend(project(&x[i * h..(i + 1) * h], &axes));
+    }
+    drop(x);
+    for r in &sim_counts {
+        emb.extend(project(&scaling.scale(&log_normalize(r)), &axes));
+    }
+
+    // as in Scrublet, the number of neighbors grows with the simulated doublets
+    let r = num_sim as f64 / num_obs as f64;
+    let k_obs = params
+        .num_neighbors
+        .unwrap_or_else(|| (0.5 * (num_obs as f64).sqrt()).round() as usize)
+        .max(1);
+    let k = ((k_obs as f64 * (1.0 + r)).round() as usize).clamp(1, num_obs + num_sim - 1);
+    info!(log, "finding the {} nearest neighbors of each cell.", k);
+    let nd = doublet_neighbor_counts(Arc::new(emb), dim, num_obs, k, num_threads);
+
+    // the posterior probability of being a doublet, given the number of doublet
+    // neighbors and the expected doublet rate
+    let rho = params.expected_rate;
+    let scores: Vec<f64> = nd
+        .iter()
+        .map(|d| {
+            let q = (*d as f64 + 1.0) / (k as f64 + 2.0);
+            (q * rho / r) / (1.0 - rho - q * (1.0 - rho - rho / r))
+        })
+        .collect();
+
+    let output_path = Path::new(output_dir);
+    std::fs::create_dir_all(output_path)?;
+    let mut out = BufWriter::new(File::create(output_path.join("doublet_scores.tsv"))?);
+    writeln!(out, "barcode\tdoublet_score\tdoublet_neighbor_fraction")?;
+    for ((bc, s), d) in barcodes.iter().zip(scores.iter()).zip(nd.iter()) {
+        writeln!(out, "{}\t{}\t{}", bc, s, *d as f64 / k as f64)?;
+    }
+    out.flush()?;
+
+    let num_over_half = scores.iter().filter(|s| **s > 0.5).count();
+    info!(
+        log,
+        "{} of {} cells have a doublet score over 0.5.", num_over_half, num_obs
+    );
+
+    let meta_info = json!({
+        "num_cells" : num_obs,
+        "num_simulated_doublets" : num_sim,
+        "num_hvg" : h,
+        "num_pcs" : dim,
+        "num_neighbors" : k,
+        "expected_doublet_rate" : rho,
+        "seed" : params.seed,
+        "num_scores_over_half" : num_over_half
+    });
+    let mut meta_file = File::create(output_path.join("doublets.json"))?;
+    let meta_str = serde_json::to_string_pretty(&meta_info).expect("could not format json.");
+    meta_file.write_all(meta_str.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::path::PathBuf;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let d = std::env::temp_dir().join(format!("af_doublets_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&d);
+        std::fs::create_dir_all(&d).unwrap();
+        d
+    }
+
+    #[test]
+    fn test_validate_params() {
+        assert!(DoubletParams::default().validate().is_ok());
+        let bad = [
+            DoubletParams {
+                sim_ratio: 0.0,
+                ..Default::default()
+            },
+            DoubletParams {
+                expected_rate: 1.0,
+                ..Default::default()
+            },
+            DoubletParams {
+                num_hvg: 0,
+                ..Default::default()
+            },
+            DoubletParams {
+                num_pcs: 0,
+                ..Default::default()
+            },
+        ];
+        let names = ["sim-ratio", "expected-rate", "num-hvg", "num-pcs"];
+        for (p, name) in bad.iter().zip(names.iter()) {
+            match p.validate() {
+                Err(afutils::ConfigError::InvalidValue { name: n, .. }) => assert_eq!(n, *name),
+                r => panic!("expected an invalid {}, got {:?}", name, r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_eds() {
+        let num_cols = 11usize;
+        let rows: Vec<SparseRow> = vec![
+            vec![(0, 1.0), (3, 2.5), (10, 7.0)],
+            vec![],
+            vec![(7, 1.0), (8, 3.0)],
+        ];
+        let d = scratch_dir("eds");
+        let path = d.join("quants_mat.gz");
+        let mut enc = GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::default());
+        for r in &rows {
+            let mut flags = vec![0u8; (num_cols + 7) / 8];
+            for (c, _) in r {
+                flags[*c as usize / 8] |= 128u8 >> (c % 8);
+            }
+            enc.write_all(&flags).unwrap();
+            for (_, v) in r {
+                enc.write_all(&v.to_le_bytes()).unwrap();
+            }
+        }
+        enc.finish().unwrap();
+
+        assert_eq!(read_eds(&path, rows.len(), num_cols).unwrap(), rows);
+        // asking for more rows than were written is an error
+        assert!(read_eds(&path, rows.len() + 1, num_cols).is_err());
+        let _ = std::fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn test_symmetric_eigen() {
+        // eigenvalues 5 (along z), 3 (along x + y) and 1 (along x - y)
+        let a = vec![2.0, 1.0, 0.0, 1.0, 2.0, 0.0, 0.0, 0.0, 5.0];
+        let (vals, vecs) = symmetric_eigen(a.clone(), 3);
+        for (v, e) in vals.iter().zip([5.0, 3.0, 1.0]) {
+            assert!((v - e).abs() < 1e-9);
+        }
+        let h = std::f64::consts::FRAC_1_SQRT_2;
+        let expected = [[0.0, 0.0, 1.0], [h, h, 0.0], [h, -h, 0.0]];
+        for (c, e) in expected.iter().enumerate() {
+            let v: Vec<f64> = (0..3).map(|k| vecs[k * 3 + c]).collect();
+            let dot: f64 = v.iter().zip(e.iter()).map(|(x, y)| x * y).sum();
+            assert!((dot.abs() - 1.0).abs() < 1e-9);
+            // and a v = lambda v
+            for r in 0..3 {
+                let av: f64 = (0..3).map(|k| a[r * 3 + k] * v[k]).sum();
+                assert!((av - vals[c] * v[r]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_principal_axes() {
+        // centered points spread mostly along u, and less along the orthogonal w
+        let h = 5;
+        let u = [0.6, 0.8, 0.0, 0.0, 0.0];
+        let w = [0.0, 0.0, 0.0, 0.8, -0.6];
+        let n = 40;
+        let mut x = Vec::<f32>::with_capacity(n * h);
+        for i in 0..n {
+            // a is odd and b is even around the center, so they are uncorrelated
+            let a = i as f64 - 19.5;
+            let b = if (a.abs()) < 10.0 { 0.5 } else { -0.5 };
+            x.extend((0..h).map(|g| (a * u[g] + b * w[g]) as f32));
+        }
+        let axes = principal_axes(&x, n, h, 3, 7);
+        // the data have rank 2
+        assert_eq!(axes.len(), 2);
+        for (axis, e) in axes.iter().zip([u, w]) {
+            let dot: f64 = axis.iter().zip(e.iter()).map(|(x, y)| x * y).sum();
+            assert!((dot.abs() - 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_kd_tree_neighbors() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let (dim, num_obs, num_points, k) = (4, 300, 900, 12);
+        let emb: Vec<f32> = (0..num_points * dim)
+            .map(|i| rng.gen_range(-1.0..1.0) * (1.0 + (i % dim) as f32))
+            .collect();
+        let counts = doublet_neighbor_counts(Arc::new(emb.clone()), dim, num_obs, k, 2);
+
+        // compare to an exhaustive search
+        for (i, c) in counts.iter().enumerate() {
+            let mut d: Vec<(f32, usize)> = (0..num_points)
+                .filter(|j| *j != i)
+                .map(|j| {
+                    let dist = (0..dim)
+                        .map(|a| (emb[i * dim + a] - emb[j * dim + a]).powi(2))
+                        .sum();
+                    (dist, j)
+                })
+                .collect();
+            d.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let expected = d[..k].iter().filter(|x| x.1 >= num_obs).count() as u32;
+            assert_eq!(*c, expected);
+        }
+    }
+
+    #[test]
+    fn test_score_doublets() {
+        // 3 cell types, each expressing its own block of 20 genes, and
+        // 20 doublets of cells of different types
+        let (num_genes, num_singlets, num_doublets) = (60, 180, 20);
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut cell = |t: usize| -> Vec<f32> {
+            (0..num_genes)
+                .map(|g| {
+                    let mean = if g / 20 == t { 8.0 } else { 0.5 };
+                    (rng.gen_range(0.0..2.0f64) * mean).round() as f32
+                })
+                .collect()
+        };
+        let mut cells: Vec<Vec<f32>> = (0..num_singlets).map(|i| cell(i % 3)).collect();
+        for i in 0..num_doublets {
+            let (a, b) = (cell(i % 3), cell((i + 1) % 3));
+            cells.push(a.iter().zip(b.iter()).map(|(x, y)| x + y).collect());
+        }
+
+        let d = scratch_dir("score");
+        let mat_dir = d.join("alevin");
+        std::fs::create_dir_all(&mat_dir).unwrap();
+        std::fs::write(d.join("quant.json"), r#"{"usa_mode" : false}"#).unwrap();
+        let rows: String = (0..cells.len()).map(|i| format!("cell{}\n", i)).collect();
+        std::fs::write(mat_dir.join("quants_mat_rows.txt"), rows).unwrap();
+        let cols: String = (0..num_genes).map(|g| format!("gene{}\n", g)).collect();
+        std::fs::write(mat_dir.join("quants_mat_cols.txt"), cols).unwrap();
+        let entries: Vec<String> = cells
+            .iter()
+            .enumerate()
+            .flat_map(|(i, c)| {
+                c.iter()
+                    .enumerate()
+                    .filter(|(_, v)| **v > 0.0)
+                    .map(move |(g, v)| format!("{} {} {}", i + 1, g + 1, v))
+            })
+            .collect();
+        let mtx = format!(
+            "%%MatrixMarket matrix coordinate real general\n{} {} {}\n{}\n",
+            cells.len(),
+            num_genes,
+            entries.len(),
+            entries.join("\n")
+        );
+        std::fs::write(mat_dir.join("quants_mat.mtx"), mtx).unwrap();
+
+        let params = DoubletParams {
+            num_pcs: 5,
+            seed: 5,
+            ..Default::default()
+        };
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let out_dir = d.join("out");
+        score_doublets(
+            d.to_str().unwrap(),
+            out_dir.to_str().unwrap(),
+            &params,
+            2,
+            &log,
+        )
+        .unwrap();
+
+        let scores: Vec<f64> = read_lines(&out_dir.join("doublet_scores.tsv"))
+            .unwrap()
+            .iter()
+            .skip(1)
+            .map(|l| l.split('\t').nth(1).unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(scores.len(), cells.len());
+        let mean = |s: &[f64]| s.iter().sum::<f64>() / s.len() as f64;
+        let (singlet_mean, doublet_mean) =
+            (mean(&scores[..num_singlets]), mean(&scores[num_singlets..]));
+        assert!(
+            doublet_mean > 2.0 * singlet_mean,
+            "doublets {} vs. singlets {}",
+            doublet_mean,
+            singlet_mean
+        );
+        // most of the highest scores are those of doublets
+        let mut order: Vec<usize> = (0..scores.len()).collect();
+        order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+        let top = order[..num_doublets]
+            .iter()
+            .filter(|i| **i >= num_singlets)
+            .count();
+        assert!(top * 2 > num_doublets, "{} doublets in the top scores", top);
+        let _ = std::fs::remove_dir_all(&d);
+    }
+}
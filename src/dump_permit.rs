@@ -0,0 +1,231 @@
+/*
+ * Copyright (c) 2020-2022 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+use serde_json::json;
+use slog::info;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::cellfilter::bc_to_string;
+use crate::utils as afutils;
+
+/// The format of the decoded permit list files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    Tsv,
+    Json,
+}
+
+impl DumpFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            DumpFormat::Tsv => "tsv",
+            DumpFormat::Json => "json",
+        }
+    }
+}
+
+/// Write the barcode frequencies `freq` (sorted by decreasing frequency)
+/// to `o_path`.
+fn write_freq(
+    o_path: &Path,
+    fmt: DumpFormat,
+    version: u64,
+    bclen: u16,
+    freq: &HashMap<u64, u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rows: Vec<(String, u64)> = freq
+        .iter()
+        .map(|(bc, c)| (bc_to_string(*bc, bclen as u8), *c))
+        .collect();
+    rows.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut writer = BufWriter::new(File::create(o_path)?);
+    match fmt {
+        DumpFormat::Tsv => {
+            writeln!(writer, "barcode\tnum_reads")?;
+            for (bc, c) in rows {
+                writeln!(writer, "{}\t{}", bc, c)?;
+            }
+        }
+        DumpFormat::Json => {
+            let barcodes: Vec<serde_json::Value> = rows
+                .into_iter()
+                .map(|(bc, c)| json!({"barcode" : bc, "num_reads" : c}))
+                .collect();
+            let out = json!({
+                "format_version" : version,
+                "barcode_length" : bclen,
+                "barcodes" : barcodes
+            });
+            serde_json::to_writer_pretty(&mut writer, &out)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the correction map `correct_map` (from each observed barcode to
+/// the permitted barcode it is corrected to) to `o_path`.
+fn write_correction_map(
+    o_path: &Path,
+    fmt: DumpFormat,
+    bclen: u16,
+    correct_map: &HashMap<u64, u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rows: Vec<(String, String)> = correct_map
+        .iter()
+        .map(|(raw, corrected)| {
+            (
+                bc_to_string(*raw, bclen as u8),
+                bc_to_string(*corrected, bclen as u8),
+            )
+        })
+        .collect();
+    rows.sort_unstable();
+
+    let mut writer = BufWriter::new(File::create(o_path)?);
+    match fmt {
+        DumpFormat::Tsv => {
+            writeln!(writer, "raw_barcode\tcorrected_barcode")?;
+            for (raw, corrected) in rows {
+                writeln!(writer, "{}\t{}", raw, corrected)?;
+            }
+        }
+        DumpFormat::Json => {
+            let corrections: serde_json::Map<String, serde_json::Value> = rows
+                .into_iter()
+                .map(|(raw, corrected)| (raw, serde_json::Value::from(corrected)))
+                .collect();
+            let out = json!({
+                "barcode_length" : bclen,
+                "corrections" : corrections
+            });
+            serde_json::to_writer_pretty(&mut writer, &out)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Decode the binary files written by generate-permit-list to `input_dir`
+/// (permit_freq.bin, all_freq.bin if present, and permit_map.bin) into
+/// TSV or JSON files, with nucleotide barcodes, in `output_dir`.
+pub fn dump_permit(
+    input_dir: &str,
+    output_dir: &str,
+    fmt: DumpFormat,
+    log: &slog::Logger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let i_dir = Path::new(input_dir);
+    let o_dir = Path::new(output_dir);
+    std::fs::create_dir_all(o_dir)?;
+
+    let (version, bclen, permit_freq) =
+        afutils::read_permit_list_freq(&i_dir.join("permit_freq.bin"))?;
+    let o_path = o_dir.join(format!("permit_freq.{}", fmt.extension()));
+    write_freq(&o_path, fmt, version, bclen, &permit_freq)?;
+    info!(
+        log,
+        "wrote {} permitted barcodes to {}",
+        permit_freq.len(),
+        o_path.display()
+    );
+
+    // only written for filtered permit lists
+    let all_freq_path = i_dir.join("all_freq.bin");
+    if all_freq_path.exists() {
+        let (version, bclen, all_freq) = afutils::read_permit_list_freq(&all_freq_path)?;
+        let o_path = o_dir.join(format!("all_freq.{}", fmt.extension()));
+        write_freq(&o_path, fmt, version, bclen, &all_freq)?;
+        info!(
+            log,
+            "wrote {} observed barcodes to {}",
+            all_freq.len(),
+            o_path.display()
+        );
+    }
+
+    let cm_file = File::open(i_dir.join("permit_map.bin"))?;
+    let correct_map: HashMap<u64, u64> = bincode::deserialize_from(&cm_file)?;
+    let o_path = o_dir.join(format!("permit_map.{}", fmt.extension()));
+    write_correction_map(&o_path, fmt, bclen, &correct_map)?;
+    info!(
+        log,
+        "wrote the corrections of {} barcodes to {}",
+        correct_map.len(),
+        o_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_permit_decodes_the_binary_files() {
+        let d = std::env::temp_dir().join(format!("af_dump_permit_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&d);
+        std::fs::create_dir_all(&d).unwrap();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+
+        // AACT and TGCA
+        let (bc1, bc2) = (0b0000_0111u64, 0b1110_0100u64);
+        let mut freq = HashMap::with_hasher(ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64));
+        freq.insert(bc1, 3u64);
+        freq.insert(bc2, 10u64);
+        afutils::write_permit_list_freq(&d.join("permit_freq.bin"), 4, &freq).unwrap();
+        // AACA is corrected to AACT
+        let correct_map: HashMap<u64, u64> = [(bc1, bc1), (bc2, bc2), (0b0000_0100u64, bc1)]
+            .iter()
+            .copied()
+            .collect();
+        let cm_file = File::create(d.join("permit_map.bin")).unwrap();
+        bincode::serialize_into(&cm_file, &correct_map).unwrap();
+
+        let out = d.join("tsv");
+        dump_permit(
+            d.to_str().unwrap(),
+            out.to_str().unwrap(),
+            DumpFormat::Tsv,
+            &log,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(out.join("permit_freq.tsv")).unwrap(),
+            "barcode\tnum_reads\nTGCA\t10\nAACT\t3\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("permit_map.tsv")).unwrap(),
+            "raw_barcode\tcorrected_barcode\nAACA\tAACT\nAACT\tAACT\nTGCA\tTGCA\n"
+        );
+        assert!(!out.join("all_freq.tsv").exists());
+
+        let out = d.join("json");
+        dump_permit(
+            d.to_str().unwrap(),
+            out.to_str().unwrap(),
+            DumpFormat::Json,
+            &log,
+        )
+        .unwrap();
+        let pf: serde_json::Value =
+            serde_json::from_reader(File::open(out.join("permit_freq.json")).unwrap()).unwrap();
+        assert_eq!(pf["barcode_length"], 4);
+        assert_eq!(pf["barcodes"][0]["barcode"], "TGCA");
+        assert_eq!(pf["barcodes"][1]["num_reads"], 3);
+        let pm: serde_json::Value =
+            serde_json::from_reader(File::open(out.join("permit_map.json")).unwrap()).unwrap();
+        assert_eq!(pm["corrections"]["AACA"], "AACT");
+        let _ = std::fs::remove_dir_all(&d);
+    }
+}
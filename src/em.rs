@@ -9,9 +9,11 @@
 
 #[allow(unused_imports)]
 use crate::eq_class::IndexedEqList;
+use crate::utils::sorted_eq_classes;
 #[allow(unused_imports)]
 use ahash::{AHasher, RandomState};
-use rand::{thread_rng, Rng};
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
 #[allow(unused_imports)]
 use slog::info;
 use statrs::distribution::Multinomial;
@@ -32,11 +34,117 @@ const MIN_ITER: u32 = 2;
 const MAX_ITER: u32 = 100;
 const REL_DIFF_TOLERANCE: f32 = 1e-2;
 
+// the number of sweeps discarded before the Gibbs
+// sampler records samples, the number of sweeps between
+// recorded samples, and the (symmetric) Dirichlet
+// concentration of the gene abundances
+const GIBBS_BURN_IN: u32 = 50;
+const GIBBS_THIN: u32 = 4;
+const GIBBS_PRIOR: f32 = 1e-2;
+
 #[derive(Copy, Clone)]
 pub enum EmInitType {
     Informative,
     Uniform,
-    Random,
+    // random initialization, seeded with the
+    // provided value so that it is reproducible
+    Random(u64),
+}
+
+/// Where the abundances used as a prior in the per-cell EM come from.
+#[derive(Clone, Debug)]
+pub enum EmPriorSource {
+    // gene-unique read counts summed over all (retained) cells
+    PseudoBulk,
+    // a 2-column (feature name, abundance) file provided by the user
+    Table(String),
+}
+
+impl EmPriorSource {
+    pub fn as_str(&self) -> &str {
+        match self {
+            EmPriorSource::PseudoBulk => "pseudo-bulk",
+            EmPriorSource::Table(f) => f,
+        }
+    }
+}
+
+/// The default weight of an EM prior, relative to the number of
+/// molecules of the cell it is applied to.
+pub const DEFAULT_EM_PRIOR_STRENGTH: f32 = 0.1;
+
+/// A Dirichlet prior over the abundances estimated by the per-cell EM.
+///
+/// For a cell with `N` molecules, the concentration of feature `i` is
+/// `1 + q_i`, where the pseudo-counts `q_i = strength * N * p_i` are
+/// the normalized prior abundances `p` scaled to sum to `strength`
+/// times the number of molecules of the cell.  The EM then computes the
+/// MAP estimate of the abundances, whose M-step is `n_i + q_i` given
+/// the expected molecule counts `n_i` of the E-step (as every
+/// concentration is at least 1, this is never negative).  The
+/// reported counts are the expected molecule counts under the MAP
+/// abundances, so that they still sum to the molecules of the cell.
+#[derive(Clone, Debug)]
+pub struct EmPrior {
+    pub source: EmPriorSource,
+    pub strength: f32,
+}
+
+/// Turn the (unnormalized) abundances `abund` into the per-molecule
+/// pseudo-counts of a Dirichlet prior of weight `strength` (see
+/// [`EmPrior`]); they sum to `strength`, and are scaled by the number
+/// of molecules of each cell in the EM.
+/// Returns `None` if no feature has a positive abundance.
+pub fn prior_pseudo_counts(abund: &[f64], strength: f32) -> Option<Vec<f32>> {
+    let tot: f64 = abund.iter().filter(|x| **x > 0.0).sum();
+    if tot <= 0.0 {
+        return None;
+    }
+    let scale = strength as f64 / tot;
+    Some(abund.iter().map(|x| (x.max(0.0) * scale) as f32).collect())
+}
+
+/// The pseudo-counts of a prior for the features of a single cell;
+/// those of feature `i` are `scale * per_molecule[i]`, where `scale`
+/// is the number of molecules of the cell.
+#[derive(Clone, Copy)]
+pub(crate) struct CellPrior<'a> {
+    per_molecule: &'a [f32],
+    scale: f32,
+}
+
+impl<'a> CellPrior<'a> {
+    fn new(per_molecule: &'a [f32], num_molecules: f32) -> Self {
+        Self {
+            per_molecule,
+            scale: num_molecules,
+        }
+    }
+
+    /// The pseudo-counts of the feature `label`, pooled over the
+    /// categories of its gene in the same way as the abundances
+    /// when `blocks` is provided.
+    #[inline(always)]
+    fn get(&self, label: u32, blocks: Option<FeatureBlocks>) -> f32 {
+        self.scale
+            * match blocks {
+                Some(b) => get_abundance_for(label, self.per_molecule, b),
+                None => self.per_molecule[label as usize],
+            }
+    }
+}
+
+/// Derive the seed used for the random number generator of a
+/// single cell from the global `seed` and a value identifying
+/// the cell (e.g. its barcode).  Since the seed depends only on the
+/// cell, and not on the thread or order in which cells are processed,
+/// results are reproducible regardless of the number of threads.
+/// This is the SplitMix64 finalizer.
+pub fn cell_seed(seed: u64, cell_key: u64) -> u64 {
+    let mut z = seed ^ cell_key.wrapping_mul(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
 }
 
 #[allow(dead_code)]
@@ -87,28 +195,52 @@ fn spliced_idx_of(idx: u32, usa_offset: (usize, usize)) -> u32 {
     }
 }
 
-/// Based on the type of the index `idx` (spliced, unspliced or ambiguous)
-/// get the appropriate count for this gene, status pair to use in the EM.
+/// The layout of the columns of a cell when each gene has several
+/// features: a block of `num_genes` columns for each of the
+/// `num_categories` categories, followed by a block for the molecules
+/// that are ambiguous between the categories of a gene.  USA mode is the
+/// case of 2 categories (spliced and unspliced).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeatureBlocks {
+    pub num_genes: usize,
+    pub num_categories: usize,
+}
+
+impl FeatureBlocks {
+    /// The layout of the `num_cols` spliced, unspliced and
+    /// ambiguous columns of USA mode.
+    pub fn usa(num_cols: usize) -> Self {
+        Self {
+            num_genes: num_cols / 3,
+            num_categories: 2,
+        }
+    }
+
+    /// The offset of the block of ambiguous columns.
+    pub fn ambiguous_offset(&self) -> usize {
+        self.num_categories * self.num_genes
+    }
+}
+
+/// Based on the block of the index `idx` (a category, or ambiguous)
+/// get the appropriate count for this gene, category pair to use in the EM.
 #[inline(always)]
-fn get_abundance_for(idx: u32, alphas_in: &[f32], usa_offset: (usize, usize)) -> f32 {
+fn get_abundance_for(idx: u32, alphas_in: &[f32], blocks: FeatureBlocks) -> f32 {
     let index = idx as usize;
-    // if this index is ambiguous, subtract off
-    // the ambig offset
-    if index >= usa_offset.1 {
+    let ng = blocks.num_genes;
+    let ambig_offset = blocks.ambiguous_offset();
+    if index >= ambig_offset {
         // in the ambiguous case, get the abundance
-        // of spliced / unspliced / and ambiguous
-        alphas_in[index - usa_offset.0] + // unspliced
-        alphas_in[index - usa_offset.1] + // spliced
-        alphas_in[index] // ambig
-    } else if index >= usa_offset.0 {
-        // in the unspliced case, get the abundance of
-        // unspliced and ambiguous
-        alphas_in[index + usa_offset.0] + // ambiguous
-        alphas_in[index] // unspliced
+        // of every category of the gene and ambiguous
+        let g = index - ambig_offset;
+        (0..blocks.num_categories)
+            .map(|c| alphas_in[c * ng + g])
+            .sum::<f32>()
+            + alphas_in[index]
     } else {
-        // otherwise it is spliced, return it as is
-        alphas_in[index + usa_offset.1] + // ambiguous
-        alphas_in[index] // spliced
+        // otherwise, get the abundance of the
+        // category and ambiguous
+        alphas_in[ambig_offset + index % ng] + alphas_in[index]
     }
 }
 
@@ -117,21 +249,24 @@ pub(crate) fn em_update_subset(
     alphas_out: &mut Vec<f32>,
     eqclasses: &IndexedEqList,
     cell_data: &[(u32, u32)], // indices into eqclasses relevant for this cell
+    prior: Option<CellPrior>,
 ) {
+    // the MAP abundance of a feature given its expected count
+    let weight = |index: usize| alphas_in[index] + prior.map_or(0.0, |p| p.get(index as u32, None));
     for (i, count) in cell_data {
         let labels = eqclasses.refs_for_eqc(*i);
 
         if labels.len() > 1 {
             let mut denominator: f32 = 0.0;
             for label in labels {
-                denominator += alphas_in[*label as usize];
+                denominator += weight(*label as usize);
             }
 
             if denominator > 0.0 {
                 let inv_denominator = *count as f32 / denominator;
                 for label in labels {
                     let index = *label as usize;
-                    let count = alphas_in[index] * inv_denominator;
+                    let count = weight(index) * inv_denominator;
                     alphas_out[index] += count;
                 }
             }
@@ -142,26 +277,32 @@ pub(crate) fn em_update_subset(
     }
 }
 
-pub(crate) fn em_update_subset_usa(
+pub(crate) fn em_update_subset_blocks(
     alphas_in: &[f32],
     alphas_out: &mut Vec<f32>,
     eqclasses: &IndexedEqList,
     cell_data: &[(u32, u32)], // indices into eqclasses relevant for this cell
-    usa_offsets: (usize, usize),
+    blocks: FeatureBlocks,
+    prior: Option<CellPrior>,
 ) {
+    // the MAP abundance of a feature given the expected counts
+    let weight = |label: u32| {
+        get_abundance_for(label, alphas_in, blocks)
+            + prior.map_or(0.0, |p| p.get(label, Some(blocks)))
+    };
     for (i, count) in cell_data {
         let labels = eqclasses.refs_for_eqc(*i);
 
         if labels.len() > 1 {
             let mut denominator: f32 = 0.0;
             for label in labels {
-                denominator += get_abundance_for(*label, alphas_in, usa_offsets);
+                denominator += weight(*label);
             }
 
             if denominator > 0.0 {
                 let inv_denominator = *count as f32 / denominator;
                 for label in labels {
-                    let count = get_abundance_for(*label, alphas_in, usa_offsets) * inv_denominator;
+                    let count = weight(*label) * inv_denominator;
                     let index = *label as usize;
                     alphas_out[index] += count;
                 }
@@ -182,7 +323,8 @@ pub fn em_optimize_subset(
     init_type: EmInitType,
     num_alphas: usize,
     only_unique: bool,
-    usa_offsets: Option<(usize, usize)>,
+    blocks: Option<FeatureBlocks>,
+    prior: Option<&[f32]>,
     _log: &slog::Logger,
 ) -> Vec<f32> {
     let mut alphas_in: Vec<f32> = vec![0.0; num_alphas];
@@ -210,8 +352,14 @@ pub fn em_optimize_subset(
         return alphas_in;
     }
 
+    let num_molecules: u32 = cell_data.iter().map(|x| x.1).sum();
+    let prior = prior.map(|p| CellPrior::new(p, num_molecules as f32));
+
     // fill in the alphas based on the initialization strategy
-    let mut rng = rand::thread_rng();
+    let mut rng = match init_type {
+        EmInitType::Random(seed) => Some(StdRng::seed_from_u64(seed)),
+        _ => None,
+    };
     let uni_prior = 1.0 / (num_alphas as f32);
     for item in alphas_in.iter_mut().take(num_alphas) {
         match init_type {
@@ -221,8 +369,10 @@ pub fn em_optimize_subset(
             EmInitType::Informative => {
                 *item = (*item + 0.5) * 1e-3;
             }
-            EmInitType::Random => {
-                *item = rng.gen::<f32>() + 1e-5;
+            EmInitType::Random(_) => {
+                if let Some(r) = rng.as_mut() {
+                    *item = r.gen::<f32>() + 1e-5;
+                }
             }
         }
     }
@@ -235,12 +385,19 @@ pub fn em_optimize_subset(
 
     while it_num < MIN_ITER || (it_num < MAX_ITER && !converged) || last_round {
         // perform one round of em update
-        match usa_offsets {
-            Some(otup) => {
-                em_update_subset_usa(&alphas_in, &mut alphas_out, eqclasses, cell_data, otup);
+        match blocks {
+            Some(b) => {
+                em_update_subset_blocks(
+                    &alphas_in,
+                    &mut alphas_out,
+                    eqclasses,
+                    cell_data,
+                    b,
+                    prior,
+                );
             }
             None => {
-                em_update_subset(&alphas_in, &mut alphas_out, eqclasses, cell_data);
+                em_update_subset(&alphas_in, &mut alphas_out, eqclasses, cell_data, prior);
             }
         }
 
@@ -297,24 +454,27 @@ pub fn em_optimize_subset(
     alphas_in
 }
 
-pub fn em_update(
+pub(crate) fn em_update(
     alphas_in: &[f32],
     alphas_out: &mut Vec<f32>,
-    eqclasses: &HashMap<Vec<u32>, u32, ahash::RandomState>,
+    eqclasses: &[(&Vec<u32>, &u32)],
+    prior: Option<CellPrior>,
 ) {
+    // the MAP abundance of a feature given its expected count
+    let weight = |index: usize| alphas_in[index] + prior.map_or(0.0, |p| p.get(index as u32, None));
     // loop over all the eqclasses
-    for (labels, count) in eqclasses {
+    for &(labels, count) in eqclasses.iter() {
         if labels.len() > 1 {
             let mut denominator: f32 = 0.0;
             for label in labels {
-                denominator += alphas_in[*label as usize];
+                denominator += weight(*label as usize);
             }
 
             if denominator > 0.0 {
                 let inv_denominator = *count as f32 / denominator;
                 for label in labels {
                     let index = *label as usize;
-                    let count = alphas_in[index] * inv_denominator;
+                    let count = weight(index) * inv_denominator;
                     alphas_out[index] += count;
                 }
             }
@@ -325,6 +485,7 @@ pub fn em_update(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn em_optimize(
     eqclasses: &HashMap<Vec<u32>, u32, ahash::RandomState>,
     unique_evidence: &mut Vec<bool>,
@@ -332,12 +493,14 @@ pub fn em_optimize(
     init_type: EmInitType,
     num_alphas: usize,
     only_unique: bool,
+    prior: Option<&[f32]>,
     _log: &slog::Logger,
 ) -> Vec<f32> {
     let mut alphas_in: Vec<f32> = vec![0.0; num_alphas];
     let mut alphas_out: Vec<f32> = vec![0.0; num_alphas];
 
-    for (labels, count) in eqclasses {
+    let eqclasses = sorted_eq_classes(eqclasses);
+    for &(labels, count) in eqclasses.iter() {
         if labels.len() == 1 {
             let idx = labels.get(0).expect("can't extract labels");
             alphas_in[*idx as usize] += *count as f32;
@@ -353,8 +516,14 @@ pub fn em_optimize(
         return alphas_in;
     }
 
+    let num_molecules: u32 = eqclasses.iter().map(|(_, c)| **c).sum();
+    let prior = prior.map(|p| CellPrior::new(p, num_molecules as f32));
+
     // fill in the alphas based on the initialization strategy
-    let mut rng = rand::thread_rng();
+    let mut rng = match init_type {
+        EmInitType::Random(seed) => Some(StdRng::seed_from_u64(seed)),
+        _ => None,
+    };
     let uni_prior = 1.0 / (num_alphas as f32);
     for item in alphas_in.iter_mut().take(num_alphas) {
         match init_type {
@@ -364,8 +533,10 @@ pub fn em_optimize(
             EmInitType::Informative => {
                 *item = (*item + 0.5) * 1e-3;
             }
-            EmInitType::Random => {
-                *item = rng.gen::<f32>() + 1e-5;
+            EmInitType::Random(_) => {
+                if let Some(r) = rng.as_mut() {
+                    *item = r.gen::<f32>() + 1e-5;
+                }
             }
         }
     }
@@ -377,7 +548,7 @@ pub fn em_optimize(
     let mut converged: bool = true;
     while it_num < MIN_ITER || (it_num < MAX_ITER && !converged) {
         // perform one round of em update
-        em_update(&alphas_in, &mut alphas_out, eqclasses);
+        em_update(&alphas_in, &mut alphas_out, &eqclasses, prior);
 
         converged = true;
         let mut max_rel_diff = -f32::INFINITY;
@@ -421,15 +592,19 @@ pub fn em_optimize(
     alphas_in
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn run_bootstrap_subset(
     eqclasses: &IndexedEqList,
     cell_data: &[(u32, u32)], // (eq_id, count) vec for classes relevant for this cell
     num_alphas: u32,          // number of genes
     num_bootstraps: u32,      // number of bootstraps to draw
-    _init_uniform: bool,
+    init_uniform: bool,
     summary_stat: bool, // if true, the output will simply be a vector of means and variances
+    seed: u64,
+    prior: Option<&[f32]>,
     _log: &slog::Logger,
 ) -> Vec<Vec<f32>> {
+    let mut rng = StdRng::seed_from_u64(seed);
     // the population sample size
     let total_fragments: u32 = cell_data.iter().map(|x| x.1).sum();
     assert!(
@@ -469,7 +644,7 @@ pub(crate) fn run_bootstrap_subset(
     // let mut old_resampled_counts = Vec::new();
     for _bs_num in 0..num_bootstraps {
         // resample from multinomial
-        let resampled_counts = thread_rng().sample(dist.clone());
+        let resampled_counts = rng.sample(dist.clone());
         for (idx, (eq_id, _orig_count)) in cell_data.iter().enumerate() {
             bootstrap_counts.push((*eq_id, resampled_counts[idx].round() as u32));
         }
@@ -479,10 +654,15 @@ pub(crate) fn run_bootstrap_subset(
             &bootstrap_counts[..], // indices into eqclasses relevant for this cell
             &mut unique_evidence,
             &mut no_ambiguity,
-            EmInitType::Random,
+            if init_uniform {
+                EmInitType::Uniform
+            } else {
+                EmInitType::Random(rng.gen())
+            },
             num_alphas_us,
             false, // only unique
             None,
+            prior,
             _log,
         );
 
@@ -520,6 +700,182 @@ pub(crate) fn run_bootstrap_subset(
     bootstraps
 }
 
+/// Draw `num_samples` posterior samples of the gene-level molecule counts
+/// for the cell whose equivalence classes are `cell_data` using a collapsed
+/// Gibbs sampler.  The gene abundances are integrated out under a Dirichlet
+/// prior and each molecule of a multi-gene equivalence class is
+/// repeatedly reassigned to one of its genes with probability proportional to
+/// the number of molecules currently assigned to that gene (pooling the
+/// counts of the categories of the gene (e.g. spliced, unspliced and
+/// ambiguous) when `blocks` is provided, as in the EM) plus its
+/// concentration.  The concentrations are `GIBBS_PRIOR` plus, if a
+/// `prior` is provided, the same pseudo-counts as in the EM.  The sampler
+/// is initialized from the EM estimate `init_alphas`.  As with the bootstrap, if `summary_stat` is true only the
+/// mean and variance of the samples are returned.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_gibbs_subset(
+    eqclasses: &IndexedEqList,
+    cell_data: &[(u32, u32)], // (eq_id, count) vec for classes relevant for this cell
+    num_alphas: u32,          // number of genes
+    num_samples: u32,         // number of posterior samples to draw
+    init_alphas: &[f32],
+    blocks: Option<FeatureBlocks>,
+    summary_stat: bool, // if true, the output will simply be a vector of means and variances
+    seed: u64,
+    prior: Option<&[f32]>,
+    _log: &slog::Logger,
+) -> Vec<Vec<f32>> {
+    let num_alphas_us = num_alphas as usize;
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let num_molecules: u32 = cell_data.iter().map(|x| x.1).sum();
+    let prior = prior.map(|p| CellPrior::new(p, num_molecules as f32));
+    // the Dirichlet concentration of a feature
+    let concentration = |label: u32| GIBBS_PRIOR + prior.map_or(0.0, |p| p.get(label, blocks));
+
+    // the number of molecules currently assigned to each gene
+    let mut assigned: Vec<f32> = vec![0.0; num_alphas_us];
+    // the current gene (as an index into the labels of its
+    // equivalence class) of every molecule in a multi-gene
+    // equivalence class, and the range of each such class
+    // within this vector.
+    let mut molecule_labels = Vec::<u32>::new();
+    let mut multi_classes = Vec::<(u32, usize, usize)>::new();
+    let mut weights = Vec::<f32>::new();
+
+    // sample an index from the (unnormalized) weights
+    let sample_label = |weights: &[f32], rng: &mut StdRng| -> usize {
+        let tot: f32 = weights.iter().sum();
+        let mut u = rng.gen::<f32>() * tot;
+        for (i, w) in weights.iter().enumerate() {
+            if u < *w {
+                return i;
+            }
+            u -= *w;
+        }
+        weights.len() - 1
+    };
+
+    for (eq_id, count) in cell_data {
+        let labels = eqclasses.refs_for_eqc(*eq_id);
+        if labels.len() == 1 {
+            assigned[labels[0] as usize] += *count as f32;
+        } else {
+            let start = molecule_labels.len();
+            weights.clear();
+            weights.extend(
+                labels
+                    .iter()
+                    .map(|l| init_alphas[*l as usize] + concentration(*l)),
+            );
+            for _ in 0..*count {
+                let li = sample_label(&weights, &mut rng);
+                assigned[labels[li] as usize] += 1.0;
+                molecule_labels.push(li as u32);
+            }
+            multi_classes.push((*eq_id, start, molecule_labels.len()));
+        }
+    }
+
+    let mut samples_sum: Vec<f32> = vec![0.0; num_alphas_us];
+    let mut samples_square_sum: Vec<f32> = vec![0.0; num_alphas_us];
+
+    let num_output = if summary_stat {
+        2usize
+    } else {
+        num_samples as usize
+    };
+    let mut samples = Vec::with_capacity(num_output);
+
+    let num_sweeps = GIBBS_BURN_IN + num_samples * GIBBS_THIN;
+    // the number of sweeps until the next sample is recorded
+    let mut sweeps_to_sample = GIBBS_BURN_IN + GIBBS_THIN;
+    for _sweep in 0..num_sweeps {
+        for (eq_id, start, end) in &multi_classes {
+            let labels = eqclasses.refs_for_eqc(*eq_id);
+            for ml in molecule_labels[*start..*end].iter_mut() {
+                // remove this molecule from its current gene
+                assigned[labels[*ml as usize] as usize] -= 1.0;
+                weights.clear();
+                weights.extend(labels.iter().map(|l| {
+                    let n = match blocks {
+                        Some(b) => get_abundance_for(*l, &assigned, b),
+                        None => assigned[*l as usize],
+                    };
+                    n + concentration(*l)
+                }));
+                // and reassign it
+                let li = sample_label(&weights, &mut rng);
+                assigned[labels[li] as usize] += 1.0;
+                *ml = li as u32;
+            }
+        }
+
+        sweeps_to_sample -= 1;
+        if sweeps_to_sample == 0 {
+            sweeps_to_sample = GIBBS_THIN;
+            if summary_stat {
+                for i in 0..num_alphas_us {
+                    samples_sum[i] += assigned[i];
+                    samples_square_sum[i] += assigned[i] * assigned[i];
+                }
+            } else {
+                samples.push(assigned.clone());
+            }
+        }
+    }
+
+    if summary_stat {
+        let mut sample_mean: Vec<f32> = vec![0.0; num_alphas_us];
+        let mut sample_var: Vec<f32> = vec![0.0; num_alphas_us];
+        for i in 0..num_alphas_us {
+            let mean_alpha = samples_sum[i] / num_samples as f32;
+            sample_mean[i] = mean_alpha;
+            sample_var[i] =
+                (samples_square_sum[i] / num_samples as f32) - (mean_alpha * mean_alpha);
+        }
+        samples.push(sample_mean);
+        samples.push(sample_var);
+    }
+
+    samples
+}
+
+/// A thin wrapper around `run_gibbs_subset` for the equivalence classes
+/// of a single cell, in the same manner as `run_bootstrap`.
+pub fn run_gibbs(
+    eqclasses: &HashMap<Vec<u32>, u32, ahash::RandomState>,
+    num_samples: u32,
+    gene_alpha: &[f32],
+    summary_stat: bool,
+    seed: u64,
+    prior: Option<&[f32]>,
+    _log: &slog::Logger,
+) -> Vec<Vec<f32>> {
+    let eql = IndexedEqList::init_from_hash(eqclasses, gene_alpha.len());
+    let mut cell_data: Vec<(u32, u32)> = eqclasses
+        .iter()
+        .enumerate()
+        .map(|(idx, (_labels, count))| (idx as u32, *count))
+        .collect();
+    // visit the equivalence classes in an order that doesn't depend
+    // on the hash table, so that seeded runs are reproducible.
+    cell_data.sort_unstable_by(|a, b| eql.refs_for_eqc(a.0).cmp(eql.refs_for_eqc(b.0)));
+    run_gibbs_subset(
+        &eql,
+        &cell_data[..],
+        gene_alpha.len() as u32,
+        num_samples,
+        gene_alpha,
+        None,
+        summary_stat,
+        seed,
+        prior,
+        _log,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn run_bootstrap(
     eqclasses: &HashMap<Vec<u32>, u32, ahash::RandomState>,
     num_bootstraps: u32,
@@ -528,8 +884,10 @@ pub fn run_bootstrap(
     // no_ambiguity: &mut Vec<bool>,
     // num_alphas: usize,
     // only_unique: bool,
-    _init_uniform: bool,
+    init_uniform: bool,
     summary_stat: bool,
+    seed: u64,
+    prior: Option<&[f32]>,
     _log: &slog::Logger,
 ) -> Vec<Vec<f32>> {
     // This function is just a thin wrapper around run_bootstrap_subset.
@@ -545,11 +903,14 @@ pub fn run_bootstrap(
 
     // since this is a local (not global) eq-list, the cell data is just
     // the indices of all equivalence classes and their corresponding counts.
-    let cell_data: Vec<(u32, u32)> = eqclasses
+    let mut cell_data: Vec<(u32, u32)> = eqclasses
         .iter()
         .enumerate()
         .map(|(idx, (_labels, count))| (idx as u32, *count))
         .collect();
+    // visit the equivalence classes in an order that doesn't depend
+    // on the hash table, so that seeded runs are reproducible.
+    cell_data.sort_unstable_by(|a, b| eql.refs_for_eqc(a.0).cmp(eql.refs_for_eqc(b.0)));
 
     // now that we have the `IndexedEqList` representation of this data, just
     // run that version of the bootstrap function and return the result.
@@ -558,8 +919,10 @@ pub fn run_bootstrap(
         &cell_data[..],
         gene_alpha.len() as u32,
         num_bootstraps,
-        _init_uniform,
+        init_uniform,
         summary_stat,
+        seed,
+        prior,
         _log,
     )
 }
@@ -645,7 +1008,12 @@ pub fn run_bootstrap_old(
         let mut converged: bool = false;
         while it_num < MIN_ITER || (it_num < MAX_ITER && !converged) {
             // perform one round of em update
-            em_update(&alphas, &mut alphas_prime, &eqclass_bootstrap);
+            em_update(
+                &alphas,
+                &mut alphas_prime,
+                &sorted_eq_classes(&eqclass_bootstrap),
+                None,
+            );
 
             converged = true;
             let mut max_rel_diff = -f32::INFINITY;
@@ -705,3 +1073,159 @@ pub fn run_bootstrap_old(
 
     bootstraps
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    // an equivalence class list where class 0 is unique to gene 0, class 1
+    // unique to gene 1 and class 2 is ambiguous between them.
+    fn two_gene_eq_list() -> IndexedEqList {
+        let mut eqc = HashMap::with_hasher(ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64));
+        eqc.insert(vec![0u32], 0u32);
+        eqc.insert(vec![1u32], 1u32);
+        eqc.insert(vec![0u32, 1u32], 2u32);
+        IndexedEqList::init_from_hash(&eqc, 2)
+    }
+
+    // the id in `eql` of the equivalence class with the given labels
+    fn eq_id(eql: &IndexedEqList, labels: &[u32]) -> u32 {
+        (0..eql.num_eq_classes() as u32)
+            .find(|i| eql.refs_for_eqc(*i) == labels)
+            .unwrap()
+    }
+
+    fn run_em(eql: &IndexedEqList, cell_data: &[(u32, u32)], prior: Option<&[f32]>) -> Vec<f32> {
+        em_optimize_subset(
+            eql,
+            cell_data,
+            &mut vec![false; 2],
+            &mut vec![true; 2],
+            EmInitType::Informative,
+            2,
+            false,
+            None,
+            prior,
+            &logger(),
+        )
+    }
+
+    #[test]
+    fn em_does_not_depend_on_hash_history() {
+        let hasher = || ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+        let classes: Vec<(Vec<u32>, u32)> = (0..50u32)
+            .map(|i| (vec![i % 7, 7 + i % 5, 12 + i % 3], 1 + i % 4))
+            .chain((0..15u32).map(|g| (vec![g], 3)))
+            .collect();
+
+        let mut fresh = HashMap::with_hasher(hasher());
+        // a table that held other equivalence classes before
+        let mut reused = HashMap::with_hasher(hasher());
+        for i in 0..10_000u32 {
+            reused.insert(vec![i, i + 1], i);
+        }
+        reused.clear();
+        for (labels, count) in classes.iter() {
+            *fresh.entry(labels.clone()).or_insert(0) += *count;
+        }
+        for (labels, count) in classes.iter().rev() {
+            *reused.entry(labels.clone()).or_insert(0) += *count;
+        }
+
+        let run = |eqc: &HashMap<Vec<u32>, u32, ahash::RandomState>| {
+            em_optimize(
+                eqc,
+                &mut vec![false; 15],
+                &mut vec![true; 15],
+                EmInitType::Informative,
+                15,
+                false,
+                None,
+                &logger(),
+            )
+        };
+        assert_eq!(run(&fresh), run(&reused));
+    }
+
+    #[test]
+    fn prior_pseudo_counts_sum_to_strength() {
+        let p = prior_pseudo_counts(&[1.0, 3.0, -2.0, 0.0], 0.5).unwrap();
+        assert_eq!(p, vec![0.125, 0.375, 0.0, 0.0]);
+        assert!(prior_pseudo_counts(&[0.0, -1.0], 1.0).is_none());
+    }
+
+    #[test]
+    fn prior_changes_ambiguous_split() {
+        let eql = two_gene_eq_list();
+        let cell_data = vec![(eq_id(&eql, &[0, 1]), 20)];
+
+        // without unique evidence, the molecules are split evenly
+        let counts = run_em(&eql, &cell_data, None);
+        assert!((counts[0] - 10.0).abs() < 1e-3, "{:?}", counts);
+
+        // with the default strength, the split follows the prior
+        let prior = prior_pseudo_counts(&[1.0, 9.0], DEFAULT_EM_PRIOR_STRENGTH).unwrap();
+        let counts = run_em(&eql, &cell_data, Some(&prior));
+        assert!((counts[0] + counts[1] - 20.0).abs() < 1e-3, "{:?}", counts);
+        assert!(counts[0] < 4.0 && counts[1] > 16.0, "{:?}", counts);
+    }
+
+    #[test]
+    fn prior_is_outweighed_by_unique_evidence() {
+        let eql = two_gene_eq_list();
+        let cell_data = vec![
+            (eq_id(&eql, &[0]), 30),
+            (eq_id(&eql, &[1]), 10),
+            (eq_id(&eql, &[0, 1]), 20),
+        ];
+        let no_prior = run_em(&eql, &cell_data, None);
+        let prior = prior_pseudo_counts(&[1.0, 9.0], DEFAULT_EM_PRIOR_STRENGTH).unwrap();
+        let with_prior = run_em(&eql, &cell_data, Some(&prior));
+        // the prior moves molecules to gene 1, but gene 0 keeps the most
+        assert!(
+            with_prior[1] > no_prior[1] + 1.0,
+            "{:?} {:?}",
+            no_prior,
+            with_prior
+        );
+        assert!(with_prior[0] > with_prior[1], "{:?}", with_prior);
+        assert!((with_prior[0] + with_prior[1] - 60.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn gibbs_samples_follow_the_prior() {
+        let eql = two_gene_eq_list();
+        let cell_data = vec![(eq_id(&eql, &[0, 1]), 20)];
+        let init = vec![10.0, 10.0];
+        let mean_counts = |abund: &[f64]| {
+            let prior = prior_pseudo_counts(abund, DEFAULT_EM_PRIOR_STRENGTH).unwrap();
+            // with summary statistics, the first output is the sample mean
+            run_gibbs_subset(
+                &eql,
+                &cell_data,
+                2,
+                200,
+                &init,
+                None,
+                true,
+                17,
+                Some(&prior),
+                &logger(),
+            )
+            .swap_remove(0)
+        };
+        let to_gene_0 = mean_counts(&[9.0, 1.0]);
+        let to_gene_1 = mean_counts(&[1.0, 9.0]);
+        assert!((to_gene_1[0] + to_gene_1[1] - 20.0).abs() < 1e-3);
+        assert!(
+            to_gene_0[0] > 12.0 && to_gene_1[1] > 12.0,
+            "{:?} {:?}",
+            to_gene_0,
+            to_gene_1
+        );
+    }
+}
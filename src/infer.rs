@@ -9,40 +9,272 @@
 
 use crate::cellfilter::permit_list_from_file;
 use crossbeam_queue::ArrayQueue;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::ProgressStyle;
 #[allow(unused_imports)]
 use slog::{crit, info, warn};
 
 use needletail::bitkmer::*;
 use sprs::TriMatI;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use crate::em::{em_optimize_subset, EmInitType};
+use crate::em::{
+    cell_seed, em_optimize_subset, prior_pseudo_counts, run_gibbs_subset, EmInitType,
+    FeatureBlocks, DEFAULT_EM_PRIOR_STRENGTH,
+};
+use crate::eq_class::IndexedEqList;
+use crate::logging;
+use crate::utils as afutils;
 use crate::utils::read_filter_list;
 
-#[allow(clippy::too_many_arguments)]
+// the cluster labels and the map from each barcode to its cluster index
+type ClusterAssignments = (Vec<String>, HashMap<u64, usize, ahash::RandomState>);
+
+/// Read the assignment of cells to clusters from `fname`, a
+/// whitespace-separated file with a barcode and a cluster label on each
+/// line.  Returns the distinct cluster labels (in the order they are first
+/// encountered) and a map from each (encoded) barcode to the index of
+/// its cluster label.
+fn read_cluster_assignments(
+    fname: &str,
+    bc_len: u16,
+) -> Result<ClusterAssignments, Box<dyn std::error::Error>> {
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut bc_to_cluster = HashMap::<u64, usize, ahash::RandomState>::with_hasher(s);
+    let mut cluster_names = Vec::<String>::new();
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut cluster_ids = HashMap::<String, usize, ahash::RandomState>::with_hasher(s);
+
+    let file = fs::File::open(fname)?;
+    for (lnum, l) in BufReader::new(file).lines().enumerate() {
+        let l = l?;
+        let mut toks = l.split_whitespace();
+        let (bc, cluster) = match (toks.next(), toks.next()) {
+            (Some(b), Some(c)) => (b, c),
+            (None, _) => continue,
+            (Some(_), None) => {
+                return Err(format!("line {} of {} has no cluster label.", lnum + 1, fname).into());
+            }
+        };
+        if bc.len() != bc_len as usize {
+            return Err(format!(
+                "the barcode {} on line {} of {} does not have length {}.",
+                bc,
+                lnum + 1,
+                fname,
+                bc_len
+            )
+            .into());
+        }
+        let mut bnk = BitNuclKmer::new(bc.as_bytes(), bc_len as u8, false);
+        let (_, k, _) = bnk.next().expect("can't extract kmer");
+        let next_id = cluster_names.len();
+        let cid = *cluster_ids.entry(cluster.to_string()).or_insert(next_id);
+        if cid == next_id {
+            cluster_names.push(cluster.to_string());
+        }
+        bc_to_cluster.insert(k.0, cid);
+    }
+    Ok((cluster_names, bc_to_cluster))
+}
+
+/// The configuration of infer; see [`InferConfig::builder`].
+#[derive(Debug, Clone)]
+pub struct InferConfig {
+    /// the cell by equivalence class count matrix
+    pub count_mat_file: String,
+    /// the gene labels of the equivalence classes
+    pub eq_label_file: String,
+    pub output_dir: String,
+    pub usa_mode: bool,
+    pub use_mtx: bool,
+    pub num_gibbs_samples: u32,
+    pub summary_stat: bool,
+    pub seed: u64,
+    /// the file assigning the cells to clusters, if any
+    pub clusters: Option<String>,
+    pub cluster_prior_strength: f32,
+    pub num_threads: u32,
+    /// the file of the barcodes to quantify, if not all of them
+    pub filter_list: Option<String>,
+    pub version: String,
+}
+
+impl InferConfig {
+    /// A builder of the configuration inferring abundances from the counts
+    /// in `count_mat_file` of the equivalence classes in `eq_label_file`,
+    /// and writing them to `output_dir`; by default, no Gibbs samples are
+    /// drawn, a random seed is chosen, and 1 thread is used.
+    pub fn builder(
+        count_mat_file: &str,
+        eq_label_file: &str,
+        output_dir: &str,
+    ) -> InferConfigBuilder {
+        InferConfigBuilder {
+            config: InferConfig {
+                count_mat_file: count_mat_file.to_string(),
+                eq_label_file: eq_label_file.to_string(),
+                output_dir: output_dir.to_string(),
+                usa_mode: false,
+                use_mtx: false,
+                num_gibbs_samples: 0,
+                summary_stat: false,
+                seed: rand::random(),
+                clusters: None,
+                cluster_prior_strength: DEFAULT_EM_PRIOR_STRENGTH,
+                num_threads: 1,
+                filter_list: None,
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+        }
+    }
+}
+
+pub struct InferConfigBuilder {
+    config: InferConfig,
+}
+
+impl InferConfigBuilder {
+    pub fn usa_mode(mut self, usa_mode: bool) -> Self {
+        self.config.usa_mode = usa_mode;
+        self
+    }
+    pub fn use_mtx(mut self, use_mtx: bool) -> Self {
+        self.config.use_mtx = use_mtx;
+        self
+    }
+    pub fn gibbs_samples(mut self, n: u32) -> Self {
+        self.config.num_gibbs_samples = n;
+        self
+    }
+    pub fn summary_stat(mut self, summary_stat: bool) -> Self {
+        self.config.summary_stat = summary_stat;
+        self
+    }
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = seed;
+        self
+    }
+    pub fn clusters(mut self, clusters: Option<&str>) -> Self {
+        self.config.clusters = clusters.map(String::from);
+        self
+    }
+    pub fn cluster_prior_strength(mut self, strength: f32) -> Self {
+        self.config.cluster_prior_strength = strength;
+        self
+    }
+    pub fn num_threads(mut self, num_threads: u32) -> Self {
+        self.config.num_threads = num_threads;
+        self
+    }
+    pub fn filter_list(mut self, filter_list: Option<&str>) -> Self {
+        self.config.filter_list = filter_list.map(String::from);
+        self
+    }
+    pub fn version(mut self, version: &str) -> Self {
+        self.config.version = version.to_string();
+        self
+    }
+
+    /// Check the configuration, and return it if it is valid.
+    pub fn build(self) -> Result<InferConfig, afutils::ConfigError> {
+        let c = self.config;
+        if c.cluster_prior_strength <= 0.0 {
+            return Err(afutils::ConfigError::invalid(
+                "cluster-prior-strength",
+                "> 0",
+                c.cluster_prior_strength,
+            ));
+        }
+        if c.num_threads < 1 {
+            return Err(afutils::ConfigError::invalid(
+                "threads",
+                ">= 1",
+                c.num_threads,
+            ));
+        }
+        Ok(c)
+    }
+}
+
+/// The statistics of an infer run.
+#[derive(Debug, Clone)]
+pub struct InferStats {
+    pub num_cells: usize,
+    /// the number of columns of the count matrix
+    pub num_features: usize,
+    pub num_eq_classes: usize,
+    /// the seed used by the Gibbs sampler
+    pub seed: u64,
+}
+
+/// Run the EM on the equivalence class `counts` aggregated over the
+/// cells of a cluster, and turn the resulting abundances into the
+/// pseudo-counts of a prior of weight `strength` for the EM of each
+/// of the cluster's cells.
+fn cluster_prior(
+    eq_classes: &IndexedEqList,
+    counts: &HashMap<u32, u32>,
+    num_genes: usize,
+    feature_blocks: Option<FeatureBlocks>,
+    strength: f32,
+    log: &slog::Logger,
+) -> Option<Vec<f32>> {
+    let mut unique_evidence = vec![false; num_genes];
+    let mut no_ambiguity = vec![false; num_genes];
+    let mut cluster_data: Vec<(u32, u32)> = counts.iter().map(|(e, c)| (*e, *c)).collect();
+    cluster_data.sort_unstable();
+    let abund = em_optimize_subset(
+        eq_classes,
+        &cluster_data,
+        &mut unique_evidence,
+        &mut no_ambiguity,
+        EmInitType::Informative,
+        num_genes,
+        false,
+        feature_blocks,
+        None,
+        log,
+    );
+    let abund: Vec<f64> = abund.iter().map(|x| *x as f64).collect();
+    prior_pseudo_counts(&abund, strength)
+}
+
+/// Infer the abundances of the cells from the equivalence class counts
+/// given in `config`, and write them to its `output_dir`.
 pub fn infer(
-    //num_bootstraps,
-    //init_uniform,
-    //summary_stat,
-    count_mat_file: String,
-    eq_label_file: String,
-    usa_mode: bool,
-    _use_mtx: bool,
-    num_threads: u32,
-    filter_list: Option<&str>,
-    output_dir: String,
+    config: InferConfig,
     log: &slog::Logger,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<InferStats, Box<dyn std::error::Error>> {
+    let InferConfig {
+        count_mat_file,
+        eq_label_file,
+        output_dir,
+        usa_mode,
+        use_mtx: _use_mtx,
+        num_gibbs_samples,
+        summary_stat,
+        seed,
+        clusters,
+        cluster_prior_strength,
+        num_threads,
+        filter_list,
+        version,
+    } = config;
+    let clusters = clusters.as_deref();
+    let filter_list = filter_list.as_deref();
+    let version = version.as_str();
     info!(
         log,
         "inferring abundances from equivalence class count input."
     );
+    if num_gibbs_samples > 0 {
+        info!(log, "drawing Gibbs samples with seed {}", seed);
+    }
 
     // get the path for the equivalence class count matrix
     let count_mat_path = std::path::Path::new(&count_mat_file);
@@ -50,6 +282,26 @@ pub fn infer(
         .parent()
         .unwrap_or_else(|| panic!("cannot get parent path of {:?}", count_mat_path));
 
+    // if the equivalence class counts were written by quant, check the version
+    // that wrote them and make sure they haven't changed since
+    let quant_meta_path = count_mat_parent.join("quant.json");
+    if quant_meta_path.exists() {
+        let quant_md = afutils::read_step_meta(&quant_meta_path, "quant", version)?;
+        afutils::check_file_sizes(
+            &quant_md,
+            "output_file_sizes",
+            count_mat_parent,
+            "quant",
+            log,
+        )?;
+    } else {
+        warn!(
+            log,
+            "no quant.json file was found alongside {:?}, so the provenance of the input can't be checked",
+            count_mat_path
+        );
+    }
+
     // read the file and convert it to csr (rows are *cells*)
     let count_mat: sprs::CsMatBase<u32, u32, Vec<u32>, Vec<u32>, Vec<u32>, _> =
         match sprs::io::read_matrix_market::<u32, u32, &std::path::Path>(count_mat_path) {
@@ -64,7 +316,9 @@ pub fn infer(
         log,
         "read {} x {} equivalence class count matrix.",
         count_mat.rows(),
-        count_mat.cols()
+        count_mat.cols();
+        "num_cells" => count_mat.rows(),
+        "num_eq_classes" => count_mat.cols()
     );
 
     let mut num_cells = count_mat.rows();
@@ -78,14 +332,15 @@ pub fn infer(
     info!(
         log,
         "read {} equivalence classes from file.",
-        global_eq_classes.num_eq_classes()
+        global_eq_classes.num_eq_classes();
+        "num_eq_classes" => global_eq_classes.num_eq_classes()
     );
 
     // the number of genes (columns) that the output (gene-level) matrix will have
     let num_genes = global_eq_classes.num_genes;
 
-    let usa_offsets = if usa_mode {
-        Some(((num_genes / 3) as usize, (2 * num_genes / 3) as usize))
+    let feature_blocks = if usa_mode {
+        Some(FeatureBlocks::usa(num_genes))
     } else {
         None
     };
@@ -133,8 +388,80 @@ pub fn infer(
         }
     }
 
+    // if we have a cell to cluster assignment, run the EM on the
+    // equivalence class counts aggregated over the cells of each
+    // cluster, and use the resulting abundances as a prior for the
+    // EM of each of the cluster's cells.
+    let mut cell_clusters: Vec<Option<usize>> = vec![None; bcvec.len()];
+    let mut cluster_priors: Vec<Option<Vec<f32>>> = Vec::new();
+    if let Some(fname) = clusters {
+        let (cluster_names, bc_to_cluster) = read_cluster_assignments(fname, bc_len)?;
+        info!(
+            log,
+            "read the assignment of {} cells to {} clusters.",
+            bc_to_cluster.len(),
+            cluster_names.len();
+            "num_cells" => bc_to_cluster.len(),
+            "num_clusters" => cluster_names.len()
+        );
+
+        let mut cluster_counts: Vec<HashMap<u32, u32>> = vec![HashMap::new(); cluster_names.len()];
+        let mut num_unassigned = 0usize;
+        for ((barcode, row_vec), cell_cluster) in bcvec
+            .iter()
+            .zip(count_mat.outer_iterator())
+            .zip(cell_clusters.iter_mut())
+        {
+            if filter_bc && !retained_bc.contains(barcode) {
+                continue;
+            }
+            match bc_to_cluster.get(barcode) {
+                Some(cid) => {
+                    *cell_cluster = Some(*cid);
+                    for (eq_id, count) in row_vec.iter() {
+                        *cluster_counts[*cid].entry(eq_id as u32).or_insert(0) += *count;
+                    }
+                }
+                None => {
+                    num_unassigned += 1;
+                }
+            }
+        }
+        if num_unassigned > 0 {
+            warn!(
+                log,
+                "{} cells are not assigned to a cluster, and will be quantified without a prior.",
+                num_unassigned
+            );
+        }
+
+        for (cname, counts) in cluster_names.iter().zip(cluster_counts.iter()) {
+            let prior = cluster_prior(
+                &global_eq_classes,
+                counts,
+                num_genes,
+                feature_blocks,
+                cluster_prior_strength,
+                log,
+            );
+            if prior.is_none() {
+                warn!(
+                    log,
+                    "cluster {} has no counts, its cells will be quantified without a prior.",
+                    cname
+                );
+            }
+            cluster_priors.push(prior);
+        }
+        info!(
+            log,
+            "computed cluster-level priors with strength {}.", cluster_prior_strength
+        );
+    }
+    let cluster_priors = Arc::new(cluster_priors);
+
     // the progress bar we'll use to monitor progress of the EM
-    let pbar = ProgressBar::new(num_cells as u64);
+    let pbar = logging::progress_bar(num_cells as u64, "inferring cells", log);
     pbar.set_style(
         ProgressStyle::default_bar()
             .template(
@@ -151,8 +478,11 @@ pub fn infer(
     };
     // the queue will hold tuples of the
     // cell id (so that the output matrix is in the same order as input)
+    // the cluster of the cell (if any) and the
     // vector of eq_id and count for each cell
-    let q = Arc::new(ArrayQueue::<(usize, Vec<(u32, u32)>)>::new(4 * n_workers));
+    let q = Arc::new(ArrayQueue::<(usize, Option<usize>, Vec<(u32, u32)>)>::new(
+        4 * n_workers,
+    ));
 
     let mut thread_handles: Vec<thread::JoinHandle<_>> = Vec::with_capacity(n_workers);
 
@@ -163,6 +493,23 @@ pub fn infer(
         (num_cells, num_genes),
         count_mat.nnz(),
     )));
+    // the Gibbs samples (stacked, num_gibbs_samples rows per cell) or
+    // the mean and variance of the samples, if they were requested.
+    let num_gibbs_rows = if summary_stat {
+        num_cells
+    } else {
+        num_cells * num_gibbs_samples as usize
+    };
+    let num_gibbs_mats = match (num_gibbs_samples, summary_stat) {
+        (0, _) => 0,
+        (_, true) => 2,
+        (_, false) => 1,
+    };
+    let gibbs_mats = Arc::new(Mutex::new(
+        (0..num_gibbs_mats)
+            .map(|_| TriMatI::<f32, u32>::new((num_gibbs_rows, num_genes)))
+            .collect::<Vec<TriMatI<f32, u32>>>(),
+    ));
 
     // for each worker, spawn off a thread
     for _worker in 0..n_workers {
@@ -174,8 +521,11 @@ pub fn infer(
         let cells_remaining = cells_to_process.clone();
         // and the output matrix
         let matout = trimat.clone();
+        let gibbs_out = gibbs_mats.clone();
         // and the global set of eq class labels
         let global_eq_classes = global_eq_classes.clone();
+        // and the cluster-level priors
+        let cluster_priors = cluster_priors.clone();
 
         //let unmapped_count = bc_unmapped_map.clone();
         //let mmrate = mmrate.clone();
@@ -197,9 +547,12 @@ pub fn infer(
             // pop from the work queue until everything is
             // processed
             while cells_remaining.load(Ordering::SeqCst) > 0 {
-                if let Some((cell_num, cell_data)) = in_q.pop() {
+                if let Some((cell_num, cell_cluster, cell_data)) = in_q.pop() {
                     cells_remaining.fetch_sub(1, Ordering::SeqCst);
 
+                    // the prior of the cell's cluster, if any
+                    let prior = cell_cluster.and_then(|c| cluster_priors[c].as_deref());
+
                     // given the set of equivalence classes and counts for
                     // this cell (coming from the input matrix), perform
                     // inference to obtain gene-level counts.
@@ -211,10 +564,41 @@ pub fn infer(
                         EmInitType::Informative,
                         num_genes,
                         false,
-                        usa_offsets,
+                        feature_blocks,
+                        prior,
                         &log,
                     );
 
+                    if num_gibbs_samples > 0 {
+                        let samples = run_gibbs_subset(
+                            &global_eq_classes,
+                            &cell_data,
+                            num_genes as u32,
+                            num_gibbs_samples,
+                            &counts,
+                            feature_blocks,
+                            summary_stat,
+                            cell_seed(seed, cell_num as u64),
+                            prior,
+                            &log,
+                        );
+                        let mut mats = gibbs_out.lock().unwrap();
+                        for (si, sample) in samples.iter().enumerate() {
+                            // with summary statistics, the mean and variance
+                            // go to their own matrices
+                            let (mi, row) = if summary_stat {
+                                (si, cell_num)
+                            } else {
+                                (0, cell_num * num_gibbs_samples as usize + si)
+                            };
+                            for (gn, c) in sample.iter().enumerate() {
+                                if *c > 0.0 {
+                                    mats[mi].add_triplet(row, gn, *c);
+                                }
+                            }
+                        }
+                    }
+
                     // Note: there is a fill method, but it is only on
                     // the nightly branch.  Use this for now:
                     unique_evidence.clear();
@@ -337,7 +721,11 @@ pub fn infer(
     // an iterator over the row data.
     // we zip the bcvec iterator (vector of barcodes in row order)
     // with the actual rows of the matrix.
-    for (barcode, row_vec) in bcvec.iter().zip(count_mat.outer_iterator()) {
+    for ((barcode, row_vec), cell_cluster) in bcvec
+        .iter()
+        .zip(count_mat.outer_iterator())
+        .zip(cell_clusters.iter())
+    {
         let process_cell = if filter_bc {
             // if the reatined_bc list contains this cell id
             // then process it
@@ -358,7 +746,7 @@ pub fn infer(
             let cell_data: Vec<(u32, u32)> = row_vec.iter().map(|e| (e.0 as u32, *e.1)).collect();
             // keep pushing this data onto our work queue while we can.
             // launch off these cells on the queue
-            let mut cd_clone = (processed_ind, cell_data.clone());
+            let mut cd_clone = (processed_ind, *cell_cluster, cell_data.clone());
             // keep trying until we can push this payload
             while let Err(t) = q.push(cd_clone) {
                 cd_clone = t;
@@ -389,5 +777,94 @@ pub fn infer(
     let writer = &*writer_deref.unwrap();
     sprs::io::write_matrix_market(&output_matrix_path, writer)?;
 
-    Ok(())
+    // and the Gibbs samples, named as the bootstrap output of quant
+    let gibbs_mats = gibbs_mats.lock().unwrap();
+    if summary_stat && num_gibbs_samples > 0 {
+        sprs::io::write_matrix_market(output_path.join("bootstraps_mean.mtx"), &gibbs_mats[0])?;
+        sprs::io::write_matrix_market(output_path.join("bootstraps_var.mtx"), &gibbs_mats[1])?;
+    } else if num_gibbs_samples > 0 {
+        sprs::io::write_matrix_market(output_path.join("bootstraps.mtx"), &gibbs_mats[0])?;
+    }
+
+    Ok(InferStats {
+        num_cells,
+        num_features: num_genes,
+        num_eq_classes: global_eq_classes.num_eq_classes(),
+        seed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infer_builder_rejects_invalid_values() {
+        let builder = || InferConfig::builder("counts.mtx", "eq_labels.txt", "out");
+        assert!(builder().build().is_ok());
+        assert!(matches!(
+            builder().num_threads(0).build(),
+            Err(afutils::ConfigError::InvalidValue {
+                name: "threads",
+                ..
+            })
+        ));
+        assert!(matches!(
+            builder().cluster_prior_strength(0.0).build(),
+            Err(afutils::ConfigError::InvalidValue {
+                name: "cluster-prior-strength",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn cluster_membership_changes_inferred_counts() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut eqc = HashMap::with_hasher(ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64));
+        eqc.insert(vec![0u32], 0u32);
+        eqc.insert(vec![1u32], 1u32);
+        eqc.insert(vec![0u32, 1u32], 2u32);
+        let eql = IndexedEqList::init_from_hash(&eqc, 2);
+        let eq_id = |labels: &[u32]| {
+            (0..eql.num_eq_classes() as u32)
+                .find(|i| eql.refs_for_eqc(*i) == labels)
+                .unwrap()
+        };
+
+        // the cells of cluster a mostly express gene 0, and those of b gene 1
+        let cluster_a: HashMap<u32, u32> =
+            vec![(eq_id(&[0]), 90), (eq_id(&[1]), 10), (eq_id(&[0, 1]), 50)]
+                .into_iter()
+                .collect();
+        let cluster_b: HashMap<u32, u32> =
+            vec![(eq_id(&[0]), 10), (eq_id(&[1]), 90), (eq_id(&[0, 1]), 50)]
+                .into_iter()
+                .collect();
+        let prior_a =
+            cluster_prior(&eql, &cluster_a, 2, None, DEFAULT_EM_PRIOR_STRENGTH, &log).unwrap();
+        let prior_b =
+            cluster_prior(&eql, &cluster_b, 2, None, DEFAULT_EM_PRIOR_STRENGTH, &log).unwrap();
+
+        // a cell with only ambiguous molecules
+        let cell_data = vec![(eq_id(&[0, 1]), 10)];
+        let infer_cell = |prior: &[f32]| {
+            em_optimize_subset(
+                &eql,
+                &cell_data,
+                &mut vec![false; 2],
+                &mut vec![true; 2],
+                EmInitType::Informative,
+                2,
+                false,
+                None,
+                Some(prior),
+                &log,
+            )
+        };
+        let in_a = infer_cell(&prior_a);
+        let in_b = infer_cell(&prior_b);
+        assert!(in_a[0] > 8.0 && in_b[1] > 8.0, "{:?} {:?}", in_a, in_b);
+        assert!((in_a[0] + in_a[1] - 10.0).abs() < 1e-3);
+    }
 }
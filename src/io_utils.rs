@@ -5,11 +5,126 @@ use scroll::Pwrite;
 
 use libradicl::rad_types;
 use std::collections::HashSet;
-use std::io::Read;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::PathBuf;
 use std::sync::Arc;
 
 pub(crate) type MetaChunk = (usize, usize, u32, u32, Vec<u8>);
 
+/// The header, tag descriptions and file-level tag values at the
+/// start of a RAD file, before its first chunk.
+pub(crate) struct RadPrelude {
+    pub hdr: rad_types::RadHeader,
+    pub fl_tags: rad_types::TagSection,
+    pub rl_tags: rad_types::TagSection,
+    pub al_tags: rad_types::TagSection,
+    pub ft_vals: rad_types::FileTags,
+}
+
+impl RadPrelude {
+    /// Read the prelude of a RAD file from `br`, leaving `br`
+    /// at the start of the first chunk.
+    pub fn from_bytes<T: Read>(br: &mut T) -> Self {
+        let hdr = rad_types::RadHeader::from_bytes(br);
+        let fl_tags = rad_types::TagSection::from_bytes(br);
+        let rl_tags = rad_types::TagSection::from_bytes(br);
+        let al_tags = rad_types::TagSection::from_bytes(br);
+        let ft_vals = rad_types::FileTags::from_bytes(br);
+        Self {
+            hdr,
+            fl_tags,
+            rl_tags,
+            al_tags,
+            ft_vals,
+        }
+    }
+
+    /// Check that the records of a RAD file with the prelude `other` can be
+    /// processed along with those of this one; i.e. that both were mapped
+    /// against the same references, and have the same tags.
+    pub fn check_compatible(&self, other: &RadPrelude) -> Result<(), String> {
+        fn same_tags(a: &rad_types::TagSection, b: &rad_types::TagSection) -> bool {
+            a.tags.len() == b.tags.len()
+                && a.tags
+                    .iter()
+                    .zip(b.tags.iter())
+                    .all(|(x, y)| x.name == y.name && x.typeid == y.typeid)
+        }
+
+        if self.hdr.is_paired != other.hdr.is_paired {
+            return Err("one is paired-end and the other is not".to_string());
+        }
+        if self.hdr.ref_names != other.hdr.ref_names {
+            return Err("their reference names differ".to_string());
+        }
+        if !same_tags(&self.fl_tags, &other.fl_tags)
+            || !same_tags(&self.rl_tags, &other.rl_tags)
+            || !same_tags(&self.al_tags, &other.al_tags)
+        {
+            return Err("their tag descriptions differ".to_string());
+        }
+        if self.ft_vals.bclen != other.ft_vals.bclen || self.ft_vals.umilen != other.ft_vals.umilen
+        {
+            return Err(format!(
+                "their barcode / UMI lengths differ ({} / {} vs. {} / {})",
+                self.ft_vals.bclen, self.ft_vals.umilen, other.ft_vals.bclen, other.ft_vals.umilen
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The paths of the `map.rad` files in the directories `rad_dirs`.
+pub(crate) fn rad_file_paths(rad_dirs: &[String]) -> Vec<PathBuf> {
+    rad_dirs
+        .iter()
+        .map(|d| std::path::Path::new(d).join("map.rad"))
+        .collect()
+}
+
+/// The absolute paths of the RAD files `rad_files`, under which their
+/// sizes are recorded (so that they don't depend on the working directory).
+pub(crate) fn rad_file_keys(rad_files: &[PathBuf]) -> Vec<String> {
+    rad_files
+        .iter()
+        .map(|p| {
+            std::fs::canonicalize(p)
+                .unwrap_or_else(|_| p.clone())
+                .display()
+                .to_string()
+        })
+        .collect()
+}
+
+/// Append to `first` (the reader of the first RAD input, positioned at its
+/// first chunk, with the prelude `prelude`) the chunks of the RAD files
+/// `rest`, after checking that each of them is compatible with the first.
+/// Returns the combined reader, from which the chunks of all the inputs can
+/// be read in order, along with the total number of chunks.
+pub(crate) fn chain_rad_inputs<'a, T: Read + 'a>(
+    first: T,
+    prelude: &RadPrelude,
+    rest: &[PathBuf],
+) -> Result<(Box<dyn Read + 'a>, u64), Box<dyn std::error::Error>> {
+    let mut num_chunks = prelude.hdr.num_chunks;
+    let mut br: Box<dyn Read + 'a> = Box::new(first);
+    for p in rest {
+        let mut nbr = BufReader::new(File::open(p)?);
+        let nprelude = RadPrelude::from_bytes(&mut nbr);
+        prelude.check_compatible(&nprelude).map_err(|e| {
+            format!(
+                "the RAD file {} can't be processed along with the first input: {}",
+                p.display(),
+                e
+            )
+        })?;
+        num_chunks += nprelude.hdr.num_chunks;
+        br = Box::new(br.chain(nbr));
+    }
+    Ok((br, num_chunks))
+}
+
 pub(crate) fn fill_work_queue<T: Read>(
     q: Arc<ArrayQueue<MetaChunk>>,
     mut br: T,
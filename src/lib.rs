@@ -8,13 +8,19 @@
  */
 
 pub mod cellfilter;
+pub mod check_t2g;
+pub mod chemistry;
 pub mod collate;
 pub mod constants;
 pub mod convert;
+pub mod doublets;
+pub mod dump_permit;
 pub mod em;
 pub mod eq_class;
 pub mod infer;
 pub mod io_utils;
+pub mod logging;
 pub mod pugutils;
 pub mod quant;
+pub mod report;
 pub mod utils;
@@ -0,0 +1,222 @@
+/*
+ * Copyright (c) 2020-2022 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+use indicatif::{ProgressBar, ProgressDrawTarget};
+use slog::{info, o, Drain};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// The format of the log records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// human readable records on the terminal, with progress bars
+    Text,
+    /// one JSON object per record, with progress reported as
+    /// periodic JSON events rather than progress bars
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("{} is not a valid log format (text or json)", s)),
+        }
+    }
+}
+
+// set when the records are written as JSON, so that progress
+// bars are replaced by JSON progress events
+static JSON_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+// how often progress events are written
+const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_secs(5);
+
+// the threads writing progress events, and the flag telling them to stop
+static PROGRESS_THREADS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+static PROGRESS_STOP: AtomicBool = AtomicBool::new(false);
+
+/// Collects the key-value pairs of a record into a JSON object, keeping
+/// numbers and booleans as such.
+struct JsonSerializer {
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl JsonSerializer {
+    fn insert<V: Into<serde_json::Value>>(&mut self, key: slog::Key, val: V) -> slog::Result {
+        self.fields.insert(key.to_string(), val.into());
+        Ok(())
+    }
+}
+
+impl slog::Serializer for JsonSerializer {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        self.insert(key, val.to_string())
+    }
+    fn emit_str(&mut self, key: slog::Key, val: &str) -> slog::Result {
+        self.insert(key, val)
+    }
+    fn emit_bool(&mut self, key: slog::Key, val: bool) -> slog::Result {
+        self.insert(key, val)
+    }
+    fn emit_u32(&mut self, key: slog::Key, val: u32) -> slog::Result {
+        self.insert(key, val)
+    }
+    fn emit_i32(&mut self, key: slog::Key, val: i32) -> slog::Result {
+        self.insert(key, val)
+    }
+    fn emit_u64(&mut self, key: slog::Key, val: u64) -> slog::Result {
+        self.insert(key, val)
+    }
+    fn emit_i64(&mut self, key: slog::Key, val: i64) -> slog::Result {
+        self.insert(key, val)
+    }
+    fn emit_usize(&mut self, key: slog::Key, val: usize) -> slog::Result {
+        self.insert(key, val)
+    }
+    fn emit_f64(&mut self, key: slog::Key, val: f64) -> slog::Result {
+        self.insert(key, val)
+    }
+}
+
+/// A drain writing each record, as a JSON object on its own line, to `out`.
+struct JsonDrain {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Drain for JsonDrain {
+    type Ok = ();
+    type Err = std::io::Error;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        let mut ser = JsonSerializer {
+            fields: serde_json::Map::new(),
+        };
+        ser.fields
+            .insert("ts".to_string(), chrono::Local::now().to_rfc3339().into());
+        ser.fields
+            .insert("level".to_string(), record.level().as_str().into());
+        ser.fields
+            .insert("msg".to_string(), record.msg().to_string().into());
+        // the values of the logger (e.g. the stage) and then those of the record
+        slog::KV::serialize(values, record, &mut ser).map_err(std::io::Error::from)?;
+        slog::KV::serialize(&record.kv(), record, &mut ser).map_err(std::io::Error::from)?;
+
+        let mut out = self.out.lock().unwrap();
+        serde_json::to_writer(&mut *out, &serde_json::Value::Object(ser.fields))?;
+        writeln!(out)?;
+        out.flush()
+    }
+}
+
+/// The root logger writing human readable records with `decorator`.
+fn text_logger<D: slog_term::Decorator + Send + 'static>(decorator: D) -> slog::Logger {
+    let drain = slog_term::CompactFormat::new(decorator)
+        .use_custom_timestamp(|out: &mut dyn std::io::Write| {
+            write!(out, "{}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S")).unwrap();
+            Ok(())
+        })
+        .build()
+        .fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    slog::Logger::root(drain, o!())
+}
+
+/// Build the root logger writing records in the format `format`, to
+/// `log_file` if it is given, or otherwise to the terminal (text) or
+/// stderr (JSON).
+pub fn build_logger(
+    format: LogFormat,
+    log_file: Option<&str>,
+) -> Result<slog::Logger, Box<dyn std::error::Error>> {
+    let log = match format {
+        LogFormat::Text => match log_file {
+            Some(f) => {
+                let decorator = slog_term::PlainDecorator::new(std::fs::File::create(f)?);
+                text_logger(decorator)
+            }
+            None => text_logger(slog_term::TermDecorator::new().build()),
+        },
+        LogFormat::Json => {
+            let out: Box<dyn Write + Send> = match log_file {
+                Some(f) => Box::new(std::io::BufWriter::new(std::fs::File::create(f)?)),
+                None => Box::new(std::io::stderr()),
+            };
+            let drain = JsonDrain {
+                out: Mutex::new(out),
+            }
+            .fuse();
+            let drain = slog_async::Async::new(drain).build().fuse();
+            JSON_PROGRESS.store(true, Ordering::SeqCst);
+            slog::Logger::root(drain, o!())
+        }
+    };
+    Ok(log)
+}
+
+/// Create a progress bar of length `len` for the task `task`. When logging
+/// JSON records, the bar is hidden, and its progress is instead written
+/// to `log` as periodic `progress` events, and once it is finished (or
+/// when [`finish_progress`] is called).
+pub fn progress_bar(len: u64, task: &'static str, log: &slog::Logger) -> ProgressBar {
+    if !JSON_PROGRESS.load(Ordering::SeqCst) {
+        return ProgressBar::new(len);
+    }
+
+    let pbar = ProgressBar::with_draw_target(len, ProgressDrawTarget::hidden());
+    let pb = pbar.clone();
+    let log = log.clone();
+    let handle = std::thread::spawn(move || {
+        let mut last_event = Instant::now();
+        loop {
+            std::thread::sleep(Duration::from_millis(200));
+            let finished = pb.is_finished();
+            let stopping = PROGRESS_STOP.load(Ordering::SeqCst);
+            if finished || stopping || last_event.elapsed() >= PROGRESS_EVENT_INTERVAL {
+                info!(log, "progress";
+                    "event" => "progress",
+                    "task" => task,
+                    "done" => pb.position(),
+                    "total" => pb.length(),
+                    "elapsed_secs" => pb.elapsed().as_secs_f64(),
+                    "finished" => finished
+                );
+                last_event = Instant::now();
+            }
+            if finished || stopping {
+                break;
+            }
+        }
+    });
+    PROGRESS_THREADS.lock().unwrap().push(handle);
+    pbar
+}
+
+/// Write the last progress events, and wait for the threads writing
+/// them to exit; this must be called before the logger is dropped, so
+/// that none of the events are lost.
+pub fn finish_progress() {
+    PROGRESS_STOP.store(true, Ordering::SeqCst);
+    let handles: Vec<JoinHandle<()>> = PROGRESS_THREADS.lock().unwrap().drain(..).collect();
+    for h in handles {
+        let _ = h.join();
+    }
+}
@@ -8,16 +8,31 @@
  */
 
 use bio_types::strand::Strand;
-use clap::{arg, crate_authors, crate_version, Command};
+use clap::{arg, crate_authors, crate_version, ArgMatches, Command};
 use csv::Error as CSVError;
 use csv::ErrorKind;
 use itertools::Itertools;
 use mimalloc::MiMalloc;
 use rand::Rng;
-use slog::{crit, o, warn, Drain};
-
-use alevin_fry::cellfilter::{generate_permit_list, CellFilterMethod};
-use alevin_fry::quant::{ResolutionStrategy, SplicedAmbiguityModel};
+use slog::{crit, info, o, warn};
+
+use alevin_fry::cellfilter::{
+    generate_permit_list, BarcodeCorrectionParams, CellFilterMethod, PermitListConfig,
+    DEFAULT_MIN_CORRECTION_POSTERIOR, DEFAULT_MIN_READS,
+};
+use alevin_fry::chemistry::Chemistry;
+use alevin_fry::collate::{CollateConfig, DEFAULT_MAX_RECORDS};
+use alevin_fry::doublets::DoubletParams;
+use alevin_fry::dump_permit::DumpFormat;
+use alevin_fry::em::{EmPrior, EmPriorSource, DEFAULT_EM_PRIOR_STRENGTH};
+use alevin_fry::infer::InferConfig;
+use alevin_fry::logging::{self, LogFormat};
+use alevin_fry::pugutils::{
+    PugGraphParams, DEFAULT_MAX_UMI_DIST, DEFAULT_PUG_COUNT_RATIO, DEFAULT_UMI_COUNT_RATIO,
+};
+use alevin_fry::quant::{
+    QcGeneSets, QuantConfig, ResolutionStrategy, SplicedAmbiguityModel, DEFAULT_SMALL_THRESH,
+};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -42,13 +57,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let num_hardware_threads = num_cpus::get() as u32;
     let max_num_threads: String = (num_cpus::get() as u32).to_string();
     let max_num_collate_threads: String = (16_u32.min(num_hardware_threads).max(2_u32)).to_string();
+    // reading the RAD file soon limits generate-permit-list, so only a few threads are used by default
+    let max_num_gpl_threads: String = (4_u32.min(num_hardware_threads).max(1_u32)).to_string();
+    let default_prior_strength: String = DEFAULT_EM_PRIOR_STRENGTH.to_string();
+    let default_min_reads: String = DEFAULT_MIN_READS.to_string();
+    let default_min_posterior: String = DEFAULT_MIN_CORRECTION_POSTERIOR.to_string();
+    let default_max_records: String = DEFAULT_MAX_RECORDS.to_string();
+    let default_umi_dist: String = DEFAULT_MAX_UMI_DIST.to_string();
+    let default_count_ratio: String = DEFAULT_PUG_COUNT_RATIO.to_string();
+    let default_umi_count_ratio: String = DEFAULT_UMI_COUNT_RATIO.to_string();
+    let default_small_thresh: String = DEFAULT_SMALL_THRESH.to_string();
+    let doublet_defaults = DoubletParams::default();
+    let default_sim_ratio: String = doublet_defaults.sim_ratio.to_string();
+    let default_expected_rate: String = doublet_defaults.expected_rate.to_string();
+    let default_num_hvg: String = doublet_defaults.num_hvg.to_string();
+    let default_num_pcs: String = doublet_defaults.num_pcs.to_string();
 
     let crate_authors = crate_authors!("\n");
     let version = crate_version!();
 
-    // capture the entire command line as a string
-    let cmdline = std::env::args().join(" ");
-
     let convert_app = Command::new("convert")
         .about("Convert a BAM file to a RAD file")
         .version(version)
@@ -72,13 +99,48 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .arg(arg!(-o --output <RADFILE> "output plain-text-file file").required(false));
 
+    let check_t2g_app = Command::new("check-t2g")
+        .about("Check a transcript-to-gene map against the reference sequences of a RAD file")
+        .version(version)
+        .author(crate_authors)
+        .arg(arg!(-r --rad <RADFILE> "input RAD file (e.g. map.rad or map.collated.rad)"))
+        .arg(arg!(-m --"tg-map" <TGMAP> "transcript to gene map (2 or 3 column tsv file, or GTF / GFF3 annotation)"))
+        .arg(arg!(-o --output <REPORT> "file to which the full report will be written as JSON").required(false));
+
+    let dump_permit_app = Command::new("dump-permit")
+        .about("Decode the permit list and barcode correction map written by generate-permit-list to TSV or JSON")
+        .version(version)
+        .author(crate_authors)
+        .arg(arg!(-i --"input-dir" <INPUTDIR> "output directory of generate-permit-list"))
+        .arg(arg!(-o --"output-dir" <OUTPUTDIR> "output directory where the decoded files will be written"))
+        .arg(
+            arg!(-f --format <FORMAT> "format of the decoded files")
+                .possible_values(["tsv", "json"])
+                .ignore_case(true)
+                .default_value("tsv"),
+        );
+
+    let report_app = Command::new("report")
+        .about("Write an HTML report on the results of generate-permit-list, collate and quant")
+        .version(version)
+        .author(crate_authors)
+        .arg(arg!(-p --"permit-dir" <PERMITDIR> "output directory of generate-permit-list and collate").required_unless_present("quant-dir"))
+        .arg(arg!(-q --"quant-dir" <QUANTDIR> "output directory of quant").required(false))
+        .arg(arg!(-o --output <OUTPUT> "the HTML file to which the report will be written"));
+
     let gen_app = Command::new("generate-permit-list")
         .about("Generate a permit list of barcodes from a RAD file")
         .version(version)
         .author(crate_authors)
-        .arg(arg!(-i --input <INPUT>  "input directory containing the map.rad RAD file"))
-        .arg(arg!(-d --"expected-ori" <EXPECTEDORI> "the expected orientation of alignments"))
+        .arg(arg!(-i --input <INPUT>  "input directory containing the map.rad RAD file; may be given several times (e.g. once per lane) to combine the records of several RAD files").multiple_occurrences(true))
+        .arg(arg!(-d --"expected-ori" <EXPECTEDORI> "the expected orientation of alignments (fw, rc, both or auto, to infer it from the reads); if not given, the orientation of the --chemistry is used")
+            .required_unless_present("chemistry"))
+        .arg(arg!(-c --chemistry <CHEMISTRY> "the single-cell protocol; the barcode and UMI lengths of the RAD file are checked against it")
+            .possible_values(Chemistry::names())
+            .ignore_case(true)
+            .required(false))
         .arg(arg!(-o --"output-dir" <OUTPUTDIR>  "output directory"))
+        .arg(arg!(-t --threads <THREADS> "number of threads used to count the barcodes (by default, up to 4)").default_value(&max_num_gpl_threads))
         .arg(arg!(
             -k --"knee-distance"  "attempt to determine the number of barcodes to keep using the knee distance method."
             ).conflicts_with_all(&["force-cell", "valid-bc", "expect-cells", "unfiltered-pl"])
@@ -94,15 +156,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .conflicts_with_all(&["force-cells", "expect-cells", "knee-distance", "unfiltered-pl"]),
         )
         .arg(
-            arg!(-u --"unfiltered-pl" <UNFILTEREDPL> "uses an unfiltered external permit list")
+            arg!(-u --"unfiltered-pl" <UNFILTEREDPL> "uses an unfiltered external permit list; if no other method is given, the cached permit list of the --chemistry is used, if present")
             .conflicts_with_all(&["force-cells", "expect-cells", "knee-distance", "valid-bc"])
             .requires("min-reads")
         )
         .arg(
             arg!(-m --"min-reads" <MINREADS> "minimum read count threshold; only used with --unfiltered-pl")
-                .default_value("10")
+                .default_value(&default_min_reads)
                 .takes_value(true)
-                .required(true));
+                .required(true))
+        .arg(
+            arg!(--"correct-indels" "also correct barcodes that are a single insertion or deletion away from a retained barcode; only used with --unfiltered-pl, as the other permit lists always correct them")
+            .takes_value(false)
+            .required(false)
+            .requires("unfiltered-pl"))
+        .arg(
+            arg!(--"min-correction-posterior" <POSTERIOR> "when a barcode has several candidate corrections, correct it to the most likely one if its posterior probability (given the candidates' abundances) is at least this large; 1.0 never resolves such barcodes")
+            .default_value(&default_min_posterior)
+            .required(false))
+        .arg(
+            arg!(--"write-corrections" "write barcode_corrections.tsv, listing each observed barcode, the barcode it was corrected to, its read count and the outcome of its correction, and ambiguous_barcodes.tsv, listing the candidate corrections of each ambiguous barcode")
+            .takes_value(false)
+            .required(false));
     //.arg(Arg::from("-v --velocity-mode 'flag for velocity mode'").takes_value(false).required(false));
 
     let collate_app = Command::new("collate")
@@ -110,11 +185,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     .version(version)
     .author(crate_authors)
     .arg(arg!(-i --"input-dir" <INPUTDIR> "input directory made by generate-permit-list"))
-    .arg(arg!(-r --"rad-dir" <RADFILE> "the directory containing the RAD file to be collated"))
+    .arg(arg!(-r --"rad-dir" <RADFILE> "the directory containing the RAD file to be collated; may be given several times, in which case it should match the --input of generate-permit-list").multiple_occurrences(true))
     .arg(arg!(-t --threads <THREADS> "number of threads to use for processing").default_value(&max_num_collate_threads))
     .arg(arg!(-c --compress "compress the output collated RAD file").takes_value(false).required(false))
     .arg(arg!(-m --"max-records" <MAXRECORDS> "the maximum number of read records to keep in memory at once")
-         .default_value("30000000"));
+         .default_value(&default_max_records))
+    .arg(arg!(--"max-memory" <MAXMEMORY> "the memory budget (e.g. 16G) from which the number of read records to keep in memory at once is derived; overrides --max-records")
+         .required(false));
     //.arg(arg!(-e --expected-ori=[expected-ori] 'the expected orientation of alignments'")
     //     .default_value(fw"));
 
@@ -123,23 +200,36 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     .version(version)
     .author(crate_authors)
     .arg(arg!(-i --"input-dir" <INPUTDIR>  "input directory containing collated RAD file"))
-    .arg(arg!(-m --"tg-map" <TGMAP>  "transcript to gene map"))
+    .arg(arg!(-m --"tg-map" <TGMAP>  "transcript to gene map; either a 2 or 3 column tsv file, a 4 column tsv file whose last column gives the category of each feature (e.g. exonic, intronic, mito), or a GTF / GFF3 annotation (optionally gzipped) from which the gene names and types are also recorded"))
     .arg(arg!(-o --"output-dir" <OUTPUTDIR> "output directory where quantification results will be written"))
     .arg(arg!(-t --threads <THREADS> "number of threads to use for processing").default_value(&max_num_threads))
     .arg(arg!(-d --"dump-eqclasses" "flag for dumping equivalence classes").takes_value(false).required(false))
     .arg(arg!(-b --"num-bootstraps" <NUMBOOTSTRAPS> "number of bootstraps to use").default_value("0"))
+    .arg(arg!(--"num-gibbs-samples" <NUMGIBBS> "number of posterior samples to draw with the Gibbs sampler, as an alternative to bootstrapping; written to the same output files as bootstraps").default_value("0"))
     .arg(arg!(--"init-uniform" "flag for uniform sampling").requires("num-bootstraps").takes_value(false).required(false))
+    .arg(arg!(--seed <SEED> "seed for the random number generators used by bootstrapping, Gibbs sampling and random initialization; a random seed is chosen (and recorded in quant.json) if not provided").required(false))
     .arg(arg!(--"summary-stat" "flag for storing only summary statistics").requires("num-bootstraps").takes_value(false).required(false))
+    .arg(arg!(--"em-prior" <PRIOR> "use a Dirichlet prior in the per-cell EM (cr-like-em and parsimony-em resolution); either `pseudo-bulk`, to use the gene-unique read counts over all cells, or a file with a feature name (as in quants_mat_cols.txt) and abundance on each line").required(false))
+    .arg(arg!(--"em-prior-strength" <STRENGTH> "the weight of the EM prior; the pseudo-counts it contributes to each cell sum to this fraction of the cell's number of UMIs").requires("em-prior").default_value(&default_prior_strength))
     .arg(arg!(--"use-mtx" "flag for writing output matrix in matrix market instead of EDS").takes_value(false).required(false))
     .arg(arg!(--"quant-subset" <SFILE> "file containing list of barcodes to quantify, those not in this list will be ignored").required(false))
     .arg(arg!(-r --resolution <RESOLUTION> "the resolution strategy by which molecules will be counted")
-        .possible_values(&["full", "trivial", "cr-like", "cr-like-em", "parsimony", "parsimony-em"])
+        .possible_values(&["full", "trivial", "cr-like", "cr-like-em", "parsimony", "parsimony-em", "cluster", "adjacency", "directional"])
         .ignore_case(true))
+    .arg(arg!(--"umi-edit-dist" <DIST> "maximum distance between UMIs that are connected when building the parsimonious UMI graph (parsimony and full resolution) or the UMI network (cluster, adjacency and directional resolution)")
+        .default_value(&default_umi_dist))
+    .arg(arg!(--"umi-indels" "when building the parsimonious UMI graph or the UMI network, measure UMI distance by edit distance (allowing insertions and deletions) rather than Hamming distance").takes_value(false).required(false))
+    .arg(arg!(--"pug-count-ratio" <RATIO> "count ratio threshold when building the parsimonious UMI graph; the edge from UMI a to its neighbor b is unidirectional if count(a) > RATIO * count(b) - 1")
+        .default_value(&default_count_ratio))
+    .arg(arg!(--"umi-count-ratio" <RATIO> "count ratio threshold for the directional resolution strategy; there is an edge from UMI a to its neighbor b if count(a) >= RATIO * count(b) - 1")
+        .default_value(&default_umi_count_ratio))
+    .arg(arg!(--"mito-genes" <GENES> "mitochondrial genes, whose fraction of each cell's UMIs is reported in featureDump.txt; either a file with a gene name or ID on each line, or a regular expression matching them (e.g. '^(MT|mt)-')").required(false))
+    .arg(arg!(--"ribo-genes" <GENES> "ribosomal genes, whose fraction of each cell's UMIs is reported in featureDump.txt; either a file with a gene name or ID on each line, or a regular expression matching them (e.g. '^(RP[SL]|Rp[sl])')").required(false))
     .arg(arg!(--"sa-model" "preferred model of splicing ambiguity")
         .possible_values(&["prefer-ambig", "winner-take-all"])
         .default_value("winner-take-all")
         .hide(true))
-    .arg(arg!(--"small-thresh" <SMALLTHRESH> "cells with fewer than these many reads will be resolved using a custom approach").default_value("10")
+    .arg(arg!(--"small-thresh" <SMALLTHRESH> "cells with fewer than these many reads will be resolved using a custom approach").default_value(&default_small_thresh)
         .hide(true));
 
     let infer_app = Command::new("infer")
@@ -153,7 +243,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     .arg(arg!(-t --threads <THREADS> "number of threads to use for processing").default_value(&max_num_threads))
     .arg(arg!(--usa "flag specifying that input equivalence classes were computed in USA mode").takes_value(false).required(false))
     .arg(arg!(--"quant-subset" <SFILE> "file containing list of barcodes to quantify, those not in this list will be ignored").required(false))
-    .arg(arg!(--"use-mtx" "flag for writing output matrix in matrix market instead of EDS").takes_value(false).required(false));
+    .arg(arg!(--"use-mtx" "flag for writing output matrix in matrix market instead of EDS").takes_value(false).required(false))
+    .arg(arg!(--"num-gibbs-samples" <NUMGIBBS> "number of posterior samples to draw with the Gibbs sampler for each cell").default_value("0"))
+    .arg(arg!(--"summary-stat" "flag for storing only the mean and variance of the Gibbs samples").takes_value(false).required(false))
+    .arg(arg!(--seed <SEED> "seed for the random number generator used by Gibbs sampling; a random seed is chosen if not provided").required(false))
+    .arg(arg!(--clusters <CLUSTERS> "file with a barcode and cluster label on each line; the EM is first run on the counts aggregated over each cluster, and the resulting abundances are used as a prior in the EM of the cluster's cells").required(false))
+    .arg(arg!(--"cluster-prior-strength" <STRENGTH> "the weight of the cluster-level prior; the pseudo-counts it contributes to each cell sum to this fraction of the cell's number of UMIs").requires("clusters").default_value(&default_prior_strength));
+
+    let doublets_app = Command::new("score-doublets")
+    .about("Score the cells quantified by quant for being doublets, using simulated doublets")
+    .version(version)
+    .author(crate_authors)
+    .arg(arg!(-i --"input-dir" <INPUTDIR> "output directory of quant, containing the count matrix").takes_value(true).required(true))
+    .arg(arg!(-o --"output-dir" <OUTPUTDIR> "output directory where the doublet scores will be written").takes_value(true).required(true))
+    .arg(arg!(-t --threads <THREADS> "number of threads to use for processing").default_value(&max_num_threads))
+    .arg(arg!(--"sim-ratio" <RATIO> "number of doublets to simulate per cell").default_value(&default_sim_ratio))
+    .arg(arg!(--"expected-rate" <RATE> "expected fraction of doublets among the cells").default_value(&default_expected_rate))
+    .arg(arg!(--"num-hvg" <NUMHVG> "number of highly variable genes used to embed the cells").default_value(&default_num_hvg))
+    .arg(arg!(--"num-pcs" <NUMPCS> "number of principal components used to embed the cells").default_value(&default_num_pcs))
+    .arg(arg!(-k --"num-neighbors" <K> "number of nearest neighbors of each cell used for scoring (scaled up to account for the simulated doublets); chosen based on the number of cells if not provided").required(false))
+    .arg(arg!(--seed <SEED> "seed for the random number generator used to simulate doublets; a random seed is chosen if not provided").required(false));
 
     let opts = Command::new("alevin-fry")
         .subcommand_required(true)
@@ -161,6 +270,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .version(version)
         .author(crate_authors)
         .about("Process RAD files from the command line")
+        .arg(arg!(--"log-format" <LOGFORMAT> "the format of the log records; json writes one JSON object per record, with progress reported as periodic JSON events")
+             .possible_values(["text", "json"])
+             .default_value("text")
+             .required(false)
+             .global(true))
+        .arg(arg!(--"log-file" <LOGFILE> "the file to which the log records are written, rather than the terminal (text) or stderr (json)")
+             .required(false)
+             .global(true))
         .subcommand(gen_app)
         //.subcommand(test_app)
         .subcommand(collate_app)
@@ -168,19 +285,53 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .subcommand(infer_app)
         .subcommand(convert_app)
         .subcommand(view_app)
+        .subcommand(check_t2g_app)
+        .subcommand(dump_permit_app)
+        .subcommand(report_app)
+        .subcommand(doublets_app)
         .get_matches();
 
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::CompactFormat::new(decorator)
-        .use_custom_timestamp(|out: &mut dyn std::io::Write| {
-            write!(out, "{}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S")).unwrap();
-            Ok(())
-        })
-        .build()
-        .fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    let log_format: LogFormat = opts.value_of_t("log-format").unwrap();
+    // every record carries the name of the stage (subcommand) being run;
+    // this is the only handle on the root logger, so that dropping it
+    // flushes the records.
+    let stage = opts.subcommand_name().unwrap_or("").to_string();
+    let log = logging::build_logger(log_format, opts.value_of("log-file"))?
+        .new(o!("stage" => stage.clone()));
+    let start_time = std::time::Instant::now();
+
+    let res = run(&opts, log.clone());
+
+    // the single exit point; the last progress events and records must be
+    // written out before the (asynchronous) logger is dropped.
+    logging::finish_progress();
+    if let Err(e) = res {
+        crit!(
+            log,
+            "{} failed after {:.1} seconds: {}",
+            stage,
+            start_time.elapsed().as_secs_f64(),
+            e;
+            "elapsed_secs" => start_time.elapsed().as_secs_f64()
+        );
+        drop(log);
+        std::process::exit(1);
+    }
+    info!(
+        log,
+        "finished {} in {:.1} seconds",
+        stage,
+        start_time.elapsed().as_secs_f64();
+        "elapsed_secs" => start_time.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
 
-    let log = slog::Logger::root(drain, o!());
+/// Run the subcommand given in `opts`, logging to `log`.
+fn run(opts: &ArgMatches, log: slog::Logger) -> Result<(), Box<dyn std::error::Error>> {
+    let version = crate_version!();
+    // capture the entire command line as a string
+    let cmdline = std::env::args().join(" ");
 
     // You can handle information about subcommands by requesting their matches by name
     // (as below), requesting just the name used, or both at the same time
@@ -204,32 +355,56 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     */
 
     if let Some(t) = opts.subcommand_matches("generate-permit-list") {
-        let input_dir: String = t.value_of_t("input").expect("no input directory specified");
+        let input_dirs: Vec<String> = t
+            .values_of_t("input")
+            .expect("no input directory specified");
         let output_dir: String = t
             .value_of_t("output-dir")
             .expect("no input directory specified");
 
+        let chemistry: Option<&'static Chemistry> = match t.value_of("chemistry") {
+            Some(c) => Some(c.parse()?),
+            None => None,
+        };
+
         let valid_ori: bool;
-        let expected_ori = match t.value_of("expected-ori").unwrap().to_uppercase().as_str() {
-            "RC" => {
+        // `None` means that the orientation is inferred from the reads
+        let expected_ori = match t
+            .value_of("expected-ori")
+            .map(|o| o.to_uppercase())
+            .as_deref()
+        {
+            None => {
+                valid_ori = true;
+                Some(
+                    chemistry
+                        .expect("--chemistry is required when --expected-ori is not given")
+                        .expected_ori,
+                )
+            }
+            Some("RC") => {
                 valid_ori = true;
-                Strand::Reverse
+                Some(Strand::Reverse)
             }
-            "FW" => {
+            Some("FW") => {
                 valid_ori = true;
-                Strand::Forward
+                Some(Strand::Forward)
             }
-            "BOTH" => {
+            Some("BOTH") => {
                 valid_ori = true;
-                Strand::Unknown
+                Some(Strand::Unknown)
             }
-            "EITHER" => {
+            Some("EITHER") => {
                 valid_ori = true;
-                Strand::Unknown
+                Some(Strand::Unknown)
+            }
+            Some("AUTO") => {
+                valid_ori = true;
+                None
             }
             _ => {
                 valid_ori = false;
-                Strand::Unknown
+                None
             }
         };
 
@@ -237,9 +412,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             crit!(
                 log,
                 "{} is not a valid option for --expected-ori",
-                expected_ori
+                t.value_of("expected-ori").unwrap()
             );
-            std::process::exit(1);
+            return Err("execution terminated unexpectedly".into());
+        }
+
+        if let Some(chem) = chemistry {
+            if let Some(ori) = expected_ori {
+                if t.is_present("expected-ori") && chem.expected_ori != ori {
+                    warn!(
+                        log,
+                        "the provided --expected-ori ({}) differs from that of the {} chemistry ({}); using {}",
+                        ori.strand_symbol(),
+                        chem.name,
+                        chem.expected_ori.strand_symbol(),
+                        ori.strand_symbol()
+                    );
+                }
+            }
         }
 
         let mut fmeth = CellFilterMethod::KneeFinding;
@@ -277,31 +467,67 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let min_reads: usize = t
                 .value_of_t("min-reads")
                 .expect("min-reads must be a valid integer");
-            if min_reads < 1 {
-                crit!(
+            fmeth = CellFilterMethod::UnfilteredExternalList(v, min_reads);
+        };
+
+        // without another way to select the permitted barcodes, the cached
+        // permit list of the chemistry (if there is one) is used as the
+        // unfiltered permit list
+        let meth_given = [
+            "knee-distance",
+            "expect-cells",
+            "force-cells",
+            "valid-bc",
+            "unfiltered-pl",
+        ]
+        .iter()
+        .any(|a| t.is_present(a));
+        if let (false, Some(chem)) = (meth_given, chemistry) {
+            if let Some(pl) = chem.cached_permit_list() {
+                info!(
                     log,
-                    "min-reads < 1 is not supported, the value {} was provided",
-                    min_reads
+                    "using the permit list of the {} chemistry cached at {} as the unfiltered permit list",
+                    chem.name,
+                    pl.display()
+                );
+                let min_reads: usize = t
+                    .value_of_t("min-reads")
+                    .expect("min-reads must be a valid integer");
+                fmeth = CellFilterMethod::UnfilteredExternalList(
+                    pl.to_string_lossy().into_owned(),
+                    min_reads,
                 );
-                std::process::exit(1);
             }
-            fmeth = CellFilterMethod::UnfilteredExternalList(v, min_reads);
+        }
+
+        let correction_params = BarcodeCorrectionParams {
+            allow_indels: t.is_present("correct-indels"),
+            min_posterior: t
+                .value_of_t("min-correction-posterior")
+                .expect("min-correction-posterior must be a valid number"),
         };
+        let num_threads: u32 = t.value_of_t("threads").unwrap();
 
-        // velo_mode --- currently, on this branch, it is always false
-        let velo_mode = false; //t.is_present("velocity-mode");
-
-        match generate_permit_list(
-            input_dir,
-            output_dir,
-            fmeth,
-            expected_ori,
-            VERSION,
-            velo_mode,
-            &cmdline,
-            &log,
-        ) {
-            Ok(nc) if nc == 0 => {
+        let config = match PermitListConfig::builder(input_dirs, &output_dir)
+            .filter_method(fmeth)
+            .expected_ori(expected_ori)
+            .correction_params(correction_params)
+            .write_corrections(t.is_present("write-corrections"))
+            .chemistry(chemistry)
+            .num_threads(num_threads)
+            .cmdline(&cmdline)
+            .version(VERSION)
+            .build()
+        {
+            Ok(c) => c,
+            Err(e) => {
+                crit!(log, "{}", e);
+                return Err("execution terminated unexpectedly".into());
+            }
+        };
+
+        match generate_permit_list(config, &log) {
+            Ok(stats) if stats.num_corrected_barcodes == 0 => {
                 warn!(log, "found 0 corrected barcodes; please check the input.");
             }
             Err(e) => return Err(e),
@@ -329,65 +555,169 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         alevin_fry::convert::view(rad_file, print_header, out_file, &log)
     }
 
+    // check that a tg-map matches the references of a RAD file
+    if let Some(t) = opts.subcommand_matches("check-t2g") {
+        let rad_file: String = t.value_of_t("rad").unwrap();
+        let tg_map: String = t.value_of_t("tg-map").unwrap();
+        let output = t.value_of("output");
+        match alevin_fry::check_t2g::check_t2g(&rad_file, &tg_map, output, &log) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err("the tg-map can't be used with the RAD file".into());
+            }
+            Err(e) => {
+                crit!(log, "could not check the tg-map: {}", e);
+                return Err("execution terminated unexpectedly".into());
+            }
+        }
+    }
+
+    if let Some(t) = opts.subcommand_matches("dump-permit") {
+        let input_dir: String = t.value_of_t("input-dir").unwrap();
+        let output_dir: String = t.value_of_t("output-dir").unwrap();
+        let fmt = match t.value_of("format").unwrap().to_lowercase().as_str() {
+            "json" => DumpFormat::Json,
+            _ => DumpFormat::Tsv,
+        };
+        if let Err(e) = alevin_fry::dump_permit::dump_permit(&input_dir, &output_dir, fmt, &log) {
+            crit!(log, "could not decode the permit list: {}", e);
+            return Err("execution terminated unexpectedly".into());
+        }
+    }
+
+    if let Some(t) = opts.subcommand_matches("report") {
+        let permit_dir = t.value_of("permit-dir");
+        let quant_dir = t.value_of("quant-dir");
+        let output: String = t.value_of_t("output").unwrap();
+        if let Err(e) = alevin_fry::report::write_report(permit_dir, quant_dir, &output, &log) {
+            crit!(log, "could not write the report: {}", e);
+            return Err("execution terminated unexpectedly".into());
+        }
+    }
+
     // collate a rad file to group together all records corresponding
     // to the same corrected barcode.
     if let Some(t) = opts.subcommand_matches("collate") {
         let input_dir: String = t.value_of_t("input-dir").unwrap();
-        let rad_dir: String = t.value_of_t("rad-dir").unwrap();
+        let rad_dirs: Vec<String> = t.values_of_t("rad-dir").unwrap();
         let num_threads = t.value_of_t("threads").unwrap();
         let compress_out = t.is_present("compress");
         let max_records: u32 = t.value_of_t("max-records").unwrap();
-        alevin_fry::collate::collate(
-            input_dir,
-            rad_dir,
-            num_threads,
-            max_records,
-            compress_out,
-            &cmdline,
-            VERSION,
-            &log,
-        )
-        .expect("could not collate.");
+        let max_memory = match t
+            .value_of("max-memory")
+            .map(alevin_fry::utils::parse_memory_size)
+        {
+            Some(Ok(m)) => Some(m),
+            Some(Err(e)) => {
+                crit!(log, "{}", e);
+                return Err("execution terminated unexpectedly".into());
+            }
+            None => None,
+        };
+        let config = match CollateConfig::builder(&input_dir, rad_dirs)
+            .num_threads(num_threads)
+            .max_records(max_records)
+            .max_memory(max_memory)
+            .compress_out(compress_out)
+            .cmdline(&cmdline)
+            .version(VERSION)
+            .build()
+        {
+            Ok(c) => c,
+            Err(e) => {
+                crit!(log, "{}", e);
+                return Err("execution terminated unexpectedly".into());
+            }
+        };
+        alevin_fry::collate::collate(config, &log)?;
     }
 
     // perform quantification of a collated rad file.
     if let Some(t) = opts.subcommand_matches("quant") {
         let num_threads = t.value_of_t("threads").unwrap();
-        let num_bootstraps = t.value_of_t("num-bootstraps").unwrap();
+        let num_bootstraps: u32 = t.value_of_t("num-bootstraps").unwrap();
+        let num_gibbs_samples: u32 = t
+            .value_of_t("num-gibbs-samples")
+            .expect("num-gibbs-samples must be a valid integer");
+        if num_bootstraps > 0 && num_gibbs_samples > 0 {
+            crit!(
+                log,
+                "only one of --num-bootstraps and --num-gibbs-samples may be provided"
+            );
+            return Err("execution terminated unexpectedly".into());
+        }
         let init_uniform = t.is_present("init-uniform");
+        let seed: u64 = match t.value_of("seed") {
+            Some(v) => v
+                .parse()
+                .expect("seed must be a valid (64-bit unsigned) integer"),
+            None => rand::random(),
+        };
         let summary_stat = t.is_present("summary-stat");
+        let em_prior_strength: f32 = t
+            .value_of_t("em-prior-strength")
+            .expect("em-prior-strength must be a valid number");
+        let em_prior = t.value_of("em-prior").map(|p| EmPrior {
+            source: match p {
+                "pseudo-bulk" => EmPriorSource::PseudoBulk,
+                fname => EmPriorSource::Table(fname.to_string()),
+            },
+            strength: em_prior_strength,
+        });
         let dump_eq = t.is_present("dump-eqclasses");
         let use_mtx = t.is_present("use-mtx");
         let input_dir: String = t.value_of_t("input-dir").unwrap();
-        let output_dir = t.value_of_t("output-dir").unwrap();
-        let tg_map = t.value_of_t("tg-map").unwrap();
+        let output_dir: String = t.value_of_t("output-dir").unwrap();
+        let tg_map: String = t.value_of_t("tg-map").unwrap();
         let resolution: ResolutionStrategy = t.value_of_t("resolution").unwrap();
         let sa_model: SplicedAmbiguityModel = t.value_of_t("sa-model").unwrap();
         let small_thresh = t.value_of_t("small-thresh").unwrap();
+        let pug_params = PugGraphParams {
+            max_umi_dist: t
+                .value_of_t("umi-edit-dist")
+                .expect("umi-edit-dist must be a valid integer"),
+            allow_indels: t.is_present("umi-indels"),
+            count_ratio: t
+                .value_of_t("pug-count-ratio")
+                .expect("pug-count-ratio must be a valid number"),
+            umi_count_ratio: t
+                .value_of_t("umi-count-ratio")
+                .expect("umi-count-ratio must be a valid number"),
+        };
+        let qc_gene_sets = QcGeneSets {
+            mito: t.value_of("mito-genes").map(String::from),
+            ribo: t.value_of("ribo-genes").map(String::from),
+        };
         let filter_list = t.value_of("quant-subset");
 
-        if dump_eq && (resolution == ResolutionStrategy::Trivial) {
-            crit!(
-                log,
-                "\n\nGene equivalence classes are not meaningful in case of Trivial resolution."
-            );
-            std::process::exit(1);
-        }
-
-        if num_bootstraps > 0 {
-            match resolution {
-                ResolutionStrategy::CellRangerLikeEm | ResolutionStrategy::Full => {
-                    // sounds good
-                }
-                _ => {
-                    eprintln!(
-                        "\n\nThe num_bootstraps argument was set to {}, but bootstrapping can only be used with the cr-like-em or full resolution strategies",
-                        num_bootstraps
-                    );
-                    std::process::exit(1);
-                }
+        let builder = QuantConfig::builder(&input_dir, &tg_map, &output_dir, resolution)
+            .num_threads(num_threads)
+            .init_uniform(init_uniform)
+            .seed(seed)
+            .em_prior(em_prior)
+            .summary_stat(summary_stat)
+            .dump_eq(dump_eq)
+            .use_mtx(use_mtx)
+            .sa_model(sa_model)
+            .small_thresh(small_thresh)
+            .pug_params(pug_params)
+            .qc_gene_sets(qc_gene_sets)
+            .filter_list(filter_list)
+            .cmdline(&cmdline)
+            .version(VERSION);
+        // Gibbs samples are written out in place of bootstraps
+        let builder = if num_gibbs_samples > 0 {
+            builder.gibbs_samples(num_gibbs_samples)
+        } else {
+            builder.bootstraps(num_bootstraps)
+        };
+        let config = match builder.build() {
+            Ok(c) => c,
+            Err(e) => {
+                crit!(log, "{}", e);
+                return Err("execution terminated unexpectedly".into());
             }
-        }
+        };
 
         // first make sure that the input direcory passed in has the
         // appropriate json file in it.
@@ -400,91 +730,35 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         // then proceed.  otherwise print a critical error.
         if json_path.exists() {
             let velo_mode = alevin_fry::utils::is_velo_mode(input_dir.to_string());
-            if velo_mode {
-                match alevin_fry::quant::velo_quantify(
-                    input_dir,
-                    tg_map,
-                    output_dir,
-                    num_threads,
-                    num_bootstraps,
-                    init_uniform,
-                    summary_stat,
-                    dump_eq,
-                    use_mtx,
-                    resolution,
-                    sa_model,
-                    small_thresh,
-                    filter_list,
-                    &cmdline,
-                    VERSION,
-                    &log,
-                ) {
-                    // if we're all good; then great!
-                    Ok(_) => {}
-                    // if we have an error, see if it's an error parsing
-                    // the CSV or something else.
-                    Err(e) => match e.downcast_ref::<CSVError>() {
-                        Some(error) => {
-                            match *error.kind() {
-                                // if a deserialize error, we already complained about it
-                                ErrorKind::Deserialize { .. } => {
-                                    return Err("execution terminated unexpectedly".into())
-                                }
-                                // if another type of error, just panic for now
-                                _ => {
-                                    panic!("could not quantify rad file.");
-                                }
-                            }
-                        }
-                        // if something else, just panic
-                        None => {
-                            panic!("could not quantify rad file.");
-                        }
-                    },
-                }; // end match if
+            let res = if velo_mode {
+                alevin_fry::quant::velo_quantify(config, &log)
             } else {
-                match alevin_fry::quant::quantify(
-                    input_dir,
-                    tg_map,
-                    output_dir,
-                    num_threads,
-                    num_bootstraps,
-                    init_uniform,
-                    summary_stat,
-                    dump_eq,
-                    use_mtx,
-                    resolution,
-                    sa_model,
-                    small_thresh,
-                    filter_list,
-                    &cmdline,
-                    VERSION,
-                    &log,
-                ) {
-                    // if we're all good; then great!
-                    Ok(_) => {}
-                    // if we have an error, see if it's an error parsing
-                    // the CSV or something else.
-                    Err(e) => match e.downcast_ref::<CSVError>() {
-                        Some(error) => {
-                            match *error.kind() {
-                                // if a deserialize error, we already complained about it
-                                ErrorKind::Deserialize { .. } => {
-                                    return Err("execution terminated unexpectedly".into())
-                                }
-                                // if another type of error, just panic for now
-                                _ => {
-                                    panic!("could not quantify rad file.");
-                                }
+                alevin_fry::quant::quantify(config, &log)
+            };
+            match res {
+                // if we're all good; then great!
+                Ok(_) => {}
+                // if we have an error, see if it's an error parsing
+                // the CSV or something else.
+                Err(e) => match e.downcast_ref::<CSVError>() {
+                    Some(error) => {
+                        match *error.kind() {
+                            // if a deserialize error, we already complained about it
+                            ErrorKind::Deserialize { .. } => {
+                                return Err("execution terminated unexpectedly".into())
+                            }
+                            // if another type of error, just panic for now
+                            _ => {
+                                panic!("could not quantify rad file.");
                             }
                         }
-                        // if something else, just panic
-                        None => {
-                            panic!("could not quantify rad file.");
-                        }
-                    },
-                }; //end quant if
-            }; // end velo_mode if
+                    }
+                    // if something else, it has been logged where it happened
+                    None => {
+                        return Err(e);
+                    }
+                },
+            }; //end quant match
         } else {
             crit!(log,
             "The provided input directory lacks a generate_permit_list.json file; this should not happen."
@@ -497,28 +771,92 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     if let Some(t) = opts.subcommand_matches("infer") {
         let num_threads = t.value_of_t("threads").unwrap();
         let use_mtx = t.is_present("use-mtx");
-        let output_dir = t.value_of_t("output-dir").unwrap();
-        let count_mat = t.value_of_t("count-mat").unwrap();
-        let eq_label_file = t.value_of_t("eq-labels").unwrap();
+        let output_dir: String = t.value_of_t("output-dir").unwrap();
+        let count_mat: String = t.value_of_t("count-mat").unwrap();
+        let eq_label_file: String = t.value_of_t("eq-labels").unwrap();
         let filter_list = t.value_of("quant-subset");
         let usa_mode = t.is_present("usa");
+        let num_gibbs_samples: u32 = t
+            .value_of_t("num-gibbs-samples")
+            .expect("num-gibbs-samples must be a valid integer");
+        let summary_stat = t.is_present("summary-stat");
+        let seed: u64 = match t.value_of("seed") {
+            Some(v) => v
+                .parse()
+                .expect("seed must be a valid (64-bit unsigned) integer"),
+            None => rand::random(),
+        };
+        let clusters = t.value_of("clusters");
+        let cluster_prior_strength: f32 = t
+            .value_of_t("cluster-prior-strength")
+            .expect("cluster-prior-strength must be a valid number");
         //let bc_file = t.value_of_t("barcodes").unwrap();
 
-        alevin_fry::infer::infer(
-            //num_bootstraps,
-            //init_uniform,
-            //summary_stat,
-            count_mat,
-            eq_label_file,
-            usa_mode,
-            //bc_file,
-            use_mtx,
+        let config = match InferConfig::builder(&count_mat, &eq_label_file, &output_dir)
+            .usa_mode(usa_mode)
+            .use_mtx(use_mtx)
+            .gibbs_samples(num_gibbs_samples)
+            .summary_stat(summary_stat)
+            .seed(seed)
+            .clusters(clusters)
+            .cluster_prior_strength(cluster_prior_strength)
+            .num_threads(num_threads)
+            .filter_list(filter_list)
+            .version(version)
+            .build()
+        {
+            Ok(c) => c,
+            Err(e) => {
+                crit!(log, "{}", e);
+                return Err("execution terminated unexpectedly".into());
+            }
+        };
+        alevin_fry::infer::infer(config, &log)?;
+    }
+
+    if let Some(t) = opts.subcommand_matches("score-doublets") {
+        let input_dir: String = t.value_of_t("input-dir").unwrap();
+        let output_dir: String = t.value_of_t("output-dir").unwrap();
+        let num_threads = t.value_of_t("threads").unwrap();
+        let params = DoubletParams {
+            sim_ratio: t
+                .value_of_t("sim-ratio")
+                .expect("sim-ratio must be a valid number"),
+            expected_rate: t
+                .value_of_t("expected-rate")
+                .expect("expected-rate must be a valid number"),
+            num_hvg: t
+                .value_of_t("num-hvg")
+                .expect("num-hvg must be a valid integer"),
+            num_pcs: t
+                .value_of_t("num-pcs")
+                .expect("num-pcs must be a valid integer"),
+            num_neighbors: t
+                .value_of("num-neighbors")
+                .map(|v| v.parse().expect("num-neighbors must be a valid integer")),
+            seed: match t.value_of("seed") {
+                Some(v) => v
+                    .parse()
+                    .expect("seed must be a valid (64-bit unsigned) integer"),
+                None => rand::random(),
+            },
+        };
+        if let Err(e) = params.validate() {
+            crit!(log, "{}", e);
+            return Err("execution terminated unexpectedly".into());
+        }
+
+        if let Err(e) = alevin_fry::doublets::score_doublets(
+            &input_dir,
+            &output_dir,
+            &params,
             num_threads,
-            filter_list,
-            output_dir,
             &log,
-        )
-        .expect("could not perform inference from equivalence class counts.");
+        ) {
+            crit!(log, "could not score doublets: {}", e);
+            return Err("execution terminated unexpectedly".into());
+        }
     }
+
     Ok(())
 }
@@ -38,6 +38,122 @@ pub enum PugEdgeType {
     YToX,
 }
 
+/// The UMI network deduplication methods described in
+///     Smith, Tom, Andreas Heger, and Ian Sudbery.
+///     "UMI-tools: modeling sequencing errors in Unique Molecular Identifiers to improve quantification accuracy."
+///     Genome research 27.3 (2017): 491-499.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum UmiNetworkMethod {
+    // each connected component of the
+    // 1-edit UMI network is a molecule
+    Cluster,
+    // each component is resolved into the minimum
+    // number of most-abundant UMIs whose neighbors
+    // cover the component
+    Adjacency,
+    // edges are only followed from a UMI to a
+    // sufficiently less abundant neighbor
+    Directional,
+}
+
+/// The default maximum distance between 2 connected UMIs.
+pub const DEFAULT_MAX_UMI_DIST: u32 = 1;
+/// The default count ratio above which an edge is unidirectional.
+pub const DEFAULT_PUG_COUNT_RATIO: f64 = 2.0;
+/// The default count ratio of the directional UMI network (as in UMI-tools).
+pub const DEFAULT_UMI_COUNT_RATIO: f64 = 2.0;
+
+/// Parameters controlling which pairs of UMIs are connected
+/// in the parsimonious UMI graph, and how the edges are directed.
+#[derive(Debug, Clone, Copy)]
+pub struct PugGraphParams {
+    /// the maximum distance between 2 UMIs that are connected
+    pub max_umi_dist: u32,
+    /// if true, the distance is the edit distance (allowing
+    /// insertions and deletions), otherwise it is the Hamming distance
+    pub allow_indels: bool,
+    /// an edge is directed from x to y if count(x) > ratio * count(y) - 1
+    pub count_ratio: f64,
+    /// in the directional UMI network, there is an edge from x to y
+    /// if count(x) >= umi_count_ratio * count(y) - 1
+    pub umi_count_ratio: f64,
+}
+
+impl Default for PugGraphParams {
+    fn default() -> Self {
+        Self {
+            max_umi_dist: DEFAULT_MAX_UMI_DIST,
+            allow_indels: false,
+            count_ratio: DEFAULT_PUG_COUNT_RATIO,
+            umi_count_ratio: DEFAULT_UMI_COUNT_RATIO,
+        }
+    }
+}
+
+impl PugGraphParams {
+    pub fn is_default(&self) -> bool {
+        let d = Self::default();
+        self.max_umi_dist == d.max_umi_dist
+            && self.allow_indels == d.allow_indels
+            && (self.count_ratio - d.count_ratio).abs() < f64::EPSILON
+            && (self.umi_count_ratio - d.umi_count_ratio).abs() < f64::EPSILON
+    }
+
+    /// If the distinct UMIs `x` and `y` (of length `umi_len`) are within
+    /// the maximum distance of each other, returns whether they are
+    /// only within it through an insertion or deletion.
+    fn connects(&self, x: u64, y: u64, umi_len: usize) -> Option<bool> {
+        let max_dist = self.max_umi_dist as usize;
+        if afutils::count_diff_2_bit_packed(x, y) <= max_dist {
+            Some(false)
+        } else if self.allow_indels
+            && afutils::edit_dist_2_bit_packed(x, y, umi_len, max_dist) <= max_dist
+        {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    /// True if a UMI seen `xc` times is sufficiently more abundant than a
+    /// neighbor seen `yc` times to direct an edge from it to the neighbor.
+    fn dominates(&self, xc: u32, yc: u32) -> bool {
+        xc as f64 > (self.count_ratio * yc as f64 - 1.0)
+    }
+
+    /// True if a UMI seen `xc` times has an edge to a neighbor seen `yc`
+    /// times in the directional UMI network.  Unlike in the PUG, the
+    /// comparison is not strict, so 2 UMIs seen once are connected.
+    fn directional_dominates(&self, xc: u32, yc: u32) -> bool {
+        xc as f64 >= (self.umi_count_ratio * yc as f64 - 1.0)
+    }
+}
+
+/// Summary of the graphs built by `extract_graph`, used to
+/// report the effect of the `PugGraphParams` on the PUGs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PugGraphStatistics {
+    pub num_graphs: u64,
+    pub num_vertices: u64,
+    pub num_edges: u64,
+    pub bidirected_pairs: u64,
+    pub unidirected_pairs: u64,
+    // connected pairs of distinct UMIs
+    // whose Hamming distance exceeds the maximum
+    pub indel_pairs: u64,
+}
+
+impl PugGraphStatistics {
+    pub fn merge(&mut self, other: &PugGraphStatistics) {
+        self.num_graphs += other.num_graphs;
+        self.num_vertices += other.num_vertices;
+        self.num_edges += other.num_edges;
+        self.bidirected_pairs += other.bidirected_pairs;
+        self.unidirected_pairs += other.unidirected_pairs;
+        self.indel_pairs += other.indel_pairs;
+    }
+}
+
 #[derive(Debug)]
 pub struct PugResolutionStatistics {
     pub used_alternative_strategy: bool,
@@ -54,32 +170,42 @@ pub struct PugResolutionStatistics {
 /// the rank of the UMI in the list of all distinct UMIs for this
 /// equivalence class.  There is a directed edge between any pair of
 /// vertices whose set of transcripts overlap and whose UMIs are within
-/// a Hamming distance of 1 of each other.  If one node has more than
-/// twice the frequency of the other, the edge is directed from the
-/// more frequent to the less freuqent node.  Otherwise, edges are
-/// added in both directions.
+/// a distance of `params.max_umi_dist` of each other (the Hamming
+/// distance, or the edit distance if `params.allow_indels` is set).
+/// If the frequency of one node, x, satisfies
+/// count(x) > `params.count_ratio` * count(y) - 1 for the other node, y,
+/// the edge is directed from x to y.  Otherwise, edges are
+/// added in both directions.  The default parameters (Hamming distance 1,
+/// ratio 2) give the original PUG construction.  Counts describing the
+/// resulting graph are accumulated into `stats`.
 pub fn extract_graph(
     eqmap: &EqMap,
+    params: &PugGraphParams,
+    umi_len: usize,
+    stats: &mut PugGraphStatistics,
     log: &slog::Logger,
 ) -> petgraph::graphmap::GraphMap<(u32, u32), (), petgraph::Directed> {
     let verbose = false;
     let mut one_edit = 0u64;
     let mut zero_edit = 0u64;
+    let mut indel_pairs = 0u64;
 
     // given 2 pairs (UMI, count), determine if an edge exists
     // between them, and if so, what type.
     let mut has_edge = |x: &(u64, u32), y: &(u64, u32)| -> PugEdgeType {
-        let hdist = afutils::count_diff_2_bit_packed(x.0, y.0);
-        if hdist == 0 {
+        if x.0 == y.0 {
             zero_edit += 1;
             return PugEdgeType::BiDirected;
         }
 
-        if hdist < 2 {
+        if let Some(through_indel) = params.connects(x.0, y.0, umi_len) {
+            if through_indel {
+                indel_pairs += 1;
+            }
             one_edit += 1;
-            if x.1 > (2 * y.1 - 1) {
+            if params.dominates(x.1, y.1) {
                 return PugEdgeType::XToY;
-            } else if y.1 > (2 * x.1 - 1) {
+            } else if params.dominates(y.1, x.1) {
                 return PugEdgeType::YToX;
             } else {
                 return PugEdgeType::BiDirected;
@@ -88,8 +214,8 @@ pub fn extract_graph(
         PugEdgeType::NoEdge
     };
 
-    let mut _bidirected = 0u64;
-    let mut _unidirected = 0u64;
+    let mut bidirected = 0u64;
+    let mut unidirected = 0u64;
 
     let mut graph = DiGraphMap::<(u32, u32), ()>::new();
     let mut hset = vec![0u8; eqmap.num_eq_classes()];
@@ -138,21 +264,21 @@ pub fn extract_graph(
                     PugEdgeType::BiDirected => {
                         graph.add_edge((eqid as u32, xi as u32), (eqid as u32, xi2 as u32), ());
                         graph.add_edge((eqid as u32, xi2 as u32), (eqid as u32, xi as u32), ());
-                        _bidirected += 1;
+                        bidirected += 1;
                         //if multi_gene_vec[eqid] == true {
                         //    bidirected_in_multigene += 1;
                         //}
                     }
                     PugEdgeType::XToY => {
                         graph.add_edge((eqid as u32, xi as u32), (eqid as u32, xi2 as u32), ());
-                        _unidirected += 1;
+                        unidirected += 1;
                         //if multi_gene_vec[eqid] == true {
                         //    unidirected_in_multigene += 1;
                         //}
                     }
                     PugEdgeType::YToX => {
                         graph.add_edge((eqid as u32, xi2 as u32), (eqid as u32, xi as u32), ());
-                        _unidirected += 1;
+                        unidirected += 1;
                         //if multi_gene_vec[eqid] == true {
                         //    unidirected_in_multigene += 1;
                         //}
@@ -209,7 +335,7 @@ pub fn extract_graph(
                             PugEdgeType::BiDirected => {
                                 graph.add_edge((eqid as u32, xi as u32), (*eq2id, yi as u32), ());
                                 graph.add_edge((*eq2id, yi as u32), (eqid as u32, xi as u32), ());
-                                _bidirected += 1;
+                                bidirected += 1;
                                 //if multi_gene_vec[eqid] == true
                                 //    || multi_gene_vec[*eq2id as usize] == true
                                 //{
@@ -218,7 +344,7 @@ pub fn extract_graph(
                             }
                             PugEdgeType::XToY => {
                                 graph.add_edge((eqid as u32, xi as u32), (*eq2id, yi as u32), ());
-                                _unidirected += 1;
+                                unidirected += 1;
                                 //if multi_gene_vec[eqid] == true
                                 //    || multi_gene_vec[*eq2id as usize] == true
                                 //{
@@ -227,7 +353,7 @@ pub fn extract_graph(
                             }
                             PugEdgeType::YToX => {
                                 graph.add_edge((*eq2id, yi as u32), (eqid as u32, xi as u32), ());
-                                _unidirected += 1;
+                                unidirected += 1;
                                 //if multi_gene_vec[eqid] == true
                                 //    || multi_gene_vec[*eq2id as usize] == true
                                 //{
@@ -253,6 +379,13 @@ pub fn extract_graph(
         info!(log, "\n\n\n{}\n\n\n", one_edit as f64 / total_edits);
     }
 
+    stats.num_graphs += 1;
+    stats.num_vertices += graph.node_count() as u64;
+    stats.num_edges += graph.edge_count() as u64;
+    stats.bidirected_pairs += bidirected;
+    stats.unidirected_pairs += unidirected;
+    stats.indel_pairs += indel_pairs;
+
     graph
 }
 
@@ -782,6 +915,217 @@ pub fn get_num_molecules_trivial_discard_all_ambig(
     (counts, multi_gene_umis as f64 / total_umis as f64)
 }
 
+/// Given 2 distinct (UMI, count) pairs, determine if an edge exists
+/// between them in the UMI-tools directional network, and if so, what
+/// type.  There is an edge from `x` to `y` if their UMIs are connected
+/// and count(x) >= `params.umi_count_ratio` * count(y) - 1, and vice
+/// versa.  Unlike in the parsimonious UMI graph, pairs whose counts are
+/// too similar have no edge.
+#[inline]
+fn directional_edge_type(
+    x: &(u64, u32),
+    y: &(u64, u32),
+    params: &PugGraphParams,
+    umi_len: usize,
+) -> PugEdgeType {
+    if params.connects(x.0, y.0, umi_len).is_none() {
+        return PugEdgeType::NoEdge;
+    }
+    match (
+        params.directional_dominates(x.1, y.1),
+        params.directional_dominates(y.1, x.1),
+    ) {
+        (true, true) => PugEdgeType::BiDirected,
+        (true, false) => PugEdgeType::XToY,
+        (false, true) => PugEdgeType::YToX,
+        (false, false) => PugEdgeType::NoEdge,
+    }
+}
+
+/// Collapse the (UMI, count) pairs in `umis`, all of which are assumed
+/// to be assigned to the same gene (or gene-level equivalence class),
+/// using the UMI-tools network method `method`, and return the number
+/// of distinct molecules they represent.  Duplicate UMIs are merged
+/// (summing their counts) before the network is built.  UMIs (of length
+/// `umi_len`) are connected as in the parsimonious UMI graph according to
+/// `params`, whose UMI count ratio is only used by the `Directional` method.
+pub fn dedup_umi_network(
+    umis: &mut Vec<(u64, u32)>,
+    method: UmiNetworkMethod,
+    params: &PugGraphParams,
+    umi_len: usize,
+) -> u32 {
+    if umis.is_empty() {
+        return 0;
+    }
+
+    // merge duplicate UMIs, keeping track of their total count
+    quickersort::sort(&mut umis[..]);
+    umis.dedup_by(|next, prev| {
+        if next.0 == prev.0 {
+            prev.1 += next.1;
+            true
+        } else {
+            false
+        }
+    });
+
+    let n = umis.len();
+    if n == 1 {
+        return 1;
+    }
+
+    // the (possibly directed) adjacency list of the UMI network,
+    // and the union-find structure of its weakly connected components
+    let mut adj: Vec<Vec<u32>> = vec![Vec::new(); n];
+    let mut vertex_sets = UnionFind::<usize>::new(n);
+    for (xi, x) in umis.iter().enumerate() {
+        for (yi, y) in umis.iter().enumerate().skip(xi + 1) {
+            let et = match method {
+                UmiNetworkMethod::Directional => directional_edge_type(x, y, params, umi_len),
+                _ => {
+                    if params.connects(x.0, y.0, umi_len).is_some() {
+                        PugEdgeType::BiDirected
+                    } else {
+                        PugEdgeType::NoEdge
+                    }
+                }
+            };
+            match et {
+                PugEdgeType::BiDirected => {
+                    adj[xi].push(yi as u32);
+                    adj[yi].push(xi as u32);
+                }
+                PugEdgeType::XToY => {
+                    adj[xi].push(yi as u32);
+                }
+                PugEdgeType::YToX => {
+                    adj[yi].push(xi as u32);
+                }
+                PugEdgeType::NoEdge => {
+                    continue;
+                }
+            }
+            vertex_sets.union(xi, yi);
+        }
+    }
+
+    // visit the UMIs from most to least abundant (ties broken by UMI)
+    let mut order: Vec<u32> = (0..n as u32).collect();
+    order.sort_unstable_by(|a, b| {
+        let (ua, ub) = (&umis[*a as usize], &umis[*b as usize]);
+        ub.1.cmp(&ua.1).then(ua.0.cmp(&ub.0))
+    });
+
+    let labels = vertex_sets.into_labeling();
+    match method {
+        UmiNetworkMethod::Cluster => {
+            // every connected component is a molecule
+            let mut roots = labels;
+            roots.sort_unstable();
+            roots.dedup();
+            roots.len() as u32
+        }
+        UmiNetworkMethod::Adjacency => {
+            // the number of vertices in each component
+            let mut comp_size = vec![0usize; n];
+            for l in labels.iter() {
+                comp_size[*l] += 1;
+            }
+            // the number of vertices of each component covered so far
+            let mut comp_covered = vec![0usize; n];
+            let mut covered = vec![false; n];
+            let mut num_molecules = 0u32;
+            // taking the UMIs of a component in order of decreasing abundance,
+            // each UMI is a molecule until the UMIs taken so far, along with
+            // their neighbors, cover the entire component.
+            for v in order.iter().map(|v| *v as usize) {
+                let l = labels[v];
+                if comp_covered[l] == comp_size[l] {
+                    continue;
+                }
+                num_molecules += 1;
+                for u in std::iter::once(v).chain(adj[v].iter().map(|u| *u as usize)) {
+                    if !covered[u] {
+                        covered[u] = true;
+                        comp_covered[l] += 1;
+                    }
+                }
+            }
+            num_molecules
+        }
+        UmiNetworkMethod::Directional => {
+            // starting from the most abundant UMI not yet assigned to
+            // a molecule, every UMI reachable along directed edges is
+            // absorbed into the same molecule.
+            let mut visited = vec![false; n];
+            let mut bfs_list = VecDeque::new();
+            let mut num_molecules = 0u32;
+            for v in order.iter().map(|v| *v as usize) {
+                if visited[v] {
+                    continue;
+                }
+                num_molecules += 1;
+                visited[v] = true;
+                bfs_list.push_back(v);
+                while let Some(cv) = bfs_list.pop_front() {
+                    for nv in adj[cv].iter().map(|u| *u as usize) {
+                        if !visited[nv] {
+                            visited[nv] = true;
+                            bfs_list.push_back(nv);
+                        }
+                    }
+                }
+            }
+            num_molecules
+        }
+    }
+}
+
+/// Resolve the UMIs of a cell using the UMI-tools network method `method`.
+/// The UMIs of every equivalence class are projected to the corresponding
+/// set of genes, and the UMIs sharing a gene set are deduplicated together.
+/// The number of molecules found for each gene set is added to the
+/// corresponding entry of `gene_eqclass_hash`; gene-unique molecules
+/// are those whose label is of length 1.
+pub fn get_num_molecules_umi_network(
+    eq_map: &EqMap,
+    tid_to_gid: &[u32],
+    gene_eqclass_hash: &mut HashMap<Vec<u32>, u32, ahash::RandomState>,
+    method: UmiNetworkMethod,
+    params: &PugGraphParams,
+    umi_len: usize,
+    _log: &slog::Logger,
+) {
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut gene_set_umis: HashMap<Vec<u32>, Vec<(u64, u32)>, ahash::RandomState> =
+        HashMap::with_hasher(s);
+
+    for eqinfo in &eq_map.eqc_info {
+        // project the transcript ids to gene ids
+        let mut gset: Vec<u32> = eq_map
+            .refs_for_eqc(eqinfo.eq_num)
+            .iter()
+            .map(|tid| tid_to_gid[*tid as usize])
+            .collect();
+        // and make the gene ids unique
+        quickersort::sort(&mut gset[..]);
+        gset.dedup();
+
+        gene_set_umis
+            .entry(gset)
+            .or_default()
+            .extend_from_slice(&eqinfo.umis);
+    }
+
+    for (gset, umis) in gene_set_umis.iter_mut() {
+        let num_molecules = dedup_umi_network(umis, method, params, umi_len);
+        if num_molecules > 0 {
+            *gene_eqclass_hash.entry(gset.clone()).or_insert(0) += num_molecules;
+        }
+    }
+}
+
 /// given the connected component (subgraph) of `g` defined by the
 /// vertices in `vertex_ids`, apply the cell-ranger-like algorithm
 /// within this subgraph.
@@ -936,7 +1280,8 @@ fn get_num_molecules_large_component(
 /// cell, the EqMap `eqmap` to decode all equivalence classes
 /// and the transcript-to-gene map `tid_to_gid`, apply the parsimonious
 /// umi resolution algorithm.  Pass any relevant logging messages along to
-/// `log`.
+/// `log`.  Returns an error if some molecule cannot be covered by any
+/// transcript.
 pub fn get_num_molecules(
     g: &petgraph::graphmap::GraphMap<(u32, u32), (), petgraph::Directed>,
     eqmap: &EqMap,
@@ -944,7 +1289,7 @@ pub fn get_num_molecules(
     num_genes: usize,
     gene_eqclass_hash: &mut HashMap<Vec<u32>, u32, ahash::RandomState>,
     log: &slog::Logger,
-) -> PugResolutionStatistics
+) -> Result<PugResolutionStatistics, Box<dyn std::error::Error + Send + Sync>>
 //,)
 {
     type U32Set = HashSet<u32, ahash::RandomState>;
@@ -1062,7 +1407,7 @@ pub fn get_num_molecules(
 
                 if best_covering_txp == std::u32::MAX {
                     crit!(log, "Could not find a covering transcript");
-                    std::process::exit(1);
+                    return Err("could not find a covering transcript.".into());
                 }
 
                 // get gene_id of best covering transcript
@@ -1179,7 +1524,7 @@ pub fn get_num_molecules(
     }
 
     /*(gene_eqclass_hash,*/
-    pug_stats
+    Ok(pug_stats)
     //)
     /*
     let mut salmon_eqclasses = Vec::<SalmonEQClass>::new();
@@ -1255,3 +1600,113 @@ pub fn get_num_molecules(
     */
     //identified_txps
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::pugutils::{dedup_umi_network, PugGraphParams, UmiNetworkMethod};
+
+    fn encode(s: &[u8]) -> u64 {
+        let (_, km, _) = needletail::bitkmer::BitNuclKmer::new(s, s.len() as u8, false)
+            .next()
+            .unwrap();
+        km.0
+    }
+
+    #[test]
+    fn test_dedup_umi_network() {
+        // AAAA is the abundant parent of AAAC, which is in turn
+        // a neighbor of AACC (but not of AAAA); GGGG is isolated.
+        let umis = vec![
+            (encode(b"AAAA"), 10),
+            (encode(b"AAAC"), 3),
+            (encode(b"AACC"), 3),
+            (encode(b"GGGG"), 1),
+        ];
+        let params = PugGraphParams::default();
+        let loose = PugGraphParams {
+            umi_count_ratio: 1.0,
+            ..params
+        };
+
+        let mut u = umis.clone();
+        assert_eq!(
+            dedup_umi_network(&mut u, UmiNetworkMethod::Cluster, &params, 4),
+            2
+        );
+
+        // AAAA covers AAAC, but AACC must be counted on its own
+        let mut u = umis.clone();
+        assert_eq!(
+            dedup_umi_network(&mut u, UmiNetworkMethod::Adjacency, &params, 4),
+            3
+        );
+
+        // 10 >= 2 * 3 - 1 but 3 < 2 * 3 - 1, so AACC is not absorbed
+        let mut u = umis.clone();
+        assert_eq!(
+            dedup_umi_network(&mut u, UmiNetworkMethod::Directional, &params, 4),
+            3
+        );
+
+        // with a looser threshold, the whole component collapses
+        let mut u = umis;
+        assert_eq!(
+            dedup_umi_network(&mut u, UmiNetworkMethod::Directional, &loose, 4),
+            2
+        );
+
+        // duplicate UMIs are merged before building the network
+        let mut u = vec![(encode(b"ACGT"), 1), (encode(b"ACGT"), 1)];
+        assert_eq!(
+            dedup_umi_network(&mut u, UmiNetworkMethod::Directional, &params, 4),
+            1
+        );
+        assert_eq!(u, vec![(encode(b"ACGT"), 2)]);
+
+        // as in UMI-tools, 2 UMIs seen once each are 1 molecule
+        // (1 >= 2 * 1 - 1), although neither dominates the other in a PUG
+        let mut u = vec![(encode(b"ACGT"), 1), (encode(b"ACGA"), 1)];
+        assert_eq!(
+            dedup_umi_network(&mut u, UmiNetworkMethod::Directional, &params, 4),
+            1
+        );
+    }
+
+    #[test]
+    fn test_dedup_umi_network_distance() {
+        // AAAA and AACC are 2 substitutions apart, and ACGT is a
+        // deletion of the first base of AACG (with a T appended)
+        let umis = vec![(encode(b"AAAA"), 10), (encode(b"AACC"), 1)];
+        let params = PugGraphParams::default();
+        let mut u = umis.clone();
+        assert_eq!(
+            dedup_umi_network(&mut u, UmiNetworkMethod::Directional, &params, 4),
+            2
+        );
+        let two = PugGraphParams {
+            max_umi_dist: 2,
+            ..params
+        };
+        let mut u = umis;
+        assert_eq!(
+            dedup_umi_network(&mut u, UmiNetworkMethod::Directional, &two, 4),
+            1
+        );
+
+        let umis = vec![(encode(b"AACG"), 10), (encode(b"ACGT"), 1)];
+        let mut u = umis.clone();
+        assert_eq!(
+            dedup_umi_network(&mut u, UmiNetworkMethod::Cluster, &params, 4),
+            2
+        );
+        let indels = PugGraphParams {
+            allow_indels: true,
+            ..params
+        };
+        let mut u = umis;
+        assert_eq!(
+            dedup_umi_network(&mut u, UmiNetworkMethod::Cluster, &indels, 4),
+            1
+        );
+    }
+}
@@ -0,0 +1,511 @@
+/*
+ * Copyright (c) 2020-2022 Rob Patro, Avi Srivastava, Hirak Sarkar, Dongze He, Mohsen Zakeri.
+ *
+ * This file is part of alevin-fry
+ * (see https://github.com/COMBINE-lab/alevin-fry).
+ *
+ * License: 3-clause BSD, see https://opensource.org/licenses/BSD-3-Clause
+ */
+
+use slog::{info, warn};
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::utils as afutils;
+
+// the size of each plot, and the margins around its plotting area
+const PLOT_WIDTH: f64 = 560.0;
+const PLOT_HEIGHT: f64 = 360.0;
+const MARGIN_LEFT: f64 = 70.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 30.0;
+const MARGIN_BOTTOM: f64 = 50.0;
+// the (maximum) number of points drawn in the barcode rank plot
+const MAX_RANK_POINTS: usize = 1000;
+const NUM_BINS: usize = 40;
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// A plot axis, mapping values in [`min`, `max`] (on a log10
+/// scale if `log`) onto the pixel range [`start`, `end`].
+struct Axis {
+    min: f64,
+    max: f64,
+    log: bool,
+    start: f64,
+    end: f64,
+}
+
+impl Axis {
+    fn new(min: f64, max: f64, log: bool, start: f64, end: f64) -> Self {
+        let (min, max) = if log {
+            (min.max(1.0).log10().floor(), max.max(1.0).log10().ceil())
+        } else {
+            (min, max)
+        };
+        let max = if max > min { max } else { min + 1.0 };
+        Self {
+            min,
+            max,
+            log,
+            start,
+            end,
+        }
+    }
+
+    fn pos(&self, v: f64) -> f64 {
+        let v = if self.log { v.max(1.0).log10() } else { v };
+        self.start + (v - self.min) / (self.max - self.min) * (self.end - self.start)
+    }
+
+    /// The values at which ticks are drawn, and their labels.
+    fn ticks(&self) -> Vec<(f64, String)> {
+        if self.log {
+            (self.min as i32..=self.max as i32)
+                .map(|e| (10f64.powi(e), format!("1e{}", e)))
+                .collect()
+        } else {
+            (0..=5)
+                .map(|i| {
+                    let v = self.min + (self.max - self.min) * i as f64 / 5.0;
+                    (
+                        v,
+                        format!("{:.3}", v)
+                            .trim_end_matches('0')
+                            .trim_end_matches('.')
+                            .to_string(),
+                    )
+                })
+                .collect()
+        }
+    }
+}
+
+/// Start an SVG plot with the given axes, title and axis labels,
+/// returning the SVG text (which must be completed with `</svg>`).
+fn plot_frame(x: &Axis, y: &Axis, title: &str, xlabel: &str, ylabel: &str) -> String {
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">
+<text x="{tx}" y="18" text-anchor="middle" class="title">{title}</text>
+<text x="{tx}" y="{xly}" text-anchor="middle">{xlabel}</text>
+<text x="16" y="{yly}" text-anchor="middle" transform="rotate(-90 16 {yly})">{ylabel}</text>
+<rect x="{l}" y="{t}" width="{pw}" height="{ph}" class="frame"/>
+"#,
+        w = PLOT_WIDTH,
+        h = PLOT_HEIGHT,
+        tx = PLOT_WIDTH / 2.0,
+        xly = PLOT_HEIGHT - 10.0,
+        yly = PLOT_HEIGHT / 2.0,
+        l = MARGIN_LEFT,
+        t = MARGIN_TOP,
+        pw = PLOT_WIDTH - MARGIN_LEFT - MARGIN_RIGHT,
+        ph = PLOT_HEIGHT - MARGIN_TOP - MARGIN_BOTTOM,
+        title = html_escape(title),
+        xlabel = html_escape(xlabel),
+        ylabel = html_escape(ylabel),
+    );
+    for (v, label) in x.ticks() {
+        let px = x.pos(v);
+        let _ = writeln!(
+            svg,
+            r#"<line x1="{px:.1}" y1="{y0}" x2="{px:.1}" y2="{y1}" class="tick"/><text x="{px:.1}" y="{ty}" text-anchor="middle">{label}</text>"#,
+            px = px,
+            y0 = y.start,
+            y1 = y.start + 5.0,
+            ty = y.start + 18.0,
+            label = label
+        );
+    }
+    for (v, label) in y.ticks() {
+        let py = y.pos(v);
+        let _ = writeln!(
+            svg,
+            r#"<line x1="{x0}" y1="{py:.1}" x2="{x1}" y2="{py:.1}" class="tick"/><text x="{tx}" y="{ty:.1}" text-anchor="end">{label}</text>"#,
+            py = py,
+            x0 = x.start - 5.0,
+            x1 = x.start,
+            tx = x.start - 8.0,
+            ty = py + 4.0,
+            label = label
+        );
+    }
+    svg
+}
+
+fn x_axis(min: f64, max: f64, log: bool) -> Axis {
+    Axis::new(min, max, log, MARGIN_LEFT, PLOT_WIDTH - MARGIN_RIGHT)
+}
+
+fn y_axis(min: f64, max: f64, log: bool) -> Axis {
+    Axis::new(min, max, log, PLOT_HEIGHT - MARGIN_BOTTOM, MARGIN_TOP)
+}
+
+/// The barcode rank plot (number of reads of each barcode against its
+/// rank, on log scales) of the frequencies `freqs` (sorted in decreasing
+/// order), marking the rank `cutoff` of the last permitted barcode.
+fn rank_plot_svg(freqs: &[u64], cutoff: usize) -> String {
+    let n = freqs.len();
+    let x = x_axis(1.0, n as f64, true);
+    let y = y_axis(1.0, freqs[0] as f64, true);
+    let mut svg = plot_frame(
+        &x,
+        &y,
+        "Barcode rank plot",
+        "barcode rank",
+        "number of reads",
+    );
+
+    // draw points at (roughly) log-spaced ranks
+    let mut ranks = Vec::<usize>::with_capacity(MAX_RANK_POINTS + 1);
+    let step = (n as f64).ln() / MAX_RANK_POINTS as f64;
+    for i in 0..=MAX_RANK_POINTS {
+        let r = ((i as f64 * step).exp().round() as usize).clamp(1, n);
+        if ranks.last() != Some(&r) {
+            ranks.push(r);
+        }
+    }
+    let points = ranks
+        .iter()
+        .map(|r| format!("{:.1},{:.1}", x.pos(*r as f64), y.pos(freqs[r - 1] as f64)))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let _ = writeln!(svg, r#"<polyline points="{}" class="line"/>"#, points);
+
+    if cutoff > 0 && cutoff <= n {
+        let px = x.pos(cutoff as f64);
+        let _ = writeln!(
+            svg,
+            r#"<line x1="{px:.1}" y1="{y0}" x2="{px:.1}" y2="{y1}" class="cutoff"><title>cutoff: rank {c}, {f} reads</title></line>"#,
+            px = px,
+            y0 = y.start,
+            y1 = y.end,
+            c = cutoff,
+            f = freqs[cutoff - 1]
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Count `values` in `NUM_BINS` equal-width bins (on a log10 scale if
+/// `log`), returning the (scaled) lower edge of the first bin, the bin
+/// width and the count in each bin.
+fn bin_values(values: &[f64], log: bool) -> (f64, f64, Vec<usize>) {
+    let tv = |v: f64| if log { v.max(1.0).log10() } else { v };
+    let lo = values.iter().cloned().map(tv).fold(f64::INFINITY, f64::min);
+    let hi = values
+        .iter()
+        .cloned()
+        .map(tv)
+        .fold(f64::NEG_INFINITY, f64::max);
+    // if all of the values are equal, they all fall in the first bin
+    let width = if hi > lo {
+        (hi - lo) / NUM_BINS as f64
+    } else {
+        lo.abs().max(1.0) / NUM_BINS as f64
+    };
+    let mut bins = vec![0usize; NUM_BINS];
+    for v in values {
+        let b = (((tv(*v) - lo) / width) as usize).min(NUM_BINS - 1);
+        bins[b] += 1;
+    }
+    (lo, width, bins)
+}
+
+/// A histogram of `values` (binned on a log10 scale if `log`).
+fn histogram_svg(values: &[f64], title: &str, xlabel: &str, log: bool) -> String {
+    let (lo, width, bins) = bin_values(values, log);
+    let bin_value = |b: f64| {
+        let v = lo + b * width;
+        if log {
+            10f64.powf(v)
+        } else {
+            v
+        }
+    };
+
+    let x = x_axis(bin_value(0.0), bin_value(NUM_BINS as f64), log);
+    let y = y_axis(0.0, *bins.iter().max().unwrap_or(&1) as f64, false);
+    let mut svg = plot_frame(&x, &y, title, xlabel, "number of cells");
+    for (i, c) in bins.iter().enumerate() {
+        let (x0, x1) = (x.pos(bin_value(i as f64)), x.pos(bin_value(i as f64 + 1.0)));
+        let y1 = y.pos(*c as f64);
+        let _ = writeln!(
+            svg,
+            r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" class="bar"><title>{:.3} - {:.3}: {} cells</title></rect>"#,
+            x0,
+            y1,
+            (x1 - x0).max(0.5),
+            y.start - y1,
+            bin_value(i as f64),
+            bin_value(i as f64 + 1.0),
+            c
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut v = values.to_vec();
+    v.sort_by(|a, b| a.total_cmp(b));
+    match v.len() {
+        0 => f64::NAN,
+        n if n % 2 == 1 => v[n / 2],
+        n => (v[n / 2 - 1] + v[n / 2]) / 2.0,
+    }
+}
+
+/// The per-cell statistics of a featureDump.txt file that are reported.
+struct CellStats {
+    mapping_rate: Vec<f64>,
+    num_umis: Vec<f64>,
+    dedup_rate: Vec<f64>,
+}
+
+fn read_feature_dump(path: &Path) -> Result<CellStats, Box<dyn std::error::Error>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(File::open(path)?);
+    let hdr = rdr.headers()?.clone();
+    let col = |name: &str| {
+        hdr.iter()
+            .position(|h| h == name)
+            .ok_or_else(|| format!("{:?} has no {} column", path, name))
+    };
+    let (mr, umi, dr) = (
+        col("MappingRate")?,
+        col("DeduplicatedReads")?,
+        col("DedupRate")?,
+    );
+    let mut stats = CellStats {
+        mapping_rate: Vec::new(),
+        num_umis: Vec::new(),
+        dedup_rate: Vec::new(),
+    };
+    for rec in rdr.records() {
+        let rec = rec?;
+        stats.mapping_rate.push(rec[mr].parse()?);
+        stats.num_umis.push(rec[umi].parse()?);
+        stats.dedup_rate.push(rec[dr].parse()?);
+    }
+    Ok(stats)
+}
+
+/// Write a self-contained HTML report on the results of a run of the
+/// pipeline: the barcode rank plot (from the output directory of
+/// generate-permit-list and collate, `permit_dir`), the distributions of
+/// per-cell statistics (from the output directory of quant, `quant_dir`),
+/// and the JSON metadata written by each step.
+pub fn write_report(
+    permit_dir: Option<&str>,
+    quant_dir: Option<&str>,
+    output: &str,
+    log: &slog::Logger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut summary = Vec::<(String, String)>::new();
+    let mut plots = Vec::<String>::new();
+    let mut metadata = Vec::<(String, String)>::new();
+
+    let mut add_metadata = |dir: &Path, fname: &str| {
+        let path = dir.join(fname);
+        if let Ok(f) = File::open(&path) {
+            match serde_json::from_reader::<_, serde_json::Value>(f) {
+                Ok(v) => metadata.push((
+                    fname.to_string(),
+                    serde_json::to_string_pretty(&v).unwrap_or_default(),
+                )),
+                Err(e) => warn!(log, "could not parse {:?}: {}", path, e),
+            }
+        }
+    };
+
+    if let Some(pdir) = permit_dir {
+        let pdir = Path::new(pdir);
+        let (_, _, permit_freq) = afutils::read_permit_list_freq(&pdir.join("permit_freq.bin"))?;
+        let num_permitted = permit_freq.len();
+        // the frequencies of all barcodes are only kept for filtered permit lists
+        let all_freq_path = pdir.join("all_freq.bin");
+        let mut freqs: Vec<u64> = if all_freq_path.exists() {
+            afutils::read_permit_list_freq(&all_freq_path)?
+                .2
+                .into_values()
+                .collect()
+        } else {
+            permit_freq.into_values().collect()
+        };
+        freqs.sort_unstable_by(|a, b| b.cmp(a));
+        summary.push(("permitted barcodes".into(), num_permitted.to_string()));
+        summary.push(("observed barcodes".into(), freqs.len().to_string()));
+        if !freqs.is_empty() {
+            plots.push(rank_plot_svg(&freqs, num_permitted));
+        }
+        add_metadata(pdir, "generate_permit_list.json");
+        add_metadata(pdir, "collate.json");
+    }
+
+    if let Some(qdir) = quant_dir {
+        let qdir = Path::new(qdir);
+        let stats = read_feature_dump(&qdir.join("featureDump.txt"))?;
+        summary.push(("quantified cells".into(), stats.num_umis.len().to_string()));
+        if !stats.num_umis.is_empty() {
+            summary.push((
+                "median UMIs per cell".into(),
+                format!("{}", median(&stats.num_umis)),
+            ));
+            summary.push((
+                "median mapping rate".into(),
+                format!("{:.4}", median(&stats.mapping_rate)),
+            ));
+            summary.push((
+                "median dedup rate".into(),
+                format!("{:.4}", median(&stats.dedup_rate)),
+            ));
+            plots.push(histogram_svg(
+                &stats.num_umis,
+                "UMIs per cell",
+                "number of UMIs",
+                true,
+            ));
+            plots.push(histogram_svg(
+                &stats.mapping_rate,
+                "Mapping rate",
+                "fraction of reads mapped",
+                false,
+            ));
+            plots.push(histogram_svg(
+                &stats.dedup_rate,
+                "Deduplication rate",
+                "UMIs / mapped reads",
+                false,
+            ));
+        }
+        add_metadata(qdir, "quant.json");
+    }
+
+    let mut html = String::new();
+    html.push_str(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>alevin-fry report</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+svg text { font-size: 12px; }
+svg text.title { font-size: 14px; font-weight: bold; }
+.frame { fill: none; stroke: #444; }
+.tick { stroke: #444; }
+.line { fill: none; stroke: #1f77b4; stroke-width: 2; }
+.cutoff { stroke: #d62728; stroke-width: 2; stroke-dasharray: 6 4; }
+.bar { fill: #1f77b4; }
+.bar:hover { fill: #ff7f0e; }
+table { border-collapse: collapse; }
+td { padding: 2px 12px; border-bottom: 1px solid #ddd; }
+pre { background: #f6f6f6; padding: 1em; overflow-x: auto; }
+</style>
+</head>
+<body>
+<h1>alevin-fry report</h1>
+<h2>Summary</h2>
+<table>
+"#,
+    );
+    for (k, v) in &summary {
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            html_escape(k),
+            html_escape(v)
+        );
+    }
+    html.push_str("</table>\n<h2>Plots</h2>\n");
+    for p in &plots {
+        html.push_str(p);
+    }
+    html.push_str("<h2>Metadata</h2>\n");
+    for (fname, contents) in &metadata {
+        let _ = writeln!(
+            html,
+            "<details><summary>{}</summary><pre>{}</pre></details>",
+            html_escape(fname),
+            html_escape(contents)
+        );
+    }
+    html.push_str("</body>\n</html>\n");
+
+    let mut out = File::create(output)?;
+    out.write_all(html.as_bytes())?;
+    info!(
+        log,
+        "wrote a report with {} plots to {}",
+        plots.len(),
+        output
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_of_odd_even_and_empty_inputs() {
+        assert_eq!(median(&[3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&[4.0, 1.0, 3.0, 2.0]), 2.5);
+        assert!(median(&[]).is_nan());
+    }
+
+    #[test]
+    fn bin_values_spans_the_range() {
+        let values: Vec<f64> = (0..=40).map(|v| v as f64).collect();
+        let (lo, width, bins) = bin_values(&values, false);
+        assert_eq!(lo, 0.0);
+        assert_eq!(width, 1.0);
+        assert_eq!(bins.len(), NUM_BINS);
+        // the maximum falls in the last bin, rather than one past it
+        assert_eq!(bins[NUM_BINS - 1], 2);
+        assert_eq!(bins.iter().sum::<usize>(), values.len());
+
+        // on a log scale, values below 1 are counted with those equal to 1
+        let (lo, _, bins) = bin_values(&[0.0, 1.0, 10.0, 100.0], true);
+        assert_eq!(lo, 0.0);
+        assert_eq!(bins[0], 2);
+        assert_eq!(bins[NUM_BINS / 2], 1);
+        assert_eq!(bins[NUM_BINS - 1], 1);
+
+        // equal values all fall in the first bin
+        let (_, width, bins) = bin_values(&[5.0; 3], false);
+        assert!(width > 0.0);
+        assert_eq!(bins[0], 3);
+    }
+
+    #[test]
+    fn read_feature_dump_reads_the_reported_columns() {
+        let path = std::env::temp_dir().join(format!("af_report_{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "CB\tCorrectedReads\tMappedReads\tDeduplicatedReads\tMappingRate\tDedupRate\n\
+             AAAC\t100\t90\t30\t0.9\t0.6667\n\
+             TTTG\t50\t25\t20\t0.5\t0.2\n",
+        )
+        .unwrap();
+        let stats = read_feature_dump(&path).unwrap();
+        assert_eq!(stats.mapping_rate, vec![0.9, 0.5]);
+        assert_eq!(stats.num_umis, vec![30.0, 20.0]);
+        assert_eq!(stats.dedup_rate, vec![0.6667, 0.2]);
+
+        // a missing column is an error
+        std::fs::write(&path, "CB\tMappingRate\nAAAC\t0.9\n").unwrap();
+        assert!(read_feature_dump(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
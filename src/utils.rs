@@ -1,13 +1,15 @@
 use crate::constants as afconst;
+use crate::em::FeatureBlocks;
 use crate::eq_class::IndexedEqList;
 use bstr::io::BufReadExt;
 use core::fmt;
 use libradicl::utils::SPLICE_MASK_U32;
 use needletail::bitkmer::*;
+use slog::{crit, info, warn};
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::str::FromStr;
 use thiserror::Error;
 
@@ -37,6 +39,60 @@ pub(super) fn count_diff_2_bit_packed(a: u64, b: u64) -> usize {
     two_bit_diffs.count_ones() as usize
 }
 
+// the longest sequence that fits in a 2-bit packed u64
+const MAX_PACKED_LEN: usize = 32;
+
+/// Compute the edit (Levenshtein) distance between 2 2-bit packed
+/// sequences of length `len`, where the first base of the sequence is
+/// stored in the highest order bits (as produced by `BitNuclKmer`).
+/// Since UMIs have a fixed length, an insertion or deletion shifts the
+/// rest of the sequence and pushes a base off of (or pulls an unknown
+/// base onto) the end of the read; overhanging bases at the end of
+/// either sequence are therefore not charged.  The computation stops early
+/// once the distance is known to exceed `max_dist`, in which case some
+/// value > `max_dist` is returned.
+pub(super) fn edit_dist_2_bit_packed(a: u64, b: u64, len: usize, max_dist: usize) -> usize {
+    debug_assert!(len <= MAX_PACKED_LEN);
+    let base_at = |x: u64, i: usize| -> u64 { (x >> (2 * (len - 1 - i))) & 0x3 };
+
+    // the rows of the DP matrix live on the stack, as this
+    // is called for many pairs of UMIs
+    let mut prev = [0usize; MAX_PACKED_LEN + 1];
+    let mut curr = [0usize; MAX_PACKED_LEN + 1];
+    for (j, d) in prev.iter_mut().enumerate().take(len + 1) {
+        *d = j;
+    }
+    // best distance allowing the tail of `b` to overhang
+    let mut best = usize::MAX;
+    let free_from = len.saturating_sub(max_dist);
+
+    for i in 1..=len {
+        curr[0] = i;
+        let ai = base_at(a, i - 1);
+        let mut row_min = curr[0];
+        for j in 1..=len {
+            let sub = prev[j - 1] + if ai == base_at(b, j - 1) { 0 } else { 1 };
+            curr[j] = sub.min(prev[j] + 1).min(curr[j - 1] + 1);
+            row_min = row_min.min(curr[j]);
+        }
+        if i >= free_from {
+            best = best.min(curr[len]);
+        }
+        // no later cell can get back within range
+        if row_min > max_dist {
+            return best.min(row_min);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    // allow the tail of `a` to overhang
+    for (j, d) in prev.iter().enumerate().take(len + 1).skip(free_from) {
+        if j > 0 {
+            best = best.min(*d);
+        }
+    }
+    best
+}
+
 #[inline(always)]
 fn unspliced_of(gid: u32) -> u32 {
     gid + 1
@@ -72,7 +128,10 @@ pub fn is_unspliced(gid: u32) -> bool {
     !is_spliced(gid)
 }
 
-/// Write the permit_freq.bin and all_freq.bin files
+/// Write the permit_freq.bin and all_freq.bin files. These consist of
+/// the file format version (`PERMIT_FILE_VER`) and the barcode length,
+/// each as a little-endian u64, followed by the bincode serialization of
+/// the map from each (2-bit encoded) barcode to its number of reads.
 pub fn write_permit_list_freq(
     o_path: &std::path::Path,
     bclen: u16,
@@ -83,12 +142,10 @@ pub fn write_permit_list_freq(
 
     {
         // the first u64 represents file format version.
-        writer
-            .write_all(&afconst::PERMIT_FILE_VER.to_le_bytes())
-            .unwrap();
+        writer.write_all(&afconst::PERMIT_FILE_VER.to_le_bytes())?;
 
         // the second u64 represents barcode length
-        writer.write_all(&(u64::from(bclen)).to_le_bytes()).unwrap();
+        writer.write_all(&(u64::from(bclen)).to_le_bytes())?;
 
         // the rest records the permitted barcode:freq hashmap
         bincode::serialize_into(&mut writer, &permit_freq_map)?;
@@ -96,6 +153,34 @@ pub fn write_permit_list_freq(
     Ok(())
 }
 
+/// The contents of a permit_freq.bin or all_freq.bin file: the file
+/// format version, the barcode length and the barcode frequencies.
+pub type PermitListFreq = (u64, u16, HashMap<u64, u64>);
+
+/// Read a permit_freq.bin or all_freq.bin file written by
+/// [`write_permit_list_freq`].
+pub fn read_permit_list_freq(
+    i_path: &std::path::Path,
+) -> Result<PermitListFreq, Box<dyn std::error::Error>> {
+    let mut rdr = BufReader::new(File::open(i_path)?);
+    let mut rbuf = [0u8; 8];
+    rdr.read_exact(&mut rbuf)?;
+    let version = u64::from_le_bytes(rbuf);
+    if version > afconst::PERMIT_FILE_VER {
+        return Err(format!(
+            "{:?} has version {}, but this version of alevin-fry can only read versions up to {}",
+            i_path,
+            version,
+            afconst::PERMIT_FILE_VER
+        )
+        .into());
+    }
+    rdr.read_exact(&mut rbuf)?;
+    let bclen = u64::from_le_bytes(rbuf) as u16;
+    let freq: HashMap<u64, u64> = bincode::deserialize_from(rdr)?;
+    Ok((version, bclen, freq))
+}
+
 /// Parse a 3 column tsv of the format
 /// transcript_name gene_name   status
 /// where status is one of S or U each gene will be allocated both a spliced and
@@ -106,7 +191,7 @@ pub fn write_permit_list_freq(
 /// and
 /// (i+1) will be the id for the unspliced version of gene A
 fn parse_tg_spliced_unspliced(
-    rdr: &mut csv::Reader<File>,
+    entries: &[TgEntry],
     ref_count: usize,
     rname_to_id: &HashMap<String, u32, ahash::RandomState>,
     gene_names: &mut Vec<String>,
@@ -118,9 +203,6 @@ fn parse_tg_spliced_unspliced(
     // vector.
     let mut tid_to_gid = vec![u32::MAX; ref_count];
 
-    // Record will be transcript, gene, splicing status
-    type TsvRec = (String, String, String);
-
     // the transcripts for which we've found a gene mapping
     let mut found = 0usize;
 
@@ -128,33 +210,28 @@ fn parse_tg_spliced_unspliced(
     // the even ids are for spliced txps, the odd ids are for unspliced txps
     // for convenience, we define a gid helper, next_gid
     let mut next_gid = 0u32;
-    // apparently the "header" (first row) will be included
-    // in the iterator returned by `deserialize` anyway
-    /*let hdr = rdr.headers()?;
-    let hdr_vec : Vec<Result<TsvRec,csv::Error>> = vec![hdr.deserialize(None)];
-    */
-    for result in rdr.deserialize() {
-        let record: TsvRec = result?;
+    for (txp, gene, status, _) in entries {
+        let status = status.as_deref().unwrap_or("");
         // first, get the first id for this gene
-        let gene_id = *gene_name_to_id.entry(record.1.clone()).or_insert_with(|| {
+        let gene_id = *gene_name_to_id.entry(gene.clone()).or_insert_with(|| {
             // as we need to return the current next_gid if we run this code
             // we add by two and then return current gene id.
             let cur_gid = next_gid;
             next_gid += 2;
             // we haven't added this gene name already,
             // we append it now to the list of gene names.
-            gene_names.push(record.1.clone());
+            gene_names.push(gene.clone());
             cur_gid
         });
 
         // get the transcript id
-        if let Some(transcript_id) = rname_to_id.get(&record.0) {
+        if let Some(transcript_id) = rname_to_id.get(txp) {
             found += 1;
-            if record.2.eq_ignore_ascii_case("U") {
+            if status.eq_ignore_ascii_case("U") {
                 // This is an unspliced txp
                 // we link it to the second gid of this gene
                 tid_to_gid[*transcript_id as usize] = unspliced_of(gene_id);
-            } else if record.2.eq_ignore_ascii_case("S") {
+            } else if status.eq_ignore_ascii_case("S") {
                 // This is a spliced txp, we link it to the
                 // first gid of this gene
                 tid_to_gid[*transcript_id as usize] = spliced_of(gene_id);
@@ -173,7 +250,7 @@ fn parse_tg_spliced_unspliced(
 }
 
 fn parse_tg_spliced(
-    rdr: &mut csv::Reader<File>,
+    entries: &[TgEntry],
     ref_count: usize,
     rname_to_id: &HashMap<String, u32, ahash::RandomState>,
     gene_names: &mut Vec<String>,
@@ -184,46 +261,466 @@ fn parse_tg_spliced(
     // and the gene name can be looked up from the id in the gene_names
     // vector.
     let mut tid_to_gid = vec![u32::MAX; ref_count];
-    // now read in the transcript to gene map
-    type TsvRec = (String, String);
     // now, map each transcript index to it's corresponding gene index
     let mut found = 0usize;
-    // apparently the "header" (first row) will be included
-    // in the iterator returned by `deserialize` anyway
-    /*let hdr = rdr.headers()?;
-    let hdr_vec : Vec<Result<TsvRec,csv::Error>> = vec![hdr.deserialize(None)];
-    */
-    for result in rdr.deserialize() {
-        match result {
-            Ok(record_in) => {
-                let record: TsvRec = record_in;
-                //let record: TSVRec = result?;
-                // first, get the id for this gene
-                let next_id = gene_name_to_id.len() as u32;
-                let gene_id = *gene_name_to_id.entry(record.1.clone()).or_insert(next_id);
-                // if we haven't added this gene name already, then
-                // append it now to the list of gene names.
-                if gene_id == next_id {
-                    gene_names.push(record.1.clone());
+    for (txp, gene, _, _) in entries {
+        // first, get the id for this gene
+        let next_id = gene_name_to_id.len() as u32;
+        let gene_id = *gene_name_to_id.entry(gene.clone()).or_insert(next_id);
+        // if we haven't added this gene name already, then
+        // append it now to the list of gene names.
+        if gene_id == next_id {
+            gene_names.push(gene.clone());
+        }
+        // get the transcript id
+        if let Some(transcript_id) = rname_to_id.get(txp) {
+            found += 1;
+            tid_to_gid[*transcript_id as usize] = gene_id;
+        }
+    }
+
+    assert_eq!(
+        found, ref_count,
+        "The tg-map must contain a gene mapping for all transcripts in the header"
+    );
+
+    Ok((tid_to_gid, false))
+}
+
+/// The name and biotype of a gene, as recorded in a GTF / GFF3 annotation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GeneAnnotation {
+    pub name: Option<String>,
+    pub biotype: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum AnnotationFormat {
+    Gtf,
+    Gff3,
+}
+
+/// Determine from its extension (ignoring a trailing `.gz`)
+/// if `path` is a GTF or GFF3 annotation file.
+pub(crate) fn annotation_format(path: &str) -> Option<AnnotationFormat> {
+    let p = path.to_lowercase();
+    let p = p.strip_suffix(".gz").unwrap_or(&p);
+    if p.ends_with(".gtf") {
+        Some(AnnotationFormat::Gtf)
+    } else if p.ends_with(".gff") || p.ends_with(".gff3") {
+        Some(AnnotationFormat::Gff3)
+    } else {
+        None
+    }
+}
+
+/// Parse the attribute column of a GTF record
+/// (e.g. `gene_id "G1"; transcript_id "T1";`).
+fn parse_gtf_attributes(attrs: &str) -> Vec<(&str, &str)> {
+    attrs
+        .split(';')
+        .filter_map(|kv| {
+            let (k, v) = kv.trim().split_once(' ')?;
+            Some((k, v.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+/// Parse the attribute column of a GFF3 record
+/// (e.g. `ID=transcript:T1;Parent=gene:G1`).
+fn parse_gff3_attributes(attrs: &str) -> Vec<(&str, &str)> {
+    attrs
+        .split(';')
+        .filter_map(|kv| {
+            let (k, v) = kv.trim().split_once('=')?;
+            Some((k, v.trim()))
+        })
+        .collect()
+}
+
+fn get_attribute<'a>(attrs: &[(&str, &'a str)], keys: &[&str]) -> Option<&'a str> {
+    keys.iter()
+        .find_map(|k| attrs.iter().find(|(ak, _)| ak == k).map(|(_, v)| *v))
+}
+
+/// Strip the type prefix that (e.g. Ensembl) GFF3 files put on IDs.
+fn strip_gff3_id_prefix(id: &str) -> &str {
+    id.strip_prefix("gene:")
+        .or_else(|| id.strip_prefix("transcript:"))
+        .unwrap_or(id)
+}
+
+// the transcript -> gene pairs (in the order they are encountered)
+// and the annotation of each gene
+pub(crate) type AnnotationRecords = (
+    Vec<(String, String)>,
+    HashMap<String, GeneAnnotation, ahash::RandomState>,
+);
+
+/// Read the transcript -> gene mapping, and the gene names and biotypes
+/// from a GTF or GFF3 file (that may be gzip compressed).  In a GTF file,
+/// the transcript and gene of a record are given by the `transcript_id`
+/// and `gene_id` attributes.  In a GFF3 file, the transcripts are the
+/// features whose `Parent` is a top-level feature (i.e. a gene).
+pub(crate) fn read_annotation_records(
+    path: &str,
+    fmt: AnnotationFormat,
+) -> Result<AnnotationRecords, Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn BufRead> = if path.to_lowercase().ends_with(".gz") {
+        Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    };
+
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut annots = HashMap::<String, GeneAnnotation, ahash::RandomState>::with_hasher(s);
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut seen_txps = HashSet::<String, ahash::RandomState>::with_hasher(s);
+    let mut txp_gene = Vec::<(String, String)>::new();
+
+    // for GFF3, the (ID, Parent, transcript_id) of every feature
+    // with an ID; these are resolved once the whole file is read
+    let mut gff_features = Vec::<(String, Option<String>, Option<String>)>::new();
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut gff_gene_ids = HashMap::<String, String, ahash::RandomState>::with_hasher(s);
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            return Err(format!("malformed annotation record: {}", line).into());
+        }
+        let feature = fields[2];
+        match fmt {
+            AnnotationFormat::Gtf => {
+                let attrs = parse_gtf_attributes(fields[8]);
+                let gene_id = match get_attribute(&attrs, &["gene_id"]) {
+                    Some(g) => g,
+                    None => continue,
+                };
+                let annot = annots.entry(gene_id.to_string()).or_default();
+                if annot.name.is_none() {
+                    annot.name = get_attribute(&attrs, &["gene_name"]).map(String::from);
                 }
-                // get the transcript id
-                if let Some(transcript_id) = rname_to_id.get(&record.0) {
-                    found += 1;
-                    tid_to_gid[*transcript_id as usize] = gene_id;
+                if annot.biotype.is_none() {
+                    annot.biotype =
+                        get_attribute(&attrs, &["gene_type", "gene_biotype"]).map(String::from);
+                }
+                if feature == "transcript" || feature == "exon" {
+                    if let Some(t) = get_attribute(&attrs, &["transcript_id"]) {
+                        if seen_txps.insert(t.to_string()) {
+                            txp_gene.push((t.to_string(), gene_id.to_string()));
+                        }
+                    }
                 }
             }
-            Err(e) => {
-                /*
-                crit!(
-                log,
-                "Encountered error [{}] when reading the transcript-to-gene map. Please make sure the transcript-to-gene mapping is a 2 column, tab separated file.",
-                e
-                );
-                */
-                return Err(Box::new(e));
+            AnnotationFormat::Gff3 => {
+                let attrs = parse_gff3_attributes(fields[8]);
+                let id = match get_attribute(&attrs, &["ID"]) {
+                    Some(i) => i,
+                    None => continue,
+                };
+                let parent = get_attribute(&attrs, &["Parent"]);
+                if parent.is_none() {
+                    let gene_id = get_attribute(&attrs, &["gene_id"])
+                        .unwrap_or_else(|| strip_gff3_id_prefix(id));
+                    gff_gene_ids.insert(id.to_string(), gene_id.to_string());
+                    annots.insert(
+                        gene_id.to_string(),
+                        GeneAnnotation {
+                            name: get_attribute(&attrs, &["Name", "gene_name"]).map(String::from),
+                            biotype: get_attribute(
+                                &attrs,
+                                &["biotype", "gene_type", "gene_biotype"],
+                            )
+                            .map(String::from),
+                        },
+                    );
+                }
+                gff_features.push((
+                    id.to_string(),
+                    parent.map(String::from),
+                    get_attribute(&attrs, &["transcript_id"]).map(String::from),
+                ));
+            }
+        }
+    }
+
+    if fmt == AnnotationFormat::Gff3 {
+        for (id, parent, txp_id) in gff_features {
+            if let Some(gene_id) = parent.and_then(|p| gff_gene_ids.get(&p)) {
+                let t = txp_id.unwrap_or_else(|| strip_gff3_id_prefix(&id).to_string());
+                if seen_txps.insert(t.clone()) {
+                    txp_gene.push((t, gene_id.clone()));
+                }
+            }
+        }
+    }
+
+    if txp_gene.is_empty() {
+        return Err(format!("found no transcripts in the annotation file {}", path).into());
+    }
+    Ok((txp_gene, annots))
+}
+
+/// Build the transcript -> gene mapping from a GTF or GFF3 annotation.
+/// Reference sequences that are not transcripts of the annotation, but
+/// whose name is that of an annotated gene or transcript followed by `-I`
+/// or `-U` (as for the intronic sequences of a splici index) are taken
+/// to be unspliced sequences of the corresponding gene; if there are any,
+/// genes are assigned spliced and unspliced ids as in the 3 column format.
+/// The annotation of each gene is appended to `gene_annots`, in the same
+/// order as `gene_names`.
+#[allow(clippy::too_many_arguments)]
+fn parse_tg_annotation(
+    path: &str,
+    records: AnnotationRecords,
+    ref_count: usize,
+    ref_names: &[String],
+    rname_to_id: &HashMap<String, u32, ahash::RandomState>,
+    gene_names: &mut Vec<String>,
+    gene_name_to_id: &mut HashMap<String, u32, ahash::RandomState>,
+    gene_annots: &mut Vec<GeneAnnotation>,
+) -> Result<(Vec<u32>, bool), Box<dyn std::error::Error>> {
+    let (txp_gene, mut annots) = records;
+
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut txp_to_gene = HashMap::<&str, &str, ahash::RandomState>::with_hasher(s);
+    for (t, g) in txp_gene.iter() {
+        txp_to_gene.insert(t.as_str(), g.as_str());
+    }
+
+    // find the gene of each reference sequence that is
+    // not an annotated transcript.
+    let mut unspliced_refs = Vec::<(u32, String)>::new();
+    for rn in ref_names.iter() {
+        if txp_to_gene.contains_key(rn.as_str()) {
+            continue;
+        }
+        let gene = rn
+            .strip_suffix("-I")
+            .or_else(|| rn.strip_suffix("-U"))
+            .and_then(|base| {
+                if annots.contains_key(base) {
+                    Some(base.to_string())
+                } else {
+                    txp_to_gene.get(base).map(|g| g.to_string())
+                }
+            });
+        match (gene, rname_to_id.get(rn)) {
+            (Some(g), Some(tid)) => unspliced_refs.push((*tid, g)),
+            _ => {
+                return Err(format!(
+                    "the reference sequence {} is not a transcript of the annotation {}",
+                    rn, path
+                )
+                .into());
             }
         }
     }
+    let with_unspliced = !unspliced_refs.is_empty();
+
+    let mut tid_to_gid = vec![u32::MAX; ref_count];
+    let mut gene_id_of = |g: &str, gene_names: &mut Vec<String>| -> u32 {
+        let next_id = if with_unspliced {
+            2 * gene_name_to_id.len() as u32
+        } else {
+            gene_name_to_id.len() as u32
+        };
+        let gid = *gene_name_to_id.entry(g.to_string()).or_insert(next_id);
+        if gid == next_id {
+            gene_names.push(g.to_string());
+            gene_annots.push(annots.remove(g).unwrap_or_default());
+        }
+        gid
+    };
+    for (t, g) in txp_gene.iter() {
+        let gid = gene_id_of(g, gene_names);
+        if let Some(tid) = rname_to_id.get(t) {
+            tid_to_gid[*tid as usize] = spliced_of(gid);
+        }
+    }
+    for (tid, g) in unspliced_refs {
+        let gid = gene_id_of(&g, gene_names);
+        tid_to_gid[tid as usize] = unspliced_of(gid);
+    }
+
+    Ok((tid_to_gid, with_unspliced))
+}
+
+/// The categories of features (e.g. exonic, intronic, mitochondrial)
+/// given by the 4th column of a transcript-to-gene map.  Each gene is
+/// assigned one id per category; the ids of a gene are consecutive, and
+/// the id of category `c` of the gene with index `g` is `g * len() + c`.
+/// The output has a block of `num_genes` columns for each category,
+/// followed by a block for the molecules that are ambiguous between
+/// the categories of a gene; this is the layout of USA mode (see
+/// [`FeatureBlocks`]), whose spliced and unspliced status are 2 categories.
+/// As in USA mode, the EM pools the abundances of the categories of a
+/// gene, only cr-like and cr-like-em resolution are supported, and
+/// uncertainty can be estimated with Gibbs sampling but not bootstrapping.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeatureCategories {
+    pub names: Vec<String>,
+}
+
+impl FeatureCategories {
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The number of output columns for `num_genes` genes.
+    pub fn num_columns(&self, num_genes: usize) -> usize {
+        (self.len() + 1) * num_genes
+    }
+
+    /// The layout of the output columns for `num_genes` genes.
+    pub fn blocks(&self, num_genes: usize) -> FeatureBlocks {
+        FeatureBlocks {
+            num_genes,
+            num_categories: self.len(),
+        }
+    }
+
+    /// The names of the output columns; the column of category `c`
+    /// of gene `g` is named `g-c`, and the ambiguous column `g-ambiguous`.
+    pub fn column_names(&self, gene_names: &[String]) -> Vec<String> {
+        let mut cols = Vec::with_capacity(self.num_columns(gene_names.len()));
+        for c in self.names.iter() {
+            cols.extend(gene_names.iter().map(|g| format!("{}-{}", g, c)));
+        }
+        cols.extend(gene_names.iter().map(|g| format!("{}-ambiguous", g)));
+        cols
+    }
+
+    /// Map the (sorted) feature ids of an equivalence class label to the
+    /// output columns; the features of a gene that appear in more than one
+    /// category are mapped to the ambiguous column of the gene.
+    pub fn label_columns(&self, labels: &[u32], num_genes: usize, cols: &mut Vec<u32>) {
+        let n = self.len() as u32;
+        let ng = num_genes as u32;
+        cols.clear();
+        let mut i = 0;
+        while i < labels.len() {
+            let g = labels[i] / n;
+            let mut j = i + 1;
+            while j < labels.len() && labels[j] / n == g {
+                j += 1;
+            }
+            if j - i == 1 {
+                cols.push((labels[i] % n) * ng + g);
+            } else {
+                cols.push(n * ng + g);
+            }
+            i = j;
+        }
+    }
+}
+
+/// The equivalence classes (labels and counts) of `gene_eqc` sorted by
+/// their labels, so that they are visited in an order that doesn't depend
+/// on the hash table (and hence on what was stored in it before), which
+/// keeps floating point results reproducible.
+pub fn sorted_eq_classes<S>(gene_eqc: &HashMap<Vec<u32>, u32, S>) -> Vec<(&Vec<u32>, &u32)> {
+    let mut eqcs: Vec<(&Vec<u32>, &u32)> = gene_eqc.iter().collect();
+    eqcs.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    eqcs
+}
+
+/// Extracts UMI counts from the `gene_eqc` HashMap when features
+/// have categories.  Only molecules compatible with a single gene are
+/// counted; they are assigned to the category column of the gene, or to
+/// its ambiguous column if they are compatible with more than one of
+/// the gene's categories.
+pub fn extract_category_counts(
+    gene_eqc: &HashMap<Vec<u32>, u32, ahash::RandomState>,
+    categories: &FeatureCategories,
+    num_genes: usize,
+) -> Vec<f32> {
+    let mut counts = vec![0_f32; categories.num_columns(num_genes)];
+    let mut cols = Vec::<u32>::with_capacity(16);
+    for (labels, count) in sorted_eq_classes(gene_eqc) {
+        categories.label_columns(labels, num_genes, &mut cols);
+        if let [c] = cols[..] {
+            counts[c as usize] += *count as f32;
+        }
+    }
+    counts
+}
+
+/// Extracts an `IndexedEqList` and equivalence class ID / count
+/// vector from the `gene_eqc` HashMap when features have categories,
+/// so that multi-gene UMIs can be resolved with the EM.  The labels
+/// of `idx_eq_list` are over the output columns.
+pub fn extract_category_eqmap(
+    gene_eqc: &HashMap<Vec<u32>, u32, ahash::RandomState>,
+    categories: &FeatureCategories,
+    num_genes: usize,
+    idx_eq_list: &mut IndexedEqList,
+    eq_id_count: &mut Vec<(u32, u32)>,
+) {
+    idx_eq_list.clear();
+    eq_id_count.clear();
+    let mut cols = Vec::<u32>::with_capacity(16);
+    for (ctr, (labels, count)) in sorted_eq_classes(gene_eqc).into_iter().enumerate() {
+        categories.label_columns(labels, num_genes, &mut cols);
+        idx_eq_list.add_label_vec(&cols);
+        eq_id_count.push((ctr as u32, *count));
+    }
+}
+
+/// Parse a 4 column tsv of the format
+/// transcript_name gene_name   status  category
+/// where category is an arbitrary label (e.g. exonic, intronic, mito).
+/// The status column is retained for compatibility with the 3 column
+/// format, but the features are given by the (gene, category) pairs.
+/// The categories are recorded, in the order they are first encountered,
+/// in `categories`.
+fn parse_tg_categories(
+    entries: &[TgEntry],
+    ref_count: usize,
+    rname_to_id: &HashMap<String, u32, ahash::RandomState>,
+    gene_names: &mut Vec<String>,
+    gene_name_to_id: &mut HashMap<String, u32, ahash::RandomState>,
+    categories: &mut FeatureCategories,
+) -> Result<(Vec<u32>, bool), Box<dyn std::error::Error>> {
+    // we need the number of categories to assign the feature ids
+    categories.names.clear();
+    for (_, _, _, cat) in entries {
+        let cat = cat.as_deref().unwrap_or("");
+        if !categories.names.iter().any(|c| c == cat) {
+            categories.names.push(cat.to_string());
+        }
+    }
+    let n = categories.len() as u32;
+
+    let mut tid_to_gid = vec![u32::MAX; ref_count];
+    let mut found = 0usize;
+    for (txp, gene, _status, cat) in entries {
+        let cat = cat.as_deref().unwrap_or("");
+        let next_id = gene_name_to_id.len() as u32 * n;
+        let base_id = *gene_name_to_id.entry(gene.clone()).or_insert(next_id);
+        if base_id == next_id {
+            gene_names.push(gene.clone());
+        }
+        if let Some(transcript_id) = rname_to_id.get(txp) {
+            found += 1;
+            let cidx = categories
+                .names
+                .iter()
+                .position(|c| *c == cat)
+                .expect("category should be known") as u32;
+            tid_to_gid[*transcript_id as usize] = base_id + cidx;
+        }
+    }
 
     assert_eq!(
         found, ref_count,
@@ -233,25 +730,137 @@ fn parse_tg_spliced(
     Ok((tid_to_gid, false))
 }
 
+/// The (transcript, gene, status, category) of an entry of a transcript-to-gene
+/// map; the status and category are only given by the 3 and 4 column formats.
+pub type TgEntry = (String, String, Option<String>, Option<String>);
+
+/// The records of a transcript-to-gene map, as read from its file.
+pub enum TgMapRecords {
+    /// a 2, 3 or 4 column tsv file (every line has `num_cols` columns)
+    Table {
+        num_cols: usize,
+        entries: Vec<TgEntry>,
+    },
+    /// a GTF / GFF3 annotation
+    Annotation(AnnotationRecords),
+}
+
+/// Read the records of the transcript-to-gene map `tg_map`, which is either
+/// a 2, 3 or 4 column tsv file or a GTF / GFF3 annotation.  The records are
+/// checked with `check_t2g::check_tg_records`, and turned into the gene ids
+/// of the references by `parse_tg_records`.
+pub fn read_tg_map(tg_map: &str) -> Result<TgMapRecords, Box<dyn std::error::Error>> {
+    if let Some(fmt) = annotation_format(tg_map) {
+        return Ok(TgMapRecords::Annotation(read_annotation_records(
+            tg_map, fmt,
+        )?));
+    }
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(b'\t')
+        .from_reader(File::open(tg_map)?);
+    let mut entries = Vec::<TgEntry>::new();
+    let mut num_cols = None;
+    for (lnum, rec) in rdr.records().enumerate() {
+        let rec = rec?;
+        // every line must have the same number of columns
+        let first_cols = *num_cols.get_or_insert(rec.len());
+        if rec.len() != first_cols {
+            return Err(format!(
+                "line {} of the tg-map has {} columns, but the first line has {}.",
+                lnum + 1,
+                rec.len(),
+                first_cols
+            )
+            .into());
+        }
+        match rec.len() {
+            2..=4 => {
+                entries.push((
+                    rec[0].to_string(),
+                    rec[1].to_string(),
+                    rec.get(2).map(String::from),
+                    rec.get(3).map(String::from),
+                ));
+            }
+            n => {
+                return Err(format!(
+                    "line {} of the tg-map has {} columns, but it should have 2, 3 or 4.",
+                    lnum + 1,
+                    n
+                )
+                .into());
+            }
+        }
+    }
+    Ok(TgMapRecords::Table {
+        num_cols: num_cols.unwrap_or(0),
+        entries,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn parse_tg_map(
     tg_map: &str,
     ref_count: usize,
     rname_to_id: &HashMap<String, u32, ahash::RandomState>,
     gene_names: &mut Vec<String>,
     gene_name_to_id: &mut HashMap<String, u32, ahash::RandomState>,
+    ref_names: &[String],
+    gene_annots: &mut Vec<GeneAnnotation>,
+    categories: &mut FeatureCategories,
 ) -> Result<(Vec<u32>, bool), Box<dyn std::error::Error>> {
-    let t2g_file = std::fs::File::open(tg_map).expect("couldn't open file");
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(false)
-        .delimiter(b'\t')
-        .from_reader(t2g_file);
+    parse_tg_records(
+        tg_map,
+        read_tg_map(tg_map)?,
+        ref_count,
+        rname_to_id,
+        gene_names,
+        gene_name_to_id,
+        ref_names,
+        gene_annots,
+        categories,
+    )
+}
 
-    let headers = rdr.headers()?;
-    match headers.len() {
+/// As `parse_tg_map`, for the `records` already read from the tg-map
+/// file `tg_map` (whose name is only used in error messages).
+#[allow(clippy::too_many_arguments)]
+pub fn parse_tg_records(
+    tg_map: &str,
+    records: TgMapRecords,
+    ref_count: usize,
+    rname_to_id: &HashMap<String, u32, ahash::RandomState>,
+    gene_names: &mut Vec<String>,
+    gene_name_to_id: &mut HashMap<String, u32, ahash::RandomState>,
+    ref_names: &[String],
+    gene_annots: &mut Vec<GeneAnnotation>,
+    categories: &mut FeatureCategories,
+) -> Result<(Vec<u32>, bool), Box<dyn std::error::Error>> {
+    let (num_cols, entries) = match records {
+        // a GTF / GFF3 annotation, rather than a transcript to gene table
+        TgMapRecords::Annotation(recs) => {
+            return parse_tg_annotation(
+                tg_map,
+                recs,
+                ref_count,
+                ref_names,
+                rname_to_id,
+                gene_names,
+                gene_name_to_id,
+                gene_annots,
+            );
+        }
+        TgMapRecords::Table { num_cols, entries } => (num_cols, entries),
+    };
+
+    match num_cols {
         2 => {
             // parse the 2 column format
             parse_tg_spliced(
-                &mut rdr,
+                &entries,
                 ref_count,
                 rname_to_id,
                 gene_names,
@@ -261,16 +870,27 @@ pub fn parse_tg_map(
         3 => {
             // parse the 3 column format
             parse_tg_spliced_unspliced(
-                &mut rdr,
+                &entries,
                 ref_count,
                 rname_to_id,
                 gene_names,
                 gene_name_to_id,
             )
         }
+        4 => {
+            // parse the 4 column format, with feature categories
+            parse_tg_categories(
+                &entries,
+                ref_count,
+                rname_to_id,
+                gene_names,
+                gene_name_to_id,
+                categories,
+            )
+        }
         _ => {
             // not supported
-            Err("Transcript-gene mapping must have either 2 or 3 columns.".into())
+            Err("Transcript-gene mapping must have 2, 3 or 4 columns.".into())
         }
     }
 }
@@ -294,7 +914,7 @@ pub fn extract_counts(
     let ambig_offset = 2 * unspliced_offset;
     let mut counts = vec![0_f32; num_counts];
 
-    for (labels, count) in gene_eqc {
+    for (labels, count) in sorted_eq_classes(gene_eqc) {
         // the length of the label will tell us if this is a
         // splicing-unique, gene-unique (but splicing ambiguous).
         // or gene-ambiguous equivalence class label.
@@ -383,7 +1003,7 @@ pub fn extract_counts_mm_uniform(
     let mut counts = vec![0_f32; num_counts];
     let mut tvec = Vec::<usize>::with_capacity(16);
 
-    for (labels, count) in gene_eqc {
+    for (labels, count) in sorted_eq_classes(gene_eqc) {
         // the length of the label will tell us if this is a
         // splicing-unique, gene-unique (but splicing ambiguous).
         // or gene-ambiguous equivalence class label.
@@ -474,7 +1094,7 @@ pub fn extract_usa_eqmap(
     let ambig_offset = 2 * unspliced_offset;
     let mut tvec = Vec::<u32>::with_capacity(16);
 
-    for (ctr, (labels, count)) in gene_eqc.iter().enumerate() {
+    for (ctr, (labels, count)) in sorted_eq_classes(gene_eqc).into_iter().enumerate() {
         // the length of the label will tell us if this is a
         // splicing-unique, gene-unique (but splicing ambiguous).
         // or gene-ambiguous equivalence class label.
@@ -611,10 +1231,10 @@ pub fn get_all_indels(bc: u64, bc_length: usize) -> Vec<u64> {
     indels
 }
 
-pub fn get_all_one_edit_neighbors(
+pub fn get_all_one_edit_neighbors<S: std::hash::BuildHasher>(
     bc: u64,
     bc_length: usize,
-    neighbors: &mut HashSet<u64>,
+    neighbors: &mut HashSet<u64, S>,
 ) -> Result<(), Box<dyn Error>> {
     neighbors.clear();
 
@@ -649,6 +1269,9 @@ pub fn generate_whitelist_set(
     Ok(one_edit_barcode_hash)
 }
 
+/// A map from barcodes to the permitted barcodes they are corrected to.
+pub type PermitListMap = HashMap<u64, u64, ahash::RandomState>;
+
 /**
  * generates a map that contains all one edit distance neighbors
  * of the permitted barcodes.  The key is the neighbor and the value
@@ -657,10 +1280,24 @@ pub fn generate_whitelist_set(
 pub fn generate_permitlist_map(
     permit_bcs: &[u64],
     bc_length: usize,
-) -> Result<HashMap<u64, u64>, Box<dyn Error>> {
+) -> Result<PermitListMap, Box<dyn Error>> {
+    generate_permitlist_map_and_ties(permit_bcs, bc_length).map(|(m, _)| m)
+}
+
+/// As `generate_permitlist_map`, but also returns the (sorted) neighbors
+/// of several permitted barcodes, which the map assigns to the first of
+/// them in `permit_bcs`.
+pub fn generate_permitlist_map_and_ties(
+    permit_bcs: &[u64],
+    bc_length: usize,
+) -> Result<(PermitListMap, Vec<u64>), Box<dyn Error>> {
     let num_bcs = permit_bcs.len();
 
-    let mut one_edit_barcode_map: HashMap<u64, u64> = HashMap::with_capacity(10 * num_bcs);
+    // a fixed hasher, so that the map (and the permit_map.bin file it's
+    // written to) is the same for the same permitted barcodes
+    let s = ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64);
+    let mut one_edit_barcode_map: HashMap<u64, u64, ahash::RandomState> =
+        HashMap::with_capacity_and_hasher(10 * num_bcs, s.clone());
     // first insert everything already in the explicit permitlist
     for bc in permit_bcs {
         one_edit_barcode_map.insert(*bc, *bc);
@@ -669,16 +1306,23 @@ pub fn generate_permitlist_map(
     // reserved space for 3*length SNP
     // + 4 * (length -1) insertion
     // + 4 * (length -1) deletion
-    let mut neighbors: HashSet<u64> = HashSet::with_capacity(3 * bc_length + 8 * (bc_length - 1));
+    let mut neighbors: HashSet<u64, ahash::RandomState> =
+        HashSet::with_capacity_and_hasher(3 * bc_length + 8 * (bc_length - 1), s);
 
+    let mut ties = Vec::<u64>::new();
     for bc in permit_bcs {
         get_all_one_edit_neighbors(*bc, bc_length, &mut neighbors)?;
         for n in &neighbors {
-            one_edit_barcode_map.entry(*n).or_insert(*bc);
+            let e = one_edit_barcode_map.entry(*n).or_insert(*bc);
+            if *e != *bc && *e != *n {
+                ties.push(*n);
+            }
         }
     }
+    ties.sort_unstable();
+    ties.dedup();
 
-    Ok(one_edit_barcode_map)
+    Ok((one_edit_barcode_map, ties))
 }
 
 /// Reads the contents of the file `flist`, which should contain
@@ -756,11 +1400,44 @@ pub enum VersionParseError {
     IncorrectFormat(String),
 }
 
+/// An invalid configuration of a step.
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("{name} must be {constraint}, the value {value} was provided")]
+    InvalidValue {
+        name: &'static str,
+        constraint: &'static str,
+        value: String,
+    },
+    #[error("{0}")]
+    Incompatible(String),
+}
+
+impl ConfigError {
+    pub fn invalid<V: fmt::Display>(
+        name: &'static str,
+        constraint: &'static str,
+        value: V,
+    ) -> Self {
+        ConfigError::InvalidValue {
+            name,
+            constraint,
+            value: value.to_string(),
+        }
+    }
+}
+
 impl FromStr for InternalVersionInfo {
     type Err = VersionParseError;
 
     fn from_str(vs: &str) -> Result<Self, Self::Err> {
-        let versions: Vec<u32> = vs.split('.').map(|s| s.parse::<u32>().unwrap()).collect();
+        // ignore any pre-release or build suffix (e.g. 0.6.0-beta or 0.6.0+abc)
+        let core = vs.split(['-', '+']).next().unwrap_or("");
+        let versions = core
+            .split('.')
+            .map(|s| s.parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| VersionParseError::IncorrectFormat(vs.to_string()))?;
         if versions.len() != 3 {
             return Err(VersionParseError::IncorrectFormat(vs.to_string()));
         }
@@ -772,15 +1449,376 @@ impl FromStr for InternalVersionInfo {
     }
 }
 
+/// Read the metadata file `meta_path` written by the pipeline step `step`
+/// (e.g. "generate-permit-list"), and check that it was written by a
+/// version of alevin-fry compatible with `version_str`.
+pub fn read_step_meta(
+    meta_path: &std::path::Path,
+    step: &str,
+    version_str: &str,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    let fname = meta_path
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let meta_file = File::open(meta_path).map_err(|e| {
+        format!(
+            "could not open {:?} ({}); please run the {} step first",
+            meta_path, e, step
+        )
+    })?;
+    let mdata: serde_json::Value = serde_json::from_reader(meta_file)?;
+
+    let calling_version = InternalVersionInfo::from_str(version_str)?;
+    let vd = match mdata.get("version_str") {
+        Some(vs) => match vs.as_str() {
+            Some(s) => InternalVersionInfo::from_str(s)?,
+            None => {
+                return Err(format!("The version_str field of {} must be a string", fname).into());
+            }
+        },
+        None => {
+            return Err(format!(
+                "The {} file does not contain a version_str field. Please re-run the {} step with a newer version of alevin-fry",
+                fname, step
+            )
+            .into());
+        }
+    };
+    calling_version.is_compatible_with(&vd)?;
+    Ok(mdata)
+}
+
+/// Record, under `key` in the metadata file `meta_path`, the sizes of the
+/// files `fnames` (those that exist) in `dir`, so that later steps can
+/// detect if these files have changed since.
+pub fn record_file_sizes<S: AsRef<str>>(
+    meta_path: &std::path::Path,
+    key: &str,
+    dir: &std::path::Path,
+    fnames: &[S],
+) -> Result<(), Box<dyn Error>> {
+    let mut sizes = serde_json::Map::new();
+    for fname in fnames {
+        let fname = fname.as_ref();
+        if let Ok(md) = std::fs::metadata(dir.join(fname)) {
+            sizes.insert(fname.to_string(), serde_json::Value::from(md.len()));
+        }
+    }
+    add_meta_entry(meta_path, key, serde_json::Value::Object(sizes))
+}
+
+/// Add (or replace) the entry `key` of the JSON metadata file `meta_path`
+/// with `value`.
+pub fn add_meta_entry(
+    meta_path: &std::path::Path,
+    key: &str,
+    value: serde_json::Value,
+) -> Result<(), Box<dyn Error>> {
+    let mut mdata: serde_json::Value = serde_json::from_reader(File::open(meta_path)?)?;
+    mdata
+        .as_object_mut()
+        .ok_or_else(|| format!("{:?} does not contain a JSON object", meta_path))?
+        .insert(key.to_string(), value);
+    let mut meta_file = File::create(meta_path)?;
+    meta_file.write_all(serde_json::to_string_pretty(&mdata)?.as_bytes())?;
+    Ok(())
+}
+
+/// Check the sizes of the files in `dir` against those recorded (with
+/// [`record_file_sizes`]) under `key` in the metadata `mdata` of the step
+/// `step`. If any of the files is missing or has a different size, the
+/// differences are logged and an error is returned, as the results of
+/// `step` are stale.
+pub fn check_file_sizes(
+    mdata: &serde_json::Value,
+    key: &str,
+    dir: &std::path::Path,
+    step: &str,
+    log: &slog::Logger,
+) -> Result<(), Box<dyn Error>> {
+    let sizes = match mdata.get(key).and_then(|v| v.as_object()) {
+        Some(s) => s,
+        None => {
+            warn!(
+                log,
+                "the metadata of the {} step does not record the sizes of its files, so they can't be checked",
+                step
+            );
+            return Ok(());
+        }
+    };
+    let mut num_changed = 0usize;
+    for (fname, size) in sizes {
+        let expected = size.as_u64().unwrap_or(0);
+        match std::fs::metadata(dir.join(fname)) {
+            Ok(md) if md.len() == expected => {}
+            Ok(md) => {
+                crit!(
+                    log,
+                    "{:?} has size {} bytes, but was {} bytes when the {} step was run",
+                    dir.join(fname),
+                    md.len(),
+                    expected,
+                    step
+                );
+                num_changed += 1;
+            }
+            Err(_) => {
+                crit!(
+                    log,
+                    "{:?} was present when the {} step was run, but no longer exists",
+                    dir.join(fname),
+                    step
+                );
+                num_changed += 1;
+            }
+        }
+    }
+    if num_changed > 0 {
+        return Err(format!(
+            "{} file(s) have changed since the {} step was run; please re-run it and the subsequent steps",
+            num_changed, step
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Check that the files recorded (with [`record_file_sizes`]) under `key`
+/// in the metadata `mdata` of the step `step` are exactly `fnames`, i.e.
+/// that a later step is run on the same set of files as `step` was. If
+/// not, the differences are logged and an error is returned.
+pub fn check_recorded_files<S: AsRef<str>>(
+    mdata: &serde_json::Value,
+    key: &str,
+    fnames: &[S],
+    step: &str,
+    log: &slog::Logger,
+) -> Result<(), Box<dyn Error>> {
+    let sizes = match mdata.get(key).and_then(|v| v.as_object()) {
+        Some(s) => s,
+        // the absence of the record is reported by check_file_sizes
+        None => return Ok(()),
+    };
+    let given: HashSet<&str> = fnames.iter().map(|f| f.as_ref()).collect();
+    let mut num_diff = 0usize;
+    for fname in sizes.keys() {
+        if !given.contains(fname.as_str()) {
+            crit!(
+                log,
+                "{} was an input of the {} step, but is not one of the given inputs",
+                fname,
+                step
+            );
+            num_diff += 1;
+        }
+    }
+    for fname in given {
+        if !sizes.contains_key(fname) {
+            crit!(
+                log,
+                "{} is one of the given inputs, but was not an input of the {} step",
+                fname,
+                step
+            );
+            num_diff += 1;
+        }
+    }
+    if num_diff > 0 {
+        return Err(format!(
+            "the given inputs differ from those of the {} step in {} file(s); please give the same inputs",
+            step, num_diff
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Parse a memory size such as `16G`, `512M`, `1.5g` or `1000000` (bytes)
+/// into a number of bytes; the `K`, `M`, `G` and `T` suffixes are powers
+/// of 1024, and may be followed by `B`.
+pub fn parse_memory_size(s: &str) -> Result<u64, String> {
+    let t = s.trim().to_uppercase();
+    let t = t.strip_suffix('B').unwrap_or(&t);
+    let (num, mult) = match t.chars().last() {
+        Some('K') => (&t[..t.len() - 1], 1u64 << 10),
+        Some('M') => (&t[..t.len() - 1], 1u64 << 20),
+        Some('G') => (&t[..t.len() - 1], 1u64 << 30),
+        Some('T') => (&t[..t.len() - 1], 1u64 << 40),
+        _ => (t, 1u64),
+    };
+    match num.trim().parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok((v * mult as f64) as u64),
+        _ => Err(format!("{} is not a valid memory size", s)),
+    }
+}
+
+/// The peak resident memory (in bytes) of this process so far, if it can
+/// be determined (currently only on Linux, from /proc/self/status).
+pub fn peak_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    // the value is given in kB
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// The runtime metrics of a step: the wall time of each of its phases,
+/// the time its worker threads spent on each kind of work, its peak
+/// memory, the bytes it read and wrote, and its number of threads. They
+/// are written under `runtime` in the metadata file of the step.
+pub struct StageMetrics {
+    start: std::time::Instant,
+    start_wall: std::time::SystemTime,
+    phase_start: std::time::Instant,
+    phase_secs: Vec<(&'static str, f64)>,
+    thread_secs: Vec<(&'static str, f64)>,
+    num_threads: u32,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl StageMetrics {
+    pub fn new(num_threads: u32) -> Self {
+        let now = std::time::Instant::now();
+        StageMetrics {
+            start: now,
+            start_wall: std::time::SystemTime::now(),
+            phase_start: now,
+            phase_secs: Vec::new(),
+            thread_secs: Vec::new(),
+            num_threads,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// Record the wall time since the end of the previous phase (or the
+    /// start of the step) as that of the phase `phase`.
+    pub fn end_phase(&mut self, phase: &'static str) {
+        let now = std::time::Instant::now();
+        self.phase_secs
+            .push((phase, (now - self.phase_start).as_secs_f64()));
+        self.phase_start = now;
+    }
+
+    /// Record `d`, the time summed over the worker threads, as that spent
+    /// on `work`.
+    pub fn add_thread_time(&mut self, work: &'static str, d: std::time::Duration) {
+        self.thread_secs.push((work, d.as_secs_f64()));
+    }
+
+    /// Add the sizes of the files `paths` (those that exist) to the bytes read.
+    pub fn add_files_read<P: AsRef<std::path::Path>>(&mut self, paths: &[P]) {
+        for p in paths {
+            if let Ok(md) = std::fs::metadata(p) {
+                self.bytes_read += md.len();
+            }
+        }
+    }
+
+    /// Add the sizes of the files `paths` (those that exist) to the bytes written.
+    pub fn add_files_written<P: AsRef<std::path::Path>>(&mut self, paths: &[P]) {
+        for p in paths {
+            if let Ok(md) = std::fs::metadata(p) {
+                self.bytes_written += md.len();
+            }
+        }
+    }
+
+    /// Add the sizes of the files in `dir` (and its sub-directories) that
+    /// were modified since the step started to the bytes written.
+    pub fn add_files_written_in(&mut self, dir: &std::path::Path) {
+        // file times can lag the clock a little, so allow some slack
+        let since = self.start_wall - std::time::Duration::from_secs(1);
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(d) = dirs.pop() {
+            let entries = match std::fs::read_dir(&d) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            for e in entries.flatten() {
+                let md = match e.metadata() {
+                    Ok(md) => md,
+                    Err(_) => continue,
+                };
+                if md.is_dir() {
+                    dirs.push(e.path());
+                } else if md.modified().map(|t| t >= since).unwrap_or(false) {
+                    self.bytes_written += md.len();
+                }
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let to_map = |v: &[(&'static str, f64)]| -> serde_json::Map<String, serde_json::Value> {
+            v.iter()
+                .map(|(k, secs)| (k.to_string(), serde_json::Value::from(*secs)))
+                .collect()
+        };
+        serde_json::json!({
+            "num_threads" : self.num_threads,
+            "wall_time_secs" : self.start.elapsed().as_secs_f64(),
+            "phase_wall_time_secs" : to_map(&self.phase_secs),
+            "thread_time_secs" : to_map(&self.thread_secs),
+            "peak_rss_bytes" : peak_memory_bytes(),
+            "bytes_read" : self.bytes_read,
+            "bytes_written" : self.bytes_written
+        })
+    }
+
+    /// Log the wall time of each phase, the time of the worker threads
+    /// and the totals, with the values as key-value fields of the records.
+    pub fn log(&self, log: &slog::Logger) {
+        for (phase, secs) in self.phase_secs.iter() {
+            info!(log, "{} phase took {:.2}s", phase, secs;
+                "event" => "phase_time",
+                "phase" => *phase,
+                "wall_time_secs" => *secs
+            );
+        }
+        for (work, secs) in self.thread_secs.iter() {
+            info!(log, "worker threads spent {:.2}s on {}", secs, work;
+                "event" => "thread_time",
+                "work" => *work,
+                "thread_time_secs" => *secs
+            );
+        }
+        info!(log, "read {} bytes and wrote {} bytes", self.bytes_read, self.bytes_written;
+            "event" => "runtime",
+            "num_threads" => self.num_threads,
+            "wall_time_secs" => self.start.elapsed().as_secs_f64(),
+            "peak_rss_bytes" => peak_memory_bytes(),
+            "bytes_read" => self.bytes_read,
+            "bytes_written" => self.bytes_written
+        );
+    }
+
+    /// Write the metrics under `runtime` in the metadata file `meta_path`.
+    pub fn write(&self, meta_path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+        add_meta_entry(meta_path, "runtime", self.to_json())
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::utils::check_recorded_files;
+    use crate::utils::edit_dist_2_bit_packed;
+    use crate::utils::generate_permitlist_map_and_ties;
     use crate::utils::generate_whitelist_set;
     use crate::utils::get_all_indels;
     use crate::utils::get_all_one_edit_neighbors;
     use crate::utils::get_all_snps;
     use crate::utils::get_bit_mask;
+    use crate::utils::parse_memory_size;
     use crate::utils::InternalVersionInfo;
-    use std::collections::HashSet;
+    use crate::utils::{
+        parse_tg_map, read_tg_map, FeatureCategories, GeneAnnotation, TgMapRecords,
+    };
+    use crate::utils::{read_permit_list_freq, write_permit_list_freq};
+    use std::collections::{HashMap, HashSet};
     use std::str::FromStr;
 
     #[test]
@@ -794,6 +1832,152 @@ mod tests {
                 patch: 3
             }
         );
+        let vi = InternalVersionInfo::from_str("0.6.0-beta.1").unwrap();
+        assert_eq!((vi.major, vi.minor, vi.patch), (0, 6, 0));
+        assert!(InternalVersionInfo::from_str("1.x.3").is_err());
+        assert!(InternalVersionInfo::from_str("1.2").is_err());
+    }
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(parse_memory_size("1000"), Ok(1000));
+        assert_eq!(parse_memory_size("16G"), Ok(16 << 30));
+        assert_eq!(parse_memory_size("512mb"), Ok(512 << 20));
+        assert_eq!(parse_memory_size("1.5G"), Ok(3 << 29));
+        assert!(parse_memory_size("G").is_err());
+        assert!(parse_memory_size("-1G").is_err());
+        assert!(parse_memory_size("lots").is_err());
+    }
+
+    #[test]
+    fn test_permit_list_freq_round_trip() {
+        let path = std::env::temp_dir().join(format!("af_test_{}_freq.bin", std::process::id()));
+        let mut freq = HashMap::with_hasher(ahash::RandomState::with_seeds(2u64, 7u64, 1u64, 8u64));
+        freq.insert(0b0001_1011u64, 42u64);
+        freq.insert(0b1110_0100u64, 7u64);
+        write_permit_list_freq(&path, 4, &freq).unwrap();
+        let (version, bclen, read_freq) = read_permit_list_freq(&path).unwrap();
+        assert_eq!(version, crate::constants::PERMIT_FILE_VER);
+        assert_eq!(bclen, 4);
+        assert_eq!(read_freq.len(), freq.len());
+        for (bc, c) in freq.iter() {
+            assert_eq!(read_freq.get(bc), Some(c));
+        }
+
+        // files written by a later version can't be read
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[..8].copy_from_slice(&(crate::constants::PERMIT_FILE_VER + 1).to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        assert!(read_permit_list_freq(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_check_recorded_files() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mdata =
+            serde_json::json!({ "input_file_sizes": { "/a/map.rad": 10, "/b/map.rad": 20 } });
+        let check =
+            |fnames: &[&str]| check_recorded_files(&mdata, "input_file_sizes", fnames, "gpl", &log);
+        assert!(check(&["/b/map.rad", "/a/map.rad"]).is_ok());
+        assert!(check(&["/a/map.rad"]).is_err());
+        assert!(check(&["/a/map.rad", "/b/map.rad", "/c/map.rad"]).is_err());
+        assert!(check(&["/a/map.rad", "/c/map.rad"]).is_err());
+        // nothing to check against
+        let old = serde_json::json!({});
+        assert!(
+            check_recorded_files(&old, "input_file_sizes", &["/c/map.rad"], "gpl", &log).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_read_tg_map() {
+        let path = std::env::temp_dir().join(format!("af_test_{}.tsv", std::process::id()));
+        std::fs::write(&path, "T1\tG1\tS\texon\nT2\tG1\tU\tintron\n").unwrap();
+        match read_tg_map(path.to_str().unwrap()).unwrap() {
+            TgMapRecords::Table { num_cols, entries } => {
+                assert_eq!(num_cols, 4);
+                assert_eq!(entries.len(), 2);
+                assert_eq!(entries[1].2.as_deref(), Some("U"));
+                assert_eq!(entries[1].3.as_deref(), Some("intron"));
+            }
+            TgMapRecords::Annotation(_) => panic!("a tsv file is not an annotation"),
+        }
+
+        // every line must have the same number of columns
+        std::fs::write(&path, "T1\tG1\nT2\tG1\tU\n").unwrap();
+        assert!(read_tg_map(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_tg_map_gtf() {
+        let gtf = "##description: test\n\
+            chr1\tt\tgene\t1\t100\t.\t+\t.\tgene_id \"G1\"; gene_name \"A\"; gene_type \"protein_coding\";\n\
+            chr1\tt\ttranscript\t1\t100\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; gene_name \"A\";\n\
+            chr1\tt\texon\t1\t50\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n\
+            chr1\tt\texon\t200\t300\t.\t-\t.\tgene_id \"G2\"; transcript_id \"T2\"; gene_biotype \"lncRNA\";\n";
+        let path = std::env::temp_dir().join(format!("af_test_{}.gtf", std::process::id()));
+        std::fs::write(&path, gtf).unwrap();
+
+        let ref_names: Vec<String> = vec!["T2".into(), "G1-I".into(), "T1".into()];
+        let mut rname_to_id = HashMap::with_hasher(ahash::RandomState::new());
+        for (i, n) in ref_names.iter().enumerate() {
+            rname_to_id.insert(n.clone(), i as u32);
+        }
+        let mut gene_names = Vec::new();
+        let mut gene_name_to_id = HashMap::with_hasher(ahash::RandomState::new());
+        let mut gene_annots = Vec::new();
+        let (tid_to_gid, with_unspliced) = parse_tg_map(
+            path.to_str().unwrap(),
+            ref_names.len(),
+            &rname_to_id,
+            &mut gene_names,
+            &mut gene_name_to_id,
+            &ref_names,
+            &mut gene_annots,
+            &mut FeatureCategories::default(),
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(with_unspliced);
+        assert_eq!(gene_names, vec!["G1", "G2"]);
+        assert_eq!(tid_to_gid, vec![2, 1, 0]);
+        assert_eq!(
+            gene_annots,
+            vec![
+                GeneAnnotation {
+                    name: Some("A".into()),
+                    biotype: Some("protein_coding".into())
+                },
+                GeneAnnotation {
+                    name: None,
+                    biotype: Some("lncRNA".into())
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_feature_category_columns() {
+        let cats = FeatureCategories {
+            names: vec!["exonic".into(), "intronic".into(), "mito".into()],
+        };
+        let num_genes = 4;
+        let mut cols = Vec::new();
+        // category 1 of gene 2
+        cats.label_columns(&[7], num_genes, &mut cols);
+        assert_eq!(cols, vec![6]);
+        // categories 0 and 1 of gene 1, and category 2 of gene 3
+        cats.label_columns(&[3, 4, 11], num_genes, &mut cols);
+        assert_eq!(cols, vec![13, 11]);
+        assert_eq!(cats.num_columns(num_genes), 16);
+        let names = cats.column_names(&["A".to_string()]);
+        assert_eq!(
+            names,
+            vec!["A-exonic", "A-intronic", "A-mito", "A-ambiguous"]
+        );
     }
 
     #[test]
@@ -823,6 +2007,50 @@ mod tests {
         assert_eq!(output, vec![1, 4, 5, 6, 9, 12, 13, 14, 15, 28, 29, 30, 31]);
     }
 
+    #[test]
+    fn test_generate_permitlist_map_ties() {
+        // AAA and AAT are both a substitution away from AAC and AAG
+        let (m, ties) = generate_permitlist_map_and_ties(&[0, 3], 3).unwrap();
+        assert_eq!(m[&0], 0);
+        assert_eq!(m[&3], 3);
+        assert!(ties.contains(&1) && ties.contains(&2));
+        assert!(!ties.contains(&0) && !ties.contains(&3));
+        assert!(ties.windows(2).all(|w| w[0] < w[1]));
+        // the neighbors of a single permitted barcode are not ties
+        let (_, ties) = generate_permitlist_map_and_ties(&[0], 3).unwrap();
+        assert!(ties.is_empty());
+    }
+
+    #[test]
+    fn test_edit_dist_2_bit_packed() {
+        let enc = |s: &[u8]| -> u64 {
+            s.iter().fold(0u64, |acc, c| {
+                (acc << 2)
+                    | match c {
+                        b'A' => 0,
+                        b'C' => 1,
+                        b'G' => 2,
+                        _ => 3,
+                    }
+            })
+        };
+        let x = enc(b"ACGTAC");
+        assert_eq!(edit_dist_2_bit_packed(x, x, 6, 1), 0);
+        assert_eq!(edit_dist_2_bit_packed(x, enc(b"ACGTAA"), 6, 1), 1);
+        // deletion of the C; the next base is pulled onto the end
+        assert_eq!(edit_dist_2_bit_packed(x, enc(b"AGTACG"), 6, 1), 1);
+        // insertion of a T; the last base falls off the end
+        assert_eq!(edit_dist_2_bit_packed(x, enc(b"ATCGTA"), 6, 1), 1);
+        assert!(edit_dist_2_bit_packed(enc(b"AAATTT"), enc(b"TTTAAA"), 6, 2) > 2);
+        // the longest sequences that can be packed
+        let y = enc(b"ACGTACGTACGTACGTACGTACGTACGTACGT");
+        assert_eq!(edit_dist_2_bit_packed(y, y, 32, 1), 0);
+        assert_eq!(
+            edit_dist_2_bit_packed(y, enc(b"CGTACGTACGTACGTACGTACGTACGTACGTA"), 32, 1),
+            1
+        );
+    }
+
     #[test]
     fn test_get_all_one_edit_neighbors() {
         let mut neighbors: HashSet<u64> = HashSet::new();